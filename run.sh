@@ -4,6 +4,6 @@ sudo ip tuntap add mode tap tap0
 sudo ip addr add dev tap0 192.168.14.1/24
 sudo ip link set tap0 up
 
-sudo qemu-system-aarch64 -M virt -cpu cortex-a53 -smp cpus=4 -m 1024M -display none -serial stdio -global virtio-mmio.force-legacy=false -device virtio-rng-device -drive if=none,cache=directsync,file=test.img,format=raw,id=hd0 -device virtio-blk-device,drive=hd0 -netdev type=tap,vhost=on,ifname=tap0,id=net0,script=no,downscript=no -device virtio-net-device,netdev=net0 -kernel $1
+sudo qemu-system-aarch64 -M virt -cpu cortex-a53 -smp cpus=4 -m 1024M -display none -serial stdio -global virtio-mmio.force-legacy=false -device virtio-rng-device -device virtio-serial-device,max_ports=4 -chardev pty,id=vcon0 -device virtconsole,chardev=vcon0 -drive if=none,cache=directsync,file=test.img,format=raw,id=hd0 -device virtio-blk-device,drive=hd0 -netdev type=tap,vhost=on,ifname=tap0,id=net0,script=no,downscript=no -device virtio-net-device,netdev=net0 -fsdev local,id=fs0,path=.,security_model=none -device virtio-9p-device,fsdev=fs0,mount_tag=host0 -device virtio-balloon-device -append "ip=192.168.14.4::192.168.14.1:255.255.255.0 netns=192.168.14.5,192.168.14.6" -kernel $1
 
 sudo ip link delete tap0
@@ -1,3 +1,3 @@
 #!/bin/sh
 
-qemu-system-aarch64 -M virt,virtualization=on -cpu cortex-a53 -smp cpus=4 -m 1024M -display none -serial stdio -global virtio-mmio.force-legacy=false -device virtio-rng-device -drive if=none,cache=directsync,file=test.img,format=raw,id=hd0 -device virtio-blk-device,drive=hd0 -kernel $1
+qemu-system-aarch64 -M virt,virtualization=on -cpu cortex-a53 -smp cpus=4 -m 1024M -display none -serial stdio -global virtio-mmio.force-legacy=false -device virtio-rng-device -device virtio-serial-device,max_ports=4 -chardev pty,id=vcon0 -device virtconsole,chardev=vcon0 -drive if=none,cache=directsync,file=test.img,format=raw,id=hd0 -device virtio-blk-device,drive=hd0 -fsdev local,id=fs0,path=.,security_model=none -device virtio-9p-device,fsdev=fs0,mount_tag=host0 -device virtio-balloon-device -kernel $1
@@ -1,18 +1,34 @@
 use core::str::from_utf8;
 
+use crate::container;
+use crate::kobject::{Container, KObjectRef};
+use crate::mm::PAGE_SIZE;
 use crate::mutex::Mutex;
+use crate::net::pcap::Capture;
+use crate::net::Interface;
+use crate::random;
+use crate::snapshot;
 use crate::uart::UART;
-use crate::virtio::{VirtIOBlk, VirtIOEntropy};
+use crate::ninep::{self, NinePClient};
+use crate::virtio::{VirtIO9P, VirtIOBalloon, VirtIOBlk, VirtIONet};
+
+const PCAP_RING: usize = 64 * 1024;
+// Larger rings could fail to allocate and take the kernel down
+const PCAP_RING_MAX: usize = 16 * PCAP_RING;
+const PCAP_SNAPLEN: usize = 1514;
 
 pub struct Shell<'a, 'b> {
     pub blk: &'a Mutex<Option<VirtIOBlk<'b>>>,
-    pub entropy: &'a Mutex<Option<VirtIOEntropy<'b>>>,
+    pub root: KObjectRef<Container>,
+    pub host: &'a Mutex<Option<VirtIO9P<'b>>>,
+    pub balloon: &'a Mutex<Option<VirtIOBalloon<'b>>>,
+    pub net: &'a Mutex<Option<Interface<VirtIONet<'b>>>>,
 }
 
 impl<'a, 'b> Shell<'a, 'b> {
     fn get_random<F: FnMut(&[u8])>(&mut self, mut f: F) {
         let mut data: [u8; 16] = [0; 16];
-        self.entropy.map(|e| e.read(&mut data));
+        random::getrandom(&mut data);
         f(b"Random: ");
         f(&data);
     }
@@ -33,7 +49,7 @@ impl<'a, 'b> Shell<'a, 'b> {
             let curlen = core::cmp::min(512, len);
             {
                 let curbuf = &mut outdata[..curlen];
-                self.entropy.map(|e| e.read(curbuf));
+                random::getrandom(curbuf);
                 for b in curbuf.iter_mut() {
                     *b = ((*b as u32 * 100) / 272 + 32) as u8;
                 }
@@ -69,6 +85,222 @@ impl<'a, 'b> Shell<'a, 'b> {
         }
     }
 
+    fn checkpoint<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        let root = self.root;
+        match self.blk.map(|blk| snapshot::save(root, blk)) {
+            Some(Ok(_)) => f(b"done"),
+            Some(Err(_)) => f(b"checkpoint failed"),
+            None => f(b"no disk"),
+        }
+    }
+
+    fn host_ls<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let path = words.next().and_then(|p| from_utf8(p).ok()).unwrap_or("");
+        let result = self.host.map(|dev| {
+            let mut client = NinePClient::mount(dev, "")?;
+            let fid = client.open(path, ninep::O_RDONLY | ninep::O_DIRECTORY)?;
+            let result = client.readdir(fid, |name| {
+                f(name);
+                f(b"\n");
+            });
+            client.clunk(fid);
+            result
+        });
+        match result {
+            Some(Ok(())) => {}
+            Some(Err(_)) => f(b"hostls failed"),
+            None => f(b"no host share"),
+        }
+    }
+
+    fn host_cat<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let path = words.next().and_then(|p| from_utf8(p).ok()).unwrap_or("");
+        let result = self.host.map(|dev| {
+            let mut client = NinePClient::mount(dev, "")?;
+            let fid = client.open(path, ninep::O_RDONLY)?;
+            let mut data = [0u8; 512];
+            let mut offset = 0;
+            let result = loop {
+                match client.read(fid, offset, &mut data) {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => {
+                        f(&data[..n]);
+                        offset += n as u64;
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+            client.clunk(fid);
+            result
+        });
+        match result {
+            Some(Ok(())) => {}
+            Some(Err(_)) => f(b"hostcat failed"),
+            None => f(b"no host share"),
+        }
+    }
+
+    // hostput <dir> <name> <text...>
+    fn host_put<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        let dir = words.next().and_then(|p| from_utf8(p).ok()).unwrap_or("");
+        let name = words.next().and_then(|p| from_utf8(p).ok()).unwrap_or("");
+        let result = self.host.map(|dev| {
+            let mut client = NinePClient::mount(dev, "")?;
+            let flags = ninep::O_WRONLY | ninep::O_CREAT | ninep::O_TRUNC;
+            let fid = client.create(dir, name, flags, 0o644)?;
+            let mut offset = 0;
+            let mut result = Ok(());
+            for (i, word) in words.enumerate() {
+                if i > 0 {
+                    result = result.and_then(|_| client.write(fid, offset, b" ").map(|_| ()));
+                    offset += 1;
+                }
+                result = result.and_then(|_| client.write(fid, offset, word).map(|_| ()));
+                offset += word.len() as u64;
+            }
+            client.clunk(fid);
+            result
+        });
+        match result {
+            Some(Ok(())) => f(b"done"),
+            Some(Err(_)) => f(b"hostput failed"),
+            None => f(b"no host share"),
+        }
+    }
+
+    fn balloon<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        let root = self.root;
+        let result = self.balloon.map(|balloon| {
+            balloon.adjust();
+            let free = container::free_npages(root) * PAGE_SIZE;
+            let avail = root.meta().free_pages.len() * PAGE_SIZE;
+            balloon.update_stats(free as u64, avail as u64);
+            alloc::format!("{}/{} pages ballooned", balloon.actual(), balloon.target())
+        });
+        match result {
+            Some(msg) => f(msg.as_bytes()),
+            None => f(b"no balloon"),
+        }
+    }
+
+    fn mem<F: FnMut(&[u8])>(&mut self, mut f: F) {
+        let mut first = true;
+        container::usage(self.root, |line| {
+            if !first {
+                f(b"\n");
+            }
+            first = false;
+            f(line);
+        });
+    }
+
+    // Copies the capture out first: `f` may be sending over the network
+    fn pcap_file(&mut self) -> Option<alloc::vec::Vec<u8>> {
+        self.net
+            .map(|iface| {
+                iface.device_mut().capture_mut().map(|capture| {
+                    let mut file = alloc::vec::Vec::new();
+                    capture.write_to(|piece| file.extend_from_slice(piece));
+                    file
+                })
+            })
+            .flatten()
+    }
+
+    fn pcap<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
+        match words.next() {
+            Some(b"start") => {
+                let size = words
+                    .next()
+                    .and_then(|size| from_utf8(size).ok())
+                    .and_then(|size| size.parse::<usize>().ok())
+                    .unwrap_or(PCAP_RING);
+                if size > PCAP_RING_MAX {
+                    return f(alloc::format!("at most {} bytes", PCAP_RING_MAX).as_bytes());
+                }
+                let started = self.net.map(|iface| {
+                    iface.device_mut().set_capture(Some(Capture::new(size, PCAP_SNAPLEN)));
+                });
+                f(if started.is_some() { b"capturing" } else { b"no network" });
+            }
+            Some(b"stop") => {
+                self.net.map(|iface| iface.device_mut().capture_mut().map(|c| c.set_paused(true)));
+                f(b"stopped");
+            }
+            Some(b"clear") => {
+                self.net.map(|iface| iface.device_mut().set_capture(None));
+                f(b"cleared");
+            }
+            // Hex, to be turned back into a file with `xxd -r -p`
+            Some(b"dump") => match self.pcap_file() {
+                Some(file) => {
+                    const HEX: &[u8; 16] = b"0123456789abcdef";
+                    for chunk in file.chunks(32) {
+                        let mut line = [0; 65];
+                        for (i, b) in chunk.iter().enumerate() {
+                            line[2 * i] = HEX[(b >> 4) as usize];
+                            line[2 * i + 1] = HEX[(b & 0xf) as usize];
+                        }
+                        line[2 * chunk.len()] = b'\n';
+                        f(&line[..2 * chunk.len() + 1]);
+                    }
+                }
+                None => f(b"no capture"),
+            },
+            // Raw, from `sector` on; the output says how much to read back
+            Some(b"save") => {
+                let sector = match words
+                    .next()
+                    .and_then(|sec| from_utf8(sec).ok())
+                    .and_then(|sec| sec.parse::<u64>().ok())
+                {
+                    Some(sector) => sector,
+                    None => return f(b"usage: pcap save <sector>"),
+                };
+                let file = match self.pcap_file() {
+                    Some(file) => file,
+                    None => return f(b"no capture"),
+                };
+                let nsectors = ((file.len() + 511) / 512) as u64;
+                let saved = self.blk.map(|blk| {
+                    // Stay clear of the snapshot at the end of the disk
+                    let end = snapshot::region_start(blk).unwrap_or_else(|_| blk.capacity());
+                    if sector.checked_add(nsectors).map_or(true, |last| last > end) {
+                        return Err(end);
+                    }
+                    for (i, chunk) in file.chunks(512).enumerate() {
+                        let mut data = [0; 512];
+                        data[..chunk.len()].copy_from_slice(chunk);
+                        blk.write(sector + i as u64, &data);
+                    }
+                    Ok(())
+                });
+                match saved {
+                    Some(Ok(())) => f(alloc::format!("saved {} bytes at sector {}", file.len(), sector).as_bytes()),
+                    Some(Err(end)) => {
+                        f(alloc::format!("{} sectors from {} don't fit below sector {}", nsectors, sector, end).as_bytes())
+                    }
+                    None => f(b"no disk"),
+                }
+            }
+            _ => {
+                let status = self.net.map(|iface| {
+                    iface.device_mut().capture_mut().map(|c| (c.records(), c.dropped(), c.is_paused()))
+                });
+                match status.flatten() {
+                    Some((records, dropped, paused)) => f(alloc::format!(
+                        "{} frames, {} overwritten{}",
+                        records,
+                        dropped,
+                        if paused { ", stopped" } else { "" }
+                    )
+                    .as_bytes()),
+                    None => f(b"usage: pcap start [bytes] | stop | clear | dump | save <sector>"),
+                }
+            }
+        }
+    }
+
     /*fn write<F: FnMut(&[u8])>(&mut self, words: &mut dyn Iterator<Item = &[u8]>, mut f: F) {
         let mut sector = words
             .next()
@@ -118,6 +350,27 @@ impl<'a, 'b> Shell<'a, 'b> {
             Some(b"read") => {
                 self.read(&mut words, f);
             }
+            Some(b"checkpoint") => {
+                self.checkpoint(f);
+            }
+            Some(b"hostls") => {
+                self.host_ls(&mut words, f);
+            }
+            Some(b"hostcat") => {
+                self.host_cat(&mut words, f);
+            }
+            Some(b"hostput") => {
+                self.host_put(&mut words, f);
+            }
+            Some(b"balloon") => {
+                self.balloon(f);
+            }
+            Some(b"pcap") => {
+                self.pcap(&mut words, f);
+            }
+            Some(b"mem") => {
+                self.mem(f);
+            }
             /*Some(b"write") => {
                 self.write(&mut words, f);
             }*/
@@ -1,5 +1,7 @@
-use crate::kobject::{KObjectRef, Container, Label, KOBJ_NPAGES};
+use crate::kobject::{KObjectRef, KObjectKind, Container, Label, Segment, KOBJ_NPAGES};
+use crate::mm::PAGE_SIZE;
 use crate::thread;
+use crate::collections::list::List;
 
 pub fn create(ct_ref: KObjectRef<Container>, label: &str) -> KObjectRef<Container> {
     // label checks
@@ -10,22 +12,204 @@ pub fn create(ct_ref: KObjectRef<Container>, label: &str) -> KObjectRef<Containe
         panic!("fail to create a container with label <{:?}>", label);
     }
 
+    let page = ct_ref.get_pages(2 * KOBJ_NPAGES).unwrap();
+    create_from(ct_ref, label, page)
+}
+
+// Makes the child out of `page` and the pages after it, already taken from
+// `ct_ref`
+fn create_from(ct_ref: KObjectRef<Container>, label: &str, page: usize) -> KObjectRef<Container> {
     let lb_slot = ct_ref.as_mut().get_slot().unwrap();
-    let lb_page = ct_ref.meta_mut().free_pages.get_multiple(KOBJ_NPAGES).unwrap();
-    let lb_ref = unsafe { Label::create(lb_page, label) };
+    let lb_ref = unsafe { Label::create(page, label) };
+    lb_ref.meta_mut().parent = Some(ct_ref);
     ct_ref.as_mut().set_slot(lb_slot, lb_ref);
 
     let new_ct_slot = ct_ref.as_mut().get_slot().unwrap();
-    let new_ct_page = ct_ref.meta_mut().free_pages.get_multiple(KOBJ_NPAGES).unwrap();
-    let new_ct_ref = unsafe { Container::create(new_ct_page) };
+    let new_ct_ref = unsafe { Container::create(page + KOBJ_NPAGES) };
     ct_ref.as_mut().set_slot(new_ct_slot, new_ct_ref);
+    new_ct_ref.meta_mut().parent = Some(ct_ref);
     new_ct_ref.meta_mut().label = Some(lb_ref);
 
     new_ct_ref
 }
 
 
-pub fn move_npages(ct_ref_1: KObjectRef<Container>, ct_ref_2: KObjectRef<Container>, npages: usize) {
+/// Creates a child of `ct_ref` labelled `label` that starts with copies of
+/// its segments, paid for out of the parent's free pages. Fails, with
+/// nothing made, if the caller may not create it, anything in the parent may
+/// not flow to it or there aren't the pages. A copy the child couldn't write
+/// to takes the child's label.
+///
+/// Only segments are forked, and they are copied up front. Sharing them
+/// copy-on-write needs the MMU and a fault handler, neither of which is set
+/// up. Threads stay behind: their stacks point into themselves, and without
+/// the MMU they can't be moved.
+pub fn fork(ct_ref: KObjectRef<Container>, label: &str) -> Option<KObjectRef<Container>> {
+    use labeled::buckle2::Buckle2;
+    use labeled::Label as _;
+
+    let local_alloc = thread::current_thread_koref()?.meta().alloc.clone();
+    let new_label = Buckle2::parse_in(label, local_alloc.clone()).ok()?;
+    let flows = |lb_ref: KObjectRef<Label>| lb_ref.as_ref().inner.can_flow_to(&new_label);
+
+    let mut segments = alloc::vec::Vec::new_in(local_alloc);
+    segments.extend(
+        ct_ref
+            .as_ref()
+            .slots
+            .iter()
+            .filter(|slot| !slot.is_null())
+            .map(|&slot| KObjectRef::<Segment>::from(slot))
+            .filter(|seg_ref| seg_ref.meta().kind == KObjectKind::Segment),
+    );
+
+    // All checked before anything is allocated, so a refusal leaves the
+    // parent as it was
+    let curr_label = thread::current_label()?;
+    let allowed = curr_label.can_flow_to(&ct_ref.label()?)
+        && flows(curr_label)
+        && flows(ct_ref.label()?)
+        && segments.iter().all(|seg_ref| seg_ref.label().map_or(false, flows));
+    if !allowed {
+        return None
+    }
+
+    // Likewise every page is taken before anything is made: the child's own,
+    // and for each copy a label, a segment object and the data
+    let npages: usize = segments.iter().map(|seg_ref| 2 * KOBJ_NPAGES + seg_ref.as_ref().npages).sum();
+    let page = ct_ref.get_pages(2 * KOBJ_NPAGES)?;
+    let copies_page = match npages {
+        0 => None,
+        _ => match ct_ref.get_pages(npages) {
+            Some(copies_page) => Some(copies_page),
+            None => {
+                unsafe { ct_ref.put_pages(page, 2 * KOBJ_NPAGES) };
+                return None
+            }
+        },
+    };
+
+    let new_ct_ref = create_from(ct_ref, label, page);
+    if let Some(copies_page) = copies_page {
+        // A new container has no quota to refuse them
+        unsafe { ct_ref.give_pages(new_ct_ref, copies_page, npages) };
+    }
+    for seg_ref in segments.iter() {
+        let seg = seg_ref.as_ref();
+        let seg_label = seg_ref.label().unwrap();
+        let copy_label = if new_label.can_flow_to(&seg_label.as_ref().inner) {
+            seg_label.as_ref().source()
+        } else {
+            label
+        };
+        // The child holds a run of exactly the pages these take
+        let new_seg_ref = crate::segment::create(new_ct_ref, copy_label, seg.npages)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (seg.start * PAGE_SIZE) as *const u8,
+                (new_seg_ref.as_ref().start * PAGE_SIZE) as *mut u8,
+                seg.npages * PAGE_SIZE,
+            )
+        };
+    }
+
+    Some(new_ct_ref)
+}
+
+/// The child container of `ct_ref` described as `descr`
+pub fn child_by_descr(ct_ref: KObjectRef<Container>, descr: &str) -> Option<KObjectRef<Container>> {
+    ct_ref
+        .as_ref()
+        .slots
+        .iter()
+        .filter(|slot| !slot.is_null())
+        .map(|&slot| KObjectRef::<Container>::from(slot))
+        .find(|ko_ref| ko_ref.meta().kind == KObjectKind::Container && ko_ref.meta().descr() == descr)
+}
+
+pub fn add_known(ct_ref: KObjectRef<Container>, known: KObjectRef<Container>) {
+    if let Some(cts) = ct_ref.as_mut().known_containers.as_mut() {
+        cts.push(known)
+    } else {
+        let alloc = ct_ref.meta().alloc.clone();
+        ct_ref.as_mut().known_containers = Some({
+            let mut list = List::new_in(alloc);
+            list.push(known);
+            list
+        });
+    }
+}
+
+
+// Free pages held by `ct_ref` and every container reachable from it
+pub fn free_npages(ct_ref: KObjectRef<Container>) -> usize {
+    let local_alloc = thread::current_thread_koref().unwrap().meta().alloc.clone();
+    let mut containers = alloc::vec::Vec::new_in(local_alloc.clone());
+    let mut visited = alloc::vec::Vec::new_in(local_alloc);
+    containers.push(ct_ref);
+    let mut count = 0;
+
+    while let Some(ct_ref) = containers.pop() {
+        if visited.contains(&ct_ref) {
+            continue
+        }
+        visited.push(ct_ref);
+        count += ct_ref.meta().free_pages.len();
+
+        if let Some(cts) = ct_ref.as_ref().known_containers.as_ref() {
+            cts.iter().for_each(|&ct| containers.push(ct))
+        }
+    }
+
+    count
+}
+
+/// Limits the pages `ct_ref` and everything below it may hold. Pages
+/// already held stay where they are.
+pub fn set_quota(ct_ref: KObjectRef<Container>, quota: Option<usize>) {
+    ct_ref.as_mut().pages.quota = quota;
+}
+
+/// Hands `f` a line per container from `ct_ref` down, indented by depth
+pub fn usage<F: FnMut(&[u8])>(ct_ref: KObjectRef<Container>, mut f: F) {
+    let local_alloc = thread::current_thread_koref().unwrap().meta().alloc.clone();
+    let mut containers = alloc::vec::Vec::new_in(local_alloc);
+    containers.push((ct_ref, 0));
+
+    while let Some((ct_ref, depth)) = containers.pop() {
+        // Children report their own arenas
+        let mut objects = [0; 6];
+        let mut bytes = ct_ref.meta().alloc.bytes();
+        for &slot in ct_ref.as_ref().slots.iter().filter(|slot| !slot.is_null()) {
+            let ko_ref = KObjectRef::<Container>::from(slot);
+            let kind = ko_ref.meta().kind;
+            objects[kind as usize] += 1;
+            match kind {
+                KObjectKind::Container => containers.push((ko_ref, depth + 1)),
+                _ => bytes += ko_ref.meta().alloc.bytes(),
+            }
+        }
+
+        let pages = ct_ref.as_ref().pages;
+        let free = ct_ref.meta().free_pages.len();
+        let descr = Some(ct_ref.meta().descr()).filter(|d| !d.is_empty()).unwrap_or("-");
+        let quota = pages.quota.map_or(alloc::string::String::from("none"), |q| alloc::format!("{}", q));
+        let line = alloc::format!(
+            "{:width$}{}: {} pages used, {} below, quota {}, {} free, {} arena bytes, \
+             {} containers, {} labels, {} threads, {} time slices, {} segments",
+            "", descr, pages.used, pages.subtree - pages.used - free, quota, free, bytes,
+            objects[KObjectKind::Container as usize], objects[KObjectKind::Label as usize],
+            objects[KObjectKind::Thread as usize], objects[KObjectKind::TimeSlices as usize],
+            objects[KObjectKind::Segment as usize],
+            width = 2 * depth,
+        );
+        f(line.as_bytes());
+    }
+}
+
+/// Moves `npages` free pages from `ct_ref_1` to `ct_ref_2`, unless that puts
+/// `ct_ref_2` or one above it over quota or `ct_ref_1` doesn't have them
+pub fn move_npages(ct_ref_1: KObjectRef<Container>, ct_ref_2: KObjectRef<Container>, npages: usize) -> Option<()> {
     // label checks (strict)
     // TODO: make it larps instead
     // let th_lb = thread::current_label().unwrap();
@@ -39,11 +223,7 @@ pub fn move_npages(ct_ref_1: KObjectRef<Container>, ct_ref_2: KObjectRef<Contain
         // panic!("fail to move {} pages", npages);
     // }
 
-    let page = ct_ref_1.meta_mut().free_pages.get_multiple(npages).unwrap();
-    (page..(page+npages))
-        .for_each(|p| unsafe {
-            ct_ref_2.meta_mut().free_pages.insert(p)
-        });
+    ct_ref_1.move_pages(ct_ref_2, npages)
 }
 
 pub fn move_time_slices() {}
@@ -60,9 +240,9 @@ pub fn search(ct_ref: KObjectRef<Container>, label: &str, avoid: KObjectRef<Cont
 
     let local_alloc = current_thread_koref().unwrap().meta().alloc.clone();
 
-    let mut containers = Vec::new();
-    let mut visited = Vec::new();
-    let mut found = Vec::new();
+    let mut containers = Vec::new_in(local_alloc.clone());
+    let mut visited = Vec::new_in(local_alloc.clone());
+    let mut found = Vec::new_in(local_alloc.clone());
 
     containers.push(ct_ref);
 
@@ -117,3 +297,64 @@ pub fn search(ct_ref: KObjectRef<Container>, label: &str, avoid: KObjectRef<Cont
     //
     //
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kobject::fixtures::{as_thread, container};
+
+    fn segments(ct_ref: KObjectRef<Container>) -> alloc::vec::Vec<KObjectRef<Segment>> {
+        ct_ref
+            .as_ref()
+            .slots
+            .iter()
+            .filter(|slot| !slot.is_null())
+            .map(|&slot| KObjectRef::<Segment>::from(slot))
+            .filter(|seg_ref| seg_ref.meta().kind == KObjectKind::Segment)
+            .collect()
+    }
+
+    #[test_case]
+    fn test_fork_copies_segments_it_may() {
+        let parent = container(None, "T,T", 64);
+        as_thread("T,T", || {
+            let seg_ref = crate::segment::create(parent, "T,T", 1).unwrap();
+            let data = (seg_ref.as_ref().start * PAGE_SIZE) as *mut u8;
+            unsafe { data.write(42) };
+
+            let child = fork(parent, "T,T").unwrap();
+            let copies = segments(child);
+            assert_eq!(copies.len(), 1);
+            assert!(copies[0].as_ref().start != seg_ref.as_ref().start);
+            assert_eq!(copies[0].label().unwrap().as_ref().source(), "T,T");
+            assert_eq!(unsafe { ((copies[0].as_ref().start * PAGE_SIZE) as *const u8).read() }, 42);
+
+            // A secret segment keeps the parent from forking a public child,
+            // and nothing is taken for the attempt
+            crate::segment::create(parent, "gongqi,T", 1).unwrap();
+            let free = parent.meta().free_pages.len();
+            let nslots = parent.as_ref().slots.len();
+            assert!(fork(parent, "T,T").is_none());
+            assert_eq!((parent.meta().free_pages.len(), parent.as_ref().slots.len()), (free, nslots));
+
+            // A secret child may read the public segment, but its copy is
+            // secret
+            let secret = fork(parent, "gongqi,T").unwrap();
+            let labels: alloc::vec::Vec<_> = segments(secret)
+                .iter()
+                .map(|seg_ref| alloc::string::String::from(seg_ref.label().unwrap().as_ref().source()))
+                .collect();
+            assert_eq!(labels, ["gongqi,T", "gongqi,T"]);
+
+            // Nor is anything made or taken when the pages run short
+            let free = parent.meta().free_pages.len();
+            let nslots = parent.as_ref().slots.len();
+            let hoard: alloc::vec::Vec<_> = (2 * KOBJ_NPAGES..free).map(|_| parent.get_pages(1).unwrap()).collect();
+            assert!(fork(parent, "gongqi,T").is_none());
+            assert_eq!(parent.meta().free_pages.len(), 2 * KOBJ_NPAGES);
+            assert_eq!(parent.as_ref().slots.len(), nslots);
+            hoard.iter().for_each(|&page| unsafe { parent.put_pages(page, 1) });
+        });
+    }
+}
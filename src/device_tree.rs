@@ -5,6 +5,15 @@ use crate::utils::*;
 
 type BE = Endian<u32, Big>;
 
+/// Reads an `ncells`-cell big-endian number off the front of `bytes`
+pub fn read_cells(bytes: &[u8], ncells: usize) -> (usize, &[u8]) {
+    let (work, rest) = bytes.split_at(ncells * 4);
+    let value = work.chunks(4).fold(0, |acc, c| {
+        acc << 32 | u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as usize
+    });
+    (value, rest)
+}
+
 #[repr(C)]
 pub struct DeviceTree {
     magic: BE,
@@ -67,6 +76,144 @@ impl DeviceTree {
     pub fn root(&self) -> Option<Node> {
         self.nodes().next()
     }
+
+    pub fn total_size(&self) -> usize {
+        self.total_size.native() as usize
+    }
+
+    /// The memory reservation block, as `(address, size)` pairs
+    pub fn reservations(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let base = unsafe {
+            (self as *const _ as *const u8)
+                .offset(self.memory_reserve_map_offset.native() as isize)
+                as *const BE
+        };
+        (0usize..)
+            .map(move |i| {
+                let word = |j: usize| unsafe { (*base.add(4 * i + j)).native() as usize };
+                (word(0) << 32 | word(1), word(2) << 32 | word(3))
+            })
+            .take_while(|&(addr, size)| addr != 0 || size != 0)
+    }
+
+    pub fn node_by_phandle(&self, phandle: u32) -> Option<Node> {
+        fn search(node: Node, phandle: u32) -> Option<Node> {
+            if node.phandle() == Some(phandle) {
+                return Some(node);
+            }
+            node.children().find_map(|child| search(child, phandle))
+        }
+        search(self.root()?, phandle)
+    }
+
+    pub fn parent_of<'a>(&'a self, node: &Node) -> Option<Node<'a>> {
+        fn search<'a>(bus: Node<'a>, target: *const BE) -> Option<Node<'a>> {
+            bus.children()
+                .find_map(|child| if child.base == target { Some(bus) } else { search(child, target) })
+        }
+        search(self.root()?, node.base)
+    }
+
+    /// The interrupt controller named by the nearest `interrupt-parent` on
+    /// `node` or above it
+    pub fn interrupt_parent<'a>(&'a self, node: &Node<'a>) -> Option<Node<'a>> {
+        let mut cur = *node;
+        loop {
+            if let Some(phandle) = cur.prop_u32("interrupt-parent") {
+                return self.node_by_phandle(phandle);
+            }
+            cur = self.parent_of(&cur)?;
+        }
+    }
+
+    /// The specifiers in `node`'s `interrupts`, each as many cells long as
+    /// its interrupt parent's `#interrupt-cells`
+    pub fn interrupts<'a>(&'a self, node: &Node<'a>) -> impl Iterator<Item = &'a [u8]> {
+        let cells = self
+            .interrupt_parent(node)
+            .and_then(|intc| intc.prop_u32("#interrupt-cells"))
+            .unwrap_or(1) as usize;
+        node.prop_by_name("interrupts")
+            .map_or(&[][..], |prop| prop.value)
+            .chunks_exact(cells.max(1) * 4)
+    }
+
+    /// The entries of `node`'s `interrupt-map` as (child, parent, specifier):
+    /// the child unit address and interrupt specifier in `node`'s own cells,
+    /// the interrupt parent it maps to and the specifier in the parent's
+    /// cells. Each entry names its own parent, whose cell sizes decide where
+    /// the next entry starts.
+    pub fn interrupt_map<'a>(&'a self, node: &Node<'a>) -> impl Iterator<Item = (&'a [u8], Node<'a>, &'a [u8])> + 'a {
+        let child_cells = node.cells().0 + node.prop_u32("#interrupt-cells").unwrap_or(1) as usize;
+        let mut map = node.prop_by_name("interrupt-map").map_or(&[][..], |prop| prop.value);
+        core::iter::from_fn(move || {
+            if map.len() < (child_cells + 1) * 4 {
+                return None;
+            }
+            let (child, rest) = map.split_at(child_cells * 4);
+            let (phandle, rest) = read_cells(rest, 1);
+            let parent = self.node_by_phandle(phandle as u32)?;
+            // The parent's unit address isn't needed to find the interrupt
+            let address_cells = parent.prop_u32("#address-cells").unwrap_or(0) as usize;
+            let interrupt_cells = parent.prop_u32("#interrupt-cells").unwrap_or(1) as usize;
+            if rest.len() < (address_cells + interrupt_cells) * 4 {
+                return None;
+            }
+            let (spec, rest) = rest[address_cells * 4..].split_at(interrupt_cells * 4);
+            map = rest;
+            Some((child, parent, spec))
+        })
+    }
+
+    /// `node`'s `reg` entries as CPU (address, size) pairs. Addresses are
+    /// translated through the `ranges` of every bus above the node; entries
+    /// that fall outside them are skipped.
+    pub fn regs<'a>(&'a self, node: &Node<'a>) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let parent = self.parent_of(node);
+        let (address_cells, size_cells) = parent.map_or((2, 1), |bus| bus.cells());
+        let mut regs = node.prop_by_name("reg").map_or(&[][..], |prop| prop.value);
+        core::iter::from_fn(move || {
+            while address_cells + size_cells > 0 && regs.len() >= (address_cells + size_cells) * 4 {
+                let (addr, rest) = read_cells(regs, address_cells);
+                let (size, rest) = read_cells(rest, size_cells);
+                regs = rest;
+                let addr = match parent {
+                    Some(bus) => self.translate(addr, bus),
+                    None => Some(addr),
+                };
+                if let Some(addr) = addr {
+                    return Some((addr, size));
+                }
+            }
+            None
+        })
+    }
+
+    // Takes `addr` from `bus`'s address space up to the root's
+    fn translate<'a>(&'a self, mut addr: usize, mut bus: Node<'a>) -> Option<usize> {
+        while let Some(parent) = self.parent_of(&bus) {
+            let (child_cells, size_cells) = bus.cells();
+            let (parent_cells, _) = parent.cells();
+            let mut ranges = bus.prop_by_name("ranges")?.value;
+            // An empty `ranges` maps addresses one to one
+            if !ranges.is_empty() {
+                let mut found = None;
+                while ranges.len() >= (child_cells + parent_cells + size_cells) * 4 {
+                    let (child, rest) = read_cells(ranges, child_cells);
+                    let (parent_addr, rest) = read_cells(rest, parent_cells);
+                    let (size, rest) = read_cells(rest, size_cells);
+                    ranges = rest;
+                    if child <= addr && addr - child < size {
+                        found = Some(parent_addr + (addr - child));
+                        break;
+                    }
+                }
+                addr = found?;
+            }
+            bus = parent;
+        }
+        Some(addr)
+    }
 }
 
 #[repr(C)]
@@ -152,11 +299,29 @@ impl<'a> Node<'a> {
         }
     }
 
-    pub fn prop_by_name(&self, name: &str) -> Option<Prop> {
+    pub fn prop_by_name(&self, name: &str) -> Option<Prop<'a>> {
         let name = name.as_bytes();
         self.props().find(|prop| prop.name == name)
     }
 
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.prop_by_name(name)
+            .filter(|prop| prop.value.len() >= 4)
+            .map(|prop| read_cells(prop.value, 1).0 as u32)
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle").or_else(|| self.prop_u32("linux,phandle"))
+    }
+
+    /// `#address-cells` and `#size-cells` for this node's children
+    pub fn cells(&self) -> (usize, usize) {
+        (
+            self.prop_u32("#address-cells").unwrap_or(2) as usize,
+            self.prop_u32("#size-cells").unwrap_or(1) as usize,
+        )
+    }
+
     pub fn children(&self) -> NodeIterator<'a> {
         NodeIterator {
             struct_base: self.base,
@@ -277,3 +442,215 @@ impl<'a> Iterator for NodeIterator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Lays out a flattened device tree, a node or property at a time
+    #[derive(Default)]
+    struct Builder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    fn cells(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    impl Builder {
+        fn pad(&mut self) {
+            while self.structure.len() % 4 != 0 {
+                self.structure.push(0);
+            }
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.structure.extend(cells(&[1]));
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.structure.extend(cells(&[2]));
+            self
+        }
+
+        fn prop(&mut self, name: &str, values: &[u32]) -> &mut Self {
+            let nameoff = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.structure.extend(cells(&[3, 4 * values.len() as u32, nameoff]));
+            self.structure.extend(cells(values));
+            self
+        }
+
+        // In words, so the header lands aligned
+        fn finish(&mut self) -> Vec<u32> {
+            self.structure.extend(cells(&[9]));
+            let header_len = 40;
+            let reserve = cells(&[0; 4]);
+            let structure_at = header_len + reserve.len();
+            let strings_at = structure_at + self.structure.len();
+            let total = strings_at + self.strings.len();
+
+            let mut blob = cells(&[
+                0xd00dfeed,
+                total as u32,
+                structure_at as u32,
+                strings_at as u32,
+                header_len as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structure.len() as u32,
+            ]);
+            blob.extend(reserve);
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+            while blob.len() % 4 != 0 {
+                blob.push(0);
+            }
+            blob.chunks(4).map(|w| u32::from_ne_bytes([w[0], w[1], w[2], w[3]])).collect()
+        }
+    }
+
+    // A GIC-like controller at the root, and a bus with its own controller
+    // and a bus nested inside it, neither mapped one to one
+    fn blob() -> Vec<u32> {
+        Builder::default()
+            .begin("")
+            .prop("#address-cells", &[2])
+            .prop("#size-cells", &[2])
+            .prop("interrupt-parent", &[1])
+                .begin("intc")
+                .prop("phandle", &[1])
+                .prop("#interrupt-cells", &[3])
+                .end()
+                .begin("uart")
+                .prop("reg", &[0, 0x0900_0000, 0, 0x1000])
+                .prop("interrupts", &[0, 1, 4])
+                .end()
+                .begin("bus")
+                .prop("#address-cells", &[1])
+                .prop("#size-cells", &[1])
+                .prop("ranges", &[0, 0, 0x1000_0000, 0x10000])
+                .prop("interrupt-parent", &[2])
+                    .begin("intc2")
+                    .prop("phandle", &[2])
+                    .prop("#interrupt-cells", &[1])
+                    .end()
+                    .begin("dev")
+                    // The second is past the end of the bus's window
+                    .prop("reg", &[0x100, 0x10, 0x20000, 0x10])
+                    .prop("interrupts", &[7, 8])
+                    .end()
+                    .begin("sub")
+                    .prop("#address-cells", &[1])
+                    .prop("#size-cells", &[1])
+                    .prop("ranges", &[0, 0x4000, 0x1000])
+                        .begin("leaf")
+                        .prop("reg", &[0x20, 0x8])
+                        .prop("interrupts", &[9])
+                        .end()
+                    .end()
+                .end()
+                // Interrupts mapped to both controllers, whose specifiers
+                // differ in length
+                .begin("pcie")
+                .prop("#address-cells", &[3])
+                .prop("#size-cells", &[2])
+                .prop("#interrupt-cells", &[1])
+                .prop("interrupt-map", &[
+                    0x800, 0, 0, 1, 1, 0, 5, 4,
+                    0x1000, 0, 0, 2, 2, 6,
+                    0x1800, 0, 0, 1, 1, 0, 7, 4,
+                ])
+                .end()
+                .begin("nobus")
+                .prop("#address-cells", &[1])
+                .prop("#size-cells", &[1])
+                    .begin("dev")
+                    .prop("reg", &[0x100, 0x10])
+                    .end()
+                .end()
+            .end()
+            .finish()
+    }
+
+    #[test_case]
+    fn test_device_tree_translates_through_nested_buses() {
+        let blob = blob();
+        let dtb = unsafe { DeviceTree::from_address(blob.as_ptr() as *const DeviceTree) };
+        let root = dtb.root().unwrap();
+
+        let uart = root.child_by_name("uart").unwrap();
+        assert_eq!(dtb.regs(&uart).collect::<Vec<_>>(), [(0x0900_0000, 0x1000)]);
+
+        let bus = root.child_by_name("bus").unwrap();
+        let dev = bus.child_by_name("dev").unwrap();
+        assert_eq!(dtb.parent_of(&dev).unwrap().name, b"bus");
+        assert_eq!(dtb.regs(&dev).collect::<Vec<_>>(), [(0x1000_0100, 0x10)]);
+
+        // Through both buses' ranges
+        let sub = bus.child_by_name("sub").unwrap();
+        let leaf = sub.child_by_name("leaf").unwrap();
+        assert_eq!(dtb.regs(&leaf).collect::<Vec<_>>(), [(0x1000_4020, 0x8)]);
+
+        // A bus without `ranges` isn't reachable from the CPU
+        let nobus = root.child_by_name("nobus").unwrap();
+        let unmapped = nobus.child_by_name("dev").unwrap();
+        assert_eq!(dtb.regs(&unmapped).count(), 0);
+    }
+
+    #[test_case]
+    fn test_device_tree_inherits_interrupt_parents() {
+        let blob = blob();
+        let dtb = unsafe { DeviceTree::from_address(blob.as_ptr() as *const DeviceTree) };
+        let root = dtb.root().unwrap();
+        assert_eq!(dtb.node_by_phandle(2).unwrap().name, b"intc2");
+        assert!(dtb.node_by_phandle(3).is_none());
+
+        let uart = root.child_by_name("uart").unwrap();
+        assert_eq!(dtb.interrupt_parent(&uart).unwrap().name, b"intc");
+        let specs: Vec<_> = dtb.interrupts(&uart).collect();
+        assert_eq!(specs, [&cells(&[0, 1, 4])[..]]);
+
+        // Two levels down, from the bus the leaf sits under
+        let bus = root.child_by_name("bus").unwrap();
+        let sub = bus.child_by_name("sub").unwrap();
+        let leaf = sub.child_by_name("leaf").unwrap();
+        assert_eq!(dtb.interrupt_parent(&leaf).unwrap().name, b"intc2");
+        assert_eq!(dtb.interrupts(&leaf).map(|spec| read_cells(spec, 1).0).collect::<Vec<_>>(), [9]);
+
+        let dev = bus.child_by_name("dev").unwrap();
+        assert_eq!(dtb.interrupts(&dev).count(), 2);
+    }
+
+    #[test_case]
+    fn test_device_tree_walks_interrupt_maps() {
+        let blob = blob();
+        let dtb = unsafe { DeviceTree::from_address(blob.as_ptr() as *const DeviceTree) };
+        let root = dtb.root().unwrap();
+
+        let pcie = root.child_by_name("pcie").unwrap();
+        let entries: Vec<_> = dtb.interrupt_map(&pcie).collect();
+        assert_eq!(entries.len(), 3);
+        let expected = [
+            (cells(&[0x800, 0, 0, 1]), &b"intc"[..], cells(&[0, 5, 4])),
+            (cells(&[0x1000, 0, 0, 2]), &b"intc2"[..], cells(&[6])),
+            (cells(&[0x1800, 0, 0, 1]), &b"intc"[..], cells(&[0, 7, 4])),
+        ];
+        for ((child, parent, spec), (want_child, want_parent, want_spec)) in entries.iter().zip(expected.iter()) {
+            assert_eq!((*child, parent.name, *spec), (&want_child[..], *want_parent, &want_spec[..]));
+        }
+
+        // Nothing to walk where there's no map
+        let uart = root.child_by_name("uart").unwrap();
+        assert_eq!(dtb.interrupt_map(&uart).count(), 0);
+    }
+}
@@ -2,7 +2,7 @@ use core::arch::asm;
 use core::sync::atomic::{AtomicBool, Ordering};
 use core::fmt::Write;
 
-use crate::{timer, gic, thread};
+use crate::{timer, gic, thread, mutex};
 
 static YIELD_BEFORE_RETURN: AtomicBool = AtomicBool::new(false);
 
@@ -65,6 +65,23 @@ const INTERRUPTS: &[(u32, &dyn Fn(u32, &Frame))] = &[
     (InterruptIndex::Timer as u32, &timer_interrupt_handler),
 ];
 
+const MAX_DEVICE_HANDLERS: usize = 16;
+
+// Handlers drivers register for their device interrupts
+static DEVICE_HANDLERS: mutex::Mutex<[Option<(u32, fn(u32))>; MAX_DEVICE_HANDLERS]> =
+    mutex::Mutex::new([None; MAX_DEVICE_HANDLERS]);
+
+pub fn register_handler(irq: u32, handler: fn(u32)) {
+    with_intr_disabled(|| {
+        let mut handlers = DEVICE_HANDLERS.lock();
+        let slot = handlers
+            .iter_mut()
+            .find(|h| h.map_or(true, |(i, _)| i == irq))
+            .expect("too many device handlers");
+        *slot = Some((irq, handler));
+    });
+}
+
 #[no_mangle]
 pub extern "C" fn exception_handler(info: Info, frame: &Frame) {
     match info.desc {
@@ -81,6 +98,14 @@ pub extern "C" fn exception_handler(info: Info, frame: &Frame) {
                     }
                 }
 
+                let handlers = *DEVICE_HANDLERS.lock();
+                for &(irq, handler) in handlers.iter().flatten() {
+                    if gic::is_pending(irq) {
+                        handler(irq);
+                        gic::clear(irq);
+                    }
+                }
+
                 if YIELD_BEFORE_RETURN
                     .compare_exchange(true, false, Ordering::SeqCst, Ordering::Relaxed)
                     == Ok(true)
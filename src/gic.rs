@@ -173,4 +173,8 @@ impl GIC {
     pub fn clear(&self) {
         clear(self.0)
     }
+
+    pub fn num(&self) -> u32 {
+        self.0
+    }
 }
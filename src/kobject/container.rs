@@ -15,8 +15,19 @@ pub enum TimeSlice {
     Redirect(KObjectRef<Container>), // redirect control
 }
 
+/// Pages a container has handed out, and how many it and everything below
+/// it may hold
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageUsage {
+    pub used: usize,
+    // Pages held, free or in use, by this container and every descendant
+    pub subtree: usize,
+    pub quota: Option<usize>,
+}
+
 pub struct Container {
     pub slots: Vec<KObjectPtr, KObjectArena>,
+    pub pages: PageUsage,
     pub scheduler: Option<KObjectRef<Thread>>,
     pub known_containers: Option<List<KObjectRef<Container>, KObjectArena>>,
     pub time_slices: Option<Vec<TimeSlice, KObjectArena>>,
@@ -39,6 +50,7 @@ impl Container {
             .as_ptr()
             .write(Container {
                 slots: Vec::new_in(ct_ref.meta().alloc.clone()),
+                pages: PageUsage::default(),
                 scheduler: None,
                 known_containers: None,
                 time_slices: None,
@@ -71,3 +83,115 @@ impl Container {
 
     fn free() {}
 }
+
+impl KObjectRef<Container> {
+    // This container and those above it, nearest first
+    pub(super) fn ancestors(self) -> impl Iterator<Item = KObjectRef<Container>> {
+        core::iter::successors(Some(self), |ct_ref| ct_ref.meta().parent)
+    }
+
+    // Those of this container and its ancestors that `other` isn't below
+    fn ancestors_apart(self, other: KObjectRef<Container>) -> impl Iterator<Item = KObjectRef<Container>> {
+        self.ancestors().take_while(move |&ct_ref| !other.ancestors().any(|a| a == ct_ref))
+    }
+
+    /// Takes `npages` contiguous free pages into use. What a container holds
+    /// doesn't change, so neither does where it stands against quotas.
+    pub fn get_pages(self, npages: usize) -> Option<usize> {
+        let page = self.meta_mut().free_pages.get_multiple(npages)?;
+        self.as_mut().pages.used += npages;
+        Some(page)
+    }
+
+    // Whether `to` and those above it may hold `npages` more from here.
+    // Those above both hold the same pages before and after.
+    fn may_move(self, to: KObjectRef<Container>, npages: usize) -> bool {
+        let over = |ct_ref: KObjectRef<Container>| {
+            let pages = ct_ref.as_ref().pages;
+            pages.quota.map_or(false, |quota| pages.subtree + npages > quota)
+        };
+        !to.ancestors_apart(self).any(over)
+    }
+
+    fn moved(self, to: KObjectRef<Container>, npages: usize) {
+        self.ancestors_apart(to).for_each(|ct_ref| ct_ref.as_mut().pages.subtree -= npages);
+        to.ancestors_apart(self).for_each(|ct_ref| ct_ref.as_mut().pages.subtree += npages);
+    }
+
+    /// Hands `npages` contiguous free pages to `to`, unless that would put
+    /// `to` or one above it over quota
+    pub fn move_pages(self, to: KObjectRef<Container>, npages: usize) -> Option<()> {
+        if !self.may_move(to, npages) {
+            return None
+        }
+
+        let page = self.meta_mut().free_pages.get_multiple(npages)?;
+        unsafe { to.meta_mut().free_pages.put_multiple(page, npages) };
+        self.moved(to, npages);
+        Some(())
+    }
+
+    /// Like `move_pages`, but for pages already taken with `get_pages`
+    // Safety: the pages must no longer be in use
+    pub unsafe fn give_pages(self, to: KObjectRef<Container>, start: usize, npages: usize) -> Option<()> {
+        if !self.may_move(to, npages) {
+            return None
+        }
+
+        to.meta_mut().free_pages.put_multiple(start, npages);
+        self.as_mut().pages.used -= npages;
+        self.moved(to, npages);
+        Some(())
+    }
+
+    /// Gives back pages from `get_pages`
+    // Safety: the pages must no longer be in use
+    pub unsafe fn put_pages(self, start: usize, npages: usize) {
+        self.meta_mut().free_pages.put_multiple(start, npages);
+        self.as_mut().pages.used -= npages;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::kobject::fixtures::container;
+
+    #[test_case]
+    fn test_quotas_cover_descendants() {
+        let root = container(None, "T,T", 16);
+        let parent = container(Some(root), "T,T", 0);
+        let child = container(Some(parent), "T,T", 0);
+        parent.as_mut().pages.quota = Some(6);
+
+        assert!(root.move_pages(child, 4).is_some());
+        assert_eq!((child.as_ref().pages.subtree, parent.as_ref().pages.subtree), (4, 4));
+        // The child has no quota of its own, but its parent's is spent
+        assert!(root.move_pages(child, 3).is_none());
+        assert!(root.move_pages(parent, 2).is_some());
+        assert_eq!(root.as_ref().pages.subtree, 16);
+
+        // Using pages, or passing them within the parent, holds no more
+        let page = child.get_pages(4).unwrap();
+        assert_eq!((child.as_ref().pages.used, parent.as_ref().pages.subtree), (4, 6));
+        unsafe { child.put_pages(page, 4) };
+        assert!(child.move_pages(parent, 4).is_some());
+        assert_eq!((child.as_ref().pages.subtree, parent.as_ref().pages.subtree), (0, 6));
+    }
+
+    #[test_case]
+    fn test_moved_pages_count_against_quota() {
+        let root = container(None, "T,T", 16);
+        let child = container(Some(root), "T,T", 2);
+        child.as_mut().pages.quota = Some(8);
+        child.get_pages(2).unwrap();
+
+        // Two in use and four free leaves room for two more
+        assert!(root.move_pages(child, 4).is_some());
+        assert!(root.move_pages(child, 3).is_none());
+        assert!(root.move_pages(child, 2).is_some());
+        assert_eq!((root.meta().free_pages.len(), child.meta().free_pages.len()), (10, 6));
+        // Nor can more be moved than there is
+        assert!(child.move_pages(root, 7).is_none());
+    }
+}
@@ -0,0 +1,45 @@
+//! Kernel objects for tests. Their pages come from the heap rather than
+//! from a container.
+
+use core::alloc::Layout;
+
+use super::{Container, KObjectRef, Label, Thread, KOBJ_NPAGES, THREAD_NPAGES};
+use crate::mm::page_tree::PageTree;
+use crate::mm::{pgid, PAGE_SIZE};
+
+/// `npages` fresh pages, by page id
+pub fn pages(npages: usize) -> usize {
+    let layout = Layout::from_size_align(npages * PAGE_SIZE, PAGE_SIZE).unwrap();
+    pgid!(unsafe { alloc::alloc::alloc(layout) } as usize)
+}
+
+/// `npages` fresh pages, free for the taking
+pub fn page_tree(npages: usize) -> PageTree {
+    if npages == 0 {
+        return PageTree::empty();
+    }
+    unsafe { PageTree::new(pages(npages) * PAGE_SIZE, npages * PAGE_SIZE) }
+}
+
+/// Gives `ko_ref` a label of its own
+pub fn labelled<T>(ko_ref: KObjectRef<T>, label: &str) -> KObjectRef<T> {
+    ko_ref.meta_mut().label = Some(unsafe { Label::create(pages(KOBJ_NPAGES), label) });
+    ko_ref
+}
+
+/// A container labelled `label`, under `parent` and with `npages` free pages
+pub fn container(parent: Option<KObjectRef<Container>>, label: &str, npages: usize) -> KObjectRef<Container> {
+    let ct_ref = unsafe { Container::create(pages(KOBJ_NPAGES)) };
+    ct_ref.meta_mut().parent = parent;
+    ct_ref.meta_mut().free_pages = page_tree(npages);
+    ct_ref.ancestors().for_each(|ct_ref| ct_ref.as_mut().pages.subtree += npages);
+    labelled(ct_ref, label)
+}
+
+/// Runs `f` as a thread labelled `label` would
+pub fn as_thread<F: FnOnce()>(label: &str, f: F) {
+    let th_ref = labelled(unsafe { Thread::create(pages(THREAD_NPAGES), || {}) }, label);
+    unsafe { crate::thread::init_thread(th_ref.as_ptr()) };
+    f();
+    unsafe { crate::thread::init_thread(core::ptr::null()) };
+}
@@ -1,25 +1,38 @@
 use labeled::buckle2::{Buckle2 as Buckle, Component};
 use labeled::{Label as IsLabel, HasPrivilege};
 
+use alloc::vec::Vec;
+
 use super::{KObjectRef, KObjectArena};
-use super::kobject_create;
+use super::kobject_create_with_description;
 
 pub struct Label {
     pub inner: Buckle<KObjectArena>,
+    // What the label was parsed from, so it can be written out again, e.g.
+    // by a snapshot. The description only keeps a prefix of it.
+    source: Vec<u8, KObjectArena>,
 }
 
 impl Label {
     pub unsafe fn create(pg: usize, input: &str) -> KObjectRef<Label> {
-        let lb_ref = kobject_create!(Label, pg);
+        let lb_ref = kobject_create_with_description!(Label, pg, input);
+        let alloc = lb_ref.meta().alloc.clone();
+        let mut source = Vec::new_in(alloc.clone());
+        source.extend_from_slice(input.as_bytes());
         lb_ref
             .as_ptr()
             .write(Label {
-                inner: Buckle::parse_in(input, lb_ref.meta().alloc.clone()).unwrap(),
+                inner: Buckle::parse_in(input, alloc).unwrap(),
+                source,
             });
 
         lb_ref
     }
 
+    pub fn source(&self) -> &str {
+        core::str::from_utf8(&self.source).unwrap()
+    }
+
     // IsLabel and HasPrivilege contain trait functions that consume the struct
     // We write our own here because it requires extra custom allocator for
     // the allocation.
@@ -3,11 +3,15 @@ use core::marker::PhantomData;
 
 mod container;
 mod label;
+mod segment;
 mod thread;
 mod time_slices;
+#[cfg(test)]
+pub mod fixtures;
 
 pub use container::Container;
 pub use label::Label;
+pub use segment::{Access, Segment};
 pub use thread::{Thread, STACK_SIZE, THREAD_NPAGES};
 pub use time_slices::{TimeSlices, TSlice};
 pub use container::TimeSlice;
@@ -17,7 +21,7 @@ use crate::mm::koarena::KObjectArena;
 use crate::mm::{pa, PAGE_SIZE};
 
 const INVALID_KOBJ_ID: usize = usize::MAX;
-const KOBJ_DESCR_LEN: usize = 32;
+pub const KOBJ_DESCR_LEN: usize = 32;
 pub const KOBJ_NPAGES: usize = 2; // first: meta data; second: kobject
 
 #[derive(Clone, Copy)]
@@ -31,6 +35,7 @@ pub enum KObjectKind {
     Label,
     Thread,
     TimeSlices,
+    Segment,
 }
 
 // A Kobject has a minimial 2 pages
@@ -38,7 +43,7 @@ pub enum KObjectKind {
 pub struct KObjectMeta {
     pub parent: Option<KObjectRef<Container>>, // TODO: atomic?
     pub label: Option<KObjectRef<Label>>, // TODO: need to be atomic
-    pub alloc: KObjectArena, // if oom, get one page from its page tree
+    pub alloc: KObjectArena, // if oom, gets pages from its container
     pub kind: KObjectKind,
     pub free_pages: PageTree,
     pub descr: [u8; KOBJ_DESCR_LEN],
@@ -56,12 +61,16 @@ impl KObjectMeta {
         }
     }
 
-    fn descr(&self) -> &str {
+    pub fn descr(&self) -> &str {
         core::str::from_utf8(&self.descr)
             .unwrap()
             .trim_end_matches(char::from(0))
     }
 
+    pub fn set_descr(&mut self, descr: &str) {
+        self.descr = descr_to_buf(descr);
+    }
+
     // fn as_ref<T>(&self) -> KObjectRef<T> {
         // match self.kind {
             // KObjectKind::None => todo!(),
@@ -209,6 +218,7 @@ impl_from_koptr_for_koref!(Container);
 impl_from_koptr_for_koref!(Thread);
 impl_from_koptr_for_koref!(Label);
 impl_from_koptr_for_koref!(TimeSlices);
+impl_from_koptr_for_koref!(Segment);
 
 
 macro_rules! kobject_create {
@@ -227,6 +237,17 @@ pub(crate) use kobject_create;
 pub(crate) use kobject_create_with_description;
 
 
+fn descr_to_buf(descr: &str) -> [u8; KOBJ_DESCR_LEN] {
+    let mut buf = [0u8; KOBJ_DESCR_LEN];
+    let len = if descr.len() > KOBJ_DESCR_LEN {
+        KOBJ_DESCR_LEN
+    } else {
+        descr.len()
+    };
+    buf[..len].copy_from_slice(&descr.as_bytes()[..len]);
+    buf
+}
+
 unsafe fn _kobject_create<T>(kind: KObjectKind, page_id: usize, descr: &str) -> KObjectRef<T>
 where
     KObjectRef<T>: From<KObjectPtr>
@@ -238,42 +259,26 @@ where
                 parent: None,
                 label: None,
                 alloc: KObjectArena::new(
+                    page_id,
                     pa!(page_id + 1) + size_of::<T>(),
                     THREAD_NPAGES * PAGE_SIZE - size_of::<T>()
                 ),
                 kind,
                 free_pages: PageTree::empty(),
-                descr: {
-                    let mut buf = [0u8; KOBJ_DESCR_LEN];
-                    let len = if descr.len() > KOBJ_DESCR_LEN {
-                        KOBJ_DESCR_LEN
-                    } else {
-                        descr.len()
-                    };
-                    buf[..len].copy_from_slice(&descr.as_bytes()[..len]);
-                    buf
-                }
+                descr: descr_to_buf(descr),
             }
         } else {
             KObjectMeta {
                 parent: None,
                 label: None,
                 alloc: KObjectArena::new(
+                    page_id,
                     pa!(page_id + 1) + size_of::<T>(),
                     PAGE_SIZE - size_of::<T>()
                 ),
                 kind,
                 free_pages: PageTree::empty(),
-                descr: {
-                    let mut buf = [0u8; KOBJ_DESCR_LEN];
-                    let len = if descr.len() > KOBJ_DESCR_LEN {
-                        KOBJ_DESCR_LEN
-                    } else {
-                        descr.len()
-                    };
-                    buf[..len].copy_from_slice(&descr.as_bytes()[..len]);
-                    buf
-                }
+                descr: descr_to_buf(descr),
             }
         }
     );
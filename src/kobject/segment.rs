@@ -0,0 +1,59 @@
+use alloc::vec::Vec;
+
+use super::{KObjectRef, KObjectArena};
+use super::kobject_create;
+use super::Container;
+
+use crate::mm::PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub container: KObjectRef<Container>,
+    pub access: Access,
+}
+
+/// Pages several containers can map, each as far as the segment's label
+/// allows
+pub struct Segment {
+    pub start: usize, // first data page
+    pub npages: usize,
+    pub mappings: Vec<Mapping, KObjectArena>,
+}
+
+unsafe impl Send for Segment {}
+
+impl Segment {
+    pub unsafe fn create(page: usize, start: usize, npages: usize) -> KObjectRef<Segment> {
+        let seg_ref = kobject_create!(Segment, page);
+        core::ptr::write_bytes((start * PAGE_SIZE) as *mut u8, 0, npages * PAGE_SIZE);
+        seg_ref
+            .as_ptr()
+            .write(Segment {
+                start,
+                npages,
+                mappings: Vec::new_in(seg_ref.meta().alloc.clone()),
+            });
+
+        seg_ref
+    }
+
+    pub fn mapping(&self, ct_ref: KObjectRef<Container>) -> Option<Access> {
+        self.mappings
+            .iter()
+            .find(|m| m.container == ct_ref)
+            .map(|m| m.access)
+    }
+
+    pub fn set_mapping(&mut self, ct_ref: KObjectRef<Container>, access: Option<Access>) {
+        self.mappings.retain(|m| m.container != ct_ref);
+        if let Some(access) = access {
+            self.mappings.push(Mapping { container: ct_ref, access });
+        }
+    }
+}
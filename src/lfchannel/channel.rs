@@ -4,7 +4,6 @@ use core::alloc::Allocator;
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use alloc::alloc::Global;
 
 use super::list::List;
 
@@ -15,7 +14,7 @@ struct Entry<T: Clone> {
     version: u64,
 }
 
-struct Channel<T: Clone, A: Allocator + Clone = Global> {
+struct Channel<T: Clone, A: Allocator + Clone> {
     chan: List<Entry<T>, A>,
     version: AtomicU64,
     destroy: AtomicBool,
@@ -31,11 +30,6 @@ impl<T: Clone, A: Allocator + Clone> Channel<T, A> {
     }
 }
 
-pub fn channel<T: Clone>() -> (Sender<T>, Receiver<T>) {
-    channel_in(Global)
-}
-
-
 pub fn channel_in<T: Clone, A: Allocator + Clone>(alloc: A) -> (Sender<T, A>, Receiver<T, A>) {
     let channel = Box::into_raw(Box::new_in(Channel::new_in(alloc.clone()), alloc));
     let s = Sender { channel };
@@ -48,7 +42,7 @@ pub fn channel_in<T: Clone, A: Allocator + Clone>(alloc: A) -> (Sender<T, A>, Re
 // Sender
 //////////////
 
-pub struct Sender<T: Clone, A: Allocator + Clone = Global> {
+pub struct Sender<T: Clone, A: Allocator + Clone> {
     channel: *mut Channel<T, A>,
 }
 
@@ -68,7 +62,7 @@ unsafe impl<T: Send + Clone, A: Send + Allocator + Clone> Send for Sender<T, A>
 // Inner Receiver
 //////////////
 
-pub struct Receiver<T: Clone, A: Allocator + Clone = Global> {
+pub struct Receiver<T: Clone, A: Allocator + Clone> {
     channel: *const Channel<T, A>,
 }
 
@@ -88,10 +82,6 @@ impl<T: Clone, A: Allocator + Clone> WrapperReceiver<T, A> {
         Self { receiver, last_seen: Cell::new(0) }
     }
 
-    pub fn recv(&self) -> Option<Vec<T>> {
-        self.recv_in(Global)
-    }
-
     pub fn recv_in<B: Allocator + Clone>(&self, alloc: B) -> Option<Vec<T, B>> {
         let channel = unsafe { &*self.receiver.channel };
         let destroyed = channel.destroy.load(Ordering::Relaxed);
@@ -126,22 +116,23 @@ unsafe impl<T: Send + Clone, A: Send + Allocator + Clone> Send for WrapperReceiv
 mod tests {
     use super::*;
     use alloc::vec;
+    use alloc::alloc::Global;
 
     #[test_case]
     fn test_channel() {
-        let (tx, rx) = channel::<i32>();
+        let (tx, rx) = channel_in::<i32, _>(Global);
         let rx = WrapperReceiver::new(rx);
 
         tx.send(1337);
         tx.send(1338);
         tx.send(1339);
-        assert_eq!(Some(vec![1339, 1338, 1337]), rx.recv());
+        assert_eq!(Some(vec![1339, 1338, 1337]), rx.recv_in(Global));
 
         tx.send(1340);
         tx.send(1341);
-        assert_eq!(Some(vec![1341, 1340]), rx.recv());
-        assert_eq!(None, rx.recv());
-        assert_eq!(None, rx.recv());
+        assert_eq!(Some(vec![1341, 1340]), rx.recv_in(Global));
+        assert_eq!(None, rx.recv_in(Global));
+        assert_eq!(None, rx.recv_in(Global));
     }
 
 }
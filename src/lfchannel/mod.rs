@@ -1,5 +1,5 @@
 mod list;
 mod channel;
 
-pub use channel::channel;
+pub use channel::channel_in;
 pub use channel::WrapperReceiver;
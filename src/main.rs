@@ -16,6 +16,7 @@ use alloc::collections::VecDeque;
 pub mod device_tree;
 pub mod gic;
 pub mod mutex;
+pub mod pci;
 pub mod thread;
 pub mod uart;
 pub mod utils;
@@ -30,8 +31,14 @@ mod kobject;
 mod schedule;
 mod lfchannel;
 mod container;
+mod segment;
+mod snapshot;
+mod ninep;
+mod net;
+mod random;
 
-use virtio::VirtIORegs;
+use virtio::{Transport, VirtIORegs};
+use mm::memmap::Region;
 
 #[cfg(target_arch = "aarch64")]
 global_asm!(
@@ -45,9 +52,6 @@ use core::panic::PanicInfo;
 use core::arch::{asm, global_asm};
 // use core::time::Duration;
 
-use mm::PAGE_SIZE;
-
-
 extern "C" {
     static HEAP_START: usize;
     fn system_off() -> !;
@@ -61,17 +65,6 @@ fn null_terminated_str(bytes: &[u8]) -> &[u8] {
     }
 }
 
-fn regs_to_usize(regs: &[u8], cell_size: usize) -> (usize, &[u8]) {
-    let mut result = 0;
-    let (work, rest) = regs.split_at(cell_size * 4);
-    for chunk in work.chunks(4) {
-        let mut c = [0; 4];
-        c.copy_from_slice(chunk);
-        result = result << 32 | (u32::from_be_bytes(c) as usize);
-    }
-    (result, rest)
-}
-
 fn get_interrupt(irq_type: usize, irq: usize) -> u32 {
     if irq_type == 0 {
         // SPI
@@ -82,31 +75,22 @@ fn get_interrupt(irq_type: usize, irq: usize) -> u32 {
     }
 }
 
-fn interrupt_for_node(node: &device_tree::Node) -> Option<u32> {
-    node.prop_by_name("interrupts").map(|interrupt| {
-        let (irq_type, rest) = regs_to_usize(interrupt.value, 1);
-        let (irq, _rest) = regs_to_usize(rest, 1);
-        get_interrupt(irq_type, irq)
-    })
+// Reads the type and number cells of a GIC interrupt specifier
+fn gic_interrupt(spec: &[u8]) -> u32 {
+    let (irq_type, rest) = device_tree::read_cells(spec, 1);
+    let (irq, _) = device_tree::read_cells(rest, 1);
+    get_interrupt(irq_type, irq)
 }
 
-fn interrupts_for_node(node: &device_tree::Node) -> Option<Vec<u32>> {
-    node.prop_by_name("interrupts").map(|interrupt| {
-        let mut interrupts: Vec<u32> = Vec::new();
-        let (mut irq_type, rest) = regs_to_usize(interrupt.value, 1);
-        let (mut irq, mut rest) = regs_to_usize(rest, 1);
-        interrupts.push(get_interrupt(irq_type, irq));
-        loop {
-            if rest.len() > 4 { // Stop at 0, 0, 15, 4
-                (irq_type, rest) = regs_to_usize(&rest[4..], 1);
-                (irq, rest) = regs_to_usize(rest, 1);
-                interrupts.push(get_interrupt(irq_type, irq));
-            } else {
-                break
-            }
-        }
-        interrupts
-    })
+fn interrupts_for_node<'a>(
+    dtb: &'a device_tree::DeviceTree,
+    node: &device_tree::Node<'a>,
+) -> impl Iterator<Item = u32> + 'a {
+    dtb.interrupts(node).filter(|spec| spec.len() >= 8).map(gic_interrupt)
+}
+
+fn interrupt_for_node(dtb: &device_tree::DeviceTree, node: &device_tree::Node) -> Option<u32> {
+    interrupts_for_node(dtb, node).next()
 }
 
 #[global_allocator]
@@ -137,65 +121,119 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
 
     static BLK: mutex::Mutex<Option<virtio::VirtIOBlk>> = mutex::Mutex::new(None);
     static ENTROPY: mutex::Mutex<Option<virtio::VirtIOEntropy>> = mutex::Mutex::new(None);
-    static NET: mutex::Mutex<Option<virtio::VirtIONet>> = mutex::Mutex::new(None);
+    static NET: mutex::Mutex<Option<net::Interface<virtio::VirtIONet>>> = mutex::Mutex::new(None);
+    static CONSOLE: mutex::Mutex<Option<virtio::VirtIOConsole>> = mutex::Mutex::new(None);
+    static NINEP: mutex::Mutex<Option<virtio::VirtIO9P>> = mutex::Mutex::new(None);
+    static BALLOON: mutex::Mutex<Option<virtio::VirtIOBalloon>> = mutex::Mutex::new(None);
+
+    // Whoever holds the driver unmasks it again once done
+    fn net_interrupt(irq: u32) {
+        if let Some(mut net) = NET.try_lock_or_defer(irq) {
+            net.as_mut().map(|iface| iface.device_mut().handle_interrupt());
+        }
+    }
+
+    fn balloon_interrupt(irq: u32) {
+        if let Some(mut balloon) = BALLOON.try_lock_or_defer(irq) {
+            balloon.as_mut().map(|balloon| balloon.handle_interrupt());
+        }
+    }
+
+    // Reseeds come from the rng device when it isn't busy elsewhere
+    fn read_entropy(seed: &mut [u8]) -> bool {
+        ENTROPY.try_lock().and_then(|mut e| e.as_mut().map(|e| e.read(seed))).is_some()
+    }
+
+    // Hands a virtio device to its driver, whichever transport it sits on
+    fn attach(transport: Transport<'static, ()>, irq: gic::GIC) {
+        match transport.device_id() {
+            virtio::DeviceId::Blk => {
+                BLK.lock().replace(virtio::VirtIOBlk::new(transport.cast(), irq));
+            }
+            virtio::DeviceId::Entropy => {
+                ENTROPY.lock().replace(virtio::VirtIOEntropy::new(transport.cast(), irq));
+            }
+            virtio::DeviceId::Net => {
+                exception::register_handler(irq.num(), net_interrupt);
+                // Configured from the bootargs once every device is attached
+                NET.lock().replace(net::Interface::new(
+                    virtio::VirtIONet::new(transport.cast(), irq),
+                    net::Ipv4Config::UNCONFIGURED,
+                ));
+            }
+            virtio::DeviceId::NinePTransport => {
+                NINEP.lock().replace(virtio::VirtIO9P::new(transport.cast(), irq));
+            }
+            virtio::DeviceId::MemoryBalloon => {
+                exception::register_handler(irq.num(), balloon_interrupt);
+                BALLOON.lock().replace(virtio::VirtIOBalloon::new(
+                    transport.cast(),
+                    Box::leak(Box::new([virtio::BalloonStat::empty(); virtio::BALLOON_NSTATS])),
+                    irq,
+                ));
+            }
+            virtio::DeviceId::Console => {
+                CONSOLE.lock().replace(virtio::VirtIOConsole::new(
+                    transport.cast(),
+                    Box::leak(Box::new(
+                        [virtio::ControlBuf::empty(); virtio::CONSOLE_CONTROL_NBUFS]
+                    )),
+                    irq,
+                ));
+            }
+            _ => {}
+        }
+    }
 
     let mut hstart = 0;
     let mut hsize = 0;
 
-    let mut mem_start = 0;
-    let mut mem_size = 0;
+    let mut memory = mm::memmap::MemoryMap::new();
+
+    let mut bootargs = Vec::new();
 
     if let Some(root) = dtb.root() {
-        let size_cell = root
-            .prop_by_name("#size-cells")
-            .map(|sc| {
-                let mut buf = [0; 4];
-                buf.copy_from_slice(sc.value);
-                u32::from_be_bytes(buf) as usize
-            })
-            .unwrap_or(2);
-        let address_cell = root
-            .prop_by_name("#address-cells")
-            .map(|sc| {
-                let mut buf = [0; 4];
-                buf.copy_from_slice(sc.value);
-                u32::from_be_bytes(buf) as usize
-            })
-            .unwrap_or(2);
-
-        for memory in root.children_by_prop("device_type", |prop| prop.value == b"memory\0") {
-            if let Some(reg) = memory.prop_by_name("reg") {
-                let (addr, rest) = regs_to_usize(reg.value, address_cell);
-                let (size, _) = regs_to_usize(rest, size_cell);
-                unsafe {
-                    let heap_start = &HEAP_START as *const _ as usize;
-                    if heap_start >= addr {
-
-                        hstart = heap_start;
-                        hsize = size;
-
-                        ALLOCATOR.lock().init(heap_start, size / 2);
-                        mem_start = mm::page_align_up(heap_start + size / 2);
-                        mem_size = mm::page_align_down(size / 2 / 2); // FIXME: size isn't the
-                        break;
-                    } else {
-                        panic!("{:#x} {:#x}", addr, heap_start);
-                    }
-                }
+        for node in root.children_by_prop("device_type", |prop| prop.value == b"memory\0") {
+            dtb.regs(&node).for_each(|(addr, size)| memory.add_ram(Region::new(addr, size)));
+        }
+
+        // What is already in use: whatever the loader asked to keep, the
+        // kernel image and the device tree itself
+        dtb.reservations().for_each(|(addr, size)| memory.reserve(Region::new(addr, size)));
+        if let Some(reserved) = root.child_by_name("reserved-memory") {
+            for child in reserved.children() {
+                dtb.regs(&child).for_each(|(addr, size)| memory.reserve(Region::new(addr, size)));
             }
         }
+        let heap_start = unsafe { &HEAP_START as *const _ as usize };
+        memory.reserve(Region { start: _start_addr as usize, end: heap_start });
+        memory.reserve(Region::new(dtb as *const _ as usize, dtb.total_size()));
+
+        // The early heap gets up to half the RAM it starts in
+        let ram = memory.ram_at(heap_start).expect("heap starts outside RAM");
+        let heap_end = memory
+            .available()
+            .iter()
+            .find(|r| r.start <= heap_start && heap_start < r.end)
+            .map(|r| r.end)
+            .expect("heap starts in reserved memory");
+        hstart = heap_start;
+        hsize = core::cmp::min(heap_end - heap_start, ram.size() / 2);
+        unsafe { ALLOCATOR.lock().init(hstart, hsize) };
+        memory.reserve(Region::new(hstart, hsize));
 
         if let Some(chosen) = root.child_by_name("chosen") {
+            if let Some(args) = chosen.prop_by_name("bootargs").filter(|args| !args.value.is_empty()) {
+                bootargs.extend_from_slice(null_terminated_str(args.value));
+            }
             chosen
                 .prop_by_name("stdout-path")
                 .map(|stdout_path| null_terminated_str(stdout_path.value))
                 .filter(|stdout_path| stdout_path == b"/pl011@9000000")
                 .map(|stdout_path| {
                     root.child_by_path(stdout_path).map(|stdout| {
-                        let irq = interrupt_for_node(&stdout).unwrap_or(0) as u32;
-                        if let Some(reg) = stdout.prop_by_name("reg") {
-                            let (addr, rest) = regs_to_usize(reg.value, address_cell);
-                            let (size, _) = regs_to_usize(rest, size_cell);
+                        let irq = interrupt_for_node(dtb, &stdout).unwrap_or(0);
+                        if let Some((addr, size)) = dtb.regs(&stdout).next() {
                             if size == 0x1000 {
                                 let mut uart = UART.lock();
                                 *uart =
@@ -209,60 +247,49 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
         exception::load_table();
 
         if let Some(timer) = root.child_by_name("timer") {
-            if let Some(irq) = interrupts_for_node(&timer)
-                .map(|irqs| {
-                    irqs.into_iter().find(|&irq| irq == timer::EL1_PHYSICAL_TIMER)
-                })
-                .flatten() {
+            if let Some(irq) = interrupts_for_node(dtb, &timer).find(|&irq| irq == timer::EL1_PHYSICAL_TIMER) {
                 timer::init_timer(unsafe { gic::GIC::new(irq) });
             }
         }
 
         for child in root.children_by_prop("compatible", |prop| prop.value == b"virtio,mmio\0") {
-            if let Some(reg) = child.prop_by_name("reg") {
-                let (addr, _rest) = regs_to_usize(reg.value, address_cell);
-                let irq =
-                    unsafe { crate::gic::GIC::new(interrupt_for_node(&child).unwrap_or(0) as u32) };
+            if let Some((addr, _size)) = dtb.regs(&child).next() {
+                let irq = unsafe { crate::gic::GIC::new(interrupt_for_node(dtb, &child).unwrap_or(0)) };
                 if let Some(virtio) = unsafe { VirtIORegs::new(addr as *mut VirtIORegs<()>) } {
-                    match virtio.device_id() {
-                        virtio::DeviceId::Blk => {
-                            let mut virtio_blk = BLK.lock();
-                            *virtio_blk = unsafe {
-                                Some(virtio::VirtIOBlk::new(
-                                    &mut *(virtio as *mut _ as *mut _),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    irq,
-                                ))
-                            };
-                        }
-                        virtio::DeviceId::Entropy => {
-                            let mut virtio_entropy = ENTROPY.lock();
-                            *virtio_entropy = unsafe {
-                                Some(virtio::VirtIOEntropy::new(
-                                    &mut *(virtio as *mut _ as *mut _),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    irq,
-                                ))
-                            };
-                        }
-                        virtio::DeviceId::Net => {
-                            let mut virtio_net = NET.lock();
-                            *virtio_net = unsafe {
-                                Some(virtio::VirtIONet::new(
-                                    &mut *(virtio as *mut _ as *mut _),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    Box::leak(Box::new(virtio::Queue::new())),
-                                    irq,
-                                ))
-                            };
-                        }
-                        _ => {}
+                    attach(Transport::Mmio(virtio), irq);
+                }
+            }
+        }
+
+        for child in root.children_by_prop("compatible", |prop| prop.value == b"pci-host-ecam-generic\0") {
+            if let Some(mut host) = pci::PciHost::from_node(&child, dtb) {
+                let functions: Vec<pci::PciFunction> = host.functions().collect();
+                for func in functions {
+                    if virtio::pci_device_id(&func).is_none() {
+                        continue;
+                    }
+                    let bars = host.assign_bars(&func);
+                    let irq = host
+                        .irq_for(&func)
+                        .map(|(irq_type, irq)| get_interrupt(irq_type, irq))
+                        .unwrap_or(0);
+                    if let Some(transport) = unsafe { virtio::PciTransport::new(&func, &bars) } {
+                        attach(Transport::Pci(transport), unsafe { gic::GIC::new(irq) });
                     }
                 }
             }
         }
     }
 
+    random::set_source(read_entropy);
+
+    let boot = net::dhcp::BootConfig::parse(&bootargs);
+    NET.map(|iface| match (boot.dhcp, boot.fallback) {
+        (true, fallback) => iface.start_dhcp(fallback),
+        (false, Some(config)) => iface.set_config(config),
+        (false, None) => {}
+    });
+
     READY_LIST.lock().replace(VecDeque::new());
     RESBLOCKS.lock().replace((Vec::new(), 0));
     TS.lock().replace(Vec::new());
@@ -277,9 +304,13 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
     use kobject::{KObjectRef, Container, Thread, Label, THREAD_NPAGES, KOBJ_NPAGES};
     use mm::page_tree::PageTree;
 
-    debug!("heap_start: {:#x}, heap_size: {:#x}, mem_start: {:#x}, mem_size: {:#x}", hstart, hsize, mem_start, mem_size);
-
-    let mut page_tree = unsafe { PageTree::new(mem_start, PAGE_SIZE * 512) };
+    // Whatever RAM is left belongs to the root container
+    let mut page_tree = PageTree::empty();
+    for region in memory.available().iter() {
+        unsafe { page_tree.init(region.start, region.size()) };
+    }
+    memory.ram().for_each(|r| debug!("ram: {:#x}-{:#x}", r.start, r.end));
+    debug!("heap_start: {:#x}, heap_size: {:#x}, free pages: {}", hstart, hsize, page_tree.len());
 
     // create the root container
     debug!("Initializing threads...");
@@ -290,6 +321,7 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
     let ct_page = page_tree.get_multiple(KOBJ_NPAGES).unwrap();
     let root_ct_ref = unsafe {
         let ct_ref = Container::create(ct_page);
+        ct_ref.as_mut().pages.subtree = page_tree.len();
         ct_ref.meta_mut().free_pages = page_tree;
         ct_ref.meta_mut().label = Some(lb_ref);
         ct_ref
@@ -299,14 +331,14 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
 
     // init the main thread
     let lb_slot = root_ct_ref.as_mut().get_slot().unwrap();
-    let lb_page_id = root_ct_ref.meta_mut().free_pages.get_multiple(KOBJ_NPAGES).unwrap();
+    let lb_page_id = root_ct_ref.get_pages(KOBJ_NPAGES).unwrap();
     let lb_ref = unsafe {
         Label::create(lb_page_id, "T,F")
     };
     root_ct_ref.as_mut().set_slot(lb_slot, lb_ref);
 
     let th_slot = root_ct_ref.as_mut().get_slot().unwrap();
-    let th_page_id = root_ct_ref.meta_mut().free_pages.get_multiple(THREAD_NPAGES).unwrap();
+    let th_page_id = root_ct_ref.get_pages(THREAD_NPAGES).unwrap();
 
     let main_th_ref = unsafe {
         let th_ref = Thread::create(th_page_id, || {});
@@ -320,26 +352,33 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
 
     debug!("Main thread initialized");
 
+    // Rebuild the pools from the last snapshot if the disk has one. Boot
+    // carries on with whatever it restored in place of a fresh pool.
+    let mut restored = None;
+    exception::with_intr_disabled(|| {
+        restored = BLK.map(|blk| snapshot::restore(root_ct_ref, blk));
+    });
+    match restored {
+        Some(Ok(n)) => debug!("Restored {} containers from snapshot", n),
+        Some(Err(e)) => debug!("No snapshot restored: {:?}", e),
+        None => {}
+    }
+
     //
     //
     //
 
     // Create a pool container
-    let ct_ref = container::create(root_ct_ref, "gongqi,gongqi");
-    if let Some(cts) = root_ct_ref.as_mut().known_containers.as_mut() {
-        cts.push(ct_ref)
-    } else {
-        let alloc = root_ct_ref.meta().alloc.clone();
-        root_ct_ref.as_mut().known_containers = Some({
-            let mut list = collections::list::List::new_in(alloc);
-            list.push(ct_ref);
-            list
-        });
-    }
-    container::move_npages(root_ct_ref, ct_ref, 100);
+    let ct_ref = container::child_by_descr(root_ct_ref, "gongqi").unwrap_or_else(|| {
+        let ct_ref = container::create(root_ct_ref, "gongqi,gongqi");
+        ct_ref.meta_mut().set_descr("gongqi");
+        container::add_known(root_ct_ref, ct_ref);
+        container::move_npages(root_ct_ref, ct_ref, 100).expect("no pages for the pool");
+        ct_ref
+    });
 
     // create a lf channel
-    let (tx, rx) = lfchannel::channel::<()>();
+    let (tx, rx) = lfchannel::channel_in::<(), _>(ct_ref.meta().alloc.clone());
 
     // create a scheduling thread for the pool
     let scheduler = thread::spawn_raw(
@@ -396,21 +435,16 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
                                                    // create
 
     // Create another pool container
-    let ct_ref2 = container::create(root_ct_ref, "gongqi&laptop,gongqi");
-    if let Some(cts) = root_ct_ref.as_mut().known_containers.as_mut() {
-        cts.push(ct_ref2)
-    } else {
-        let alloc = root_ct_ref.meta().alloc.clone();
-        root_ct_ref.as_mut().known_containers = Some({
-            let mut list = collections::list::List::new_in(alloc);
-            list.push(ct_ref2);
-            list
-        });
-    }
-    container::move_npages(root_ct_ref, ct_ref2, 100);
+    let ct_ref2 = container::child_by_descr(root_ct_ref, "gongqi-laptop").unwrap_or_else(|| {
+        let ct_ref2 = container::create(root_ct_ref, "gongqi&laptop,gongqi");
+        ct_ref2.meta_mut().set_descr("gongqi-laptop");
+        container::add_known(root_ct_ref, ct_ref2);
+        container::move_npages(root_ct_ref, ct_ref2, 100).expect("no pages for the pool");
+        ct_ref2
+    });
 
     // create a lf channel
-    let (tx2, rx2) = lfchannel::channel::<()>();
+    let (tx2, rx2) = lfchannel::channel_in::<(), _>(ct_ref2.meta().alloc.clone());
 
     // create a scheduling thread for the pool
     let scheduler2 = thread::spawn_raw(
@@ -459,6 +493,43 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
     ct_ref2.as_mut().scheduler = Some(scheduler2.0);
 
 
+    // Give each pool its own virtio console port
+    exception::with_intr_disabled(|| {
+        CONSOLE.map(|console| {
+            for ct in [ct_ref, ct_ref2] {
+                if let Some(port) = console.open_port(ct) {
+                    console.write(port, b"console port ready\n");
+                }
+            }
+        });
+    });
+
+    // Give each pool its own address on the NIC. Only what may flow to the
+    // network label gets out.
+    let net_lb_slot = root_ct_ref.as_mut().get_slot().unwrap();
+    let net_lb_page = root_ct_ref.get_pages(KOBJ_NPAGES).unwrap();
+    let net_lb_ref = unsafe { Label::create(net_lb_page, "T,T") };
+    net_lb_ref.meta_mut().parent = Some(root_ct_ref);
+    root_ct_ref.as_mut().set_slot(net_lb_slot, net_lb_ref);
+    exception::with_intr_disabled(|| {
+        NET.map(|iface| {
+            iface.set_network_label(net_lb_ref);
+            // Whether it's static or still to come from DHCP, the pools live
+            // on the interface's subnet
+            for (&ct, &addr) in [ct_ref, ct_ref2].iter().zip(boot.namespaces.iter()) {
+                iface.add_namespace_on_subnet(ct, addr);
+            }
+        });
+    });
+
+    // Let the host reclaim what it asks for out of the root's free pages
+    exception::with_intr_disabled(|| {
+        BALLOON.map(|balloon| {
+            balloon.draw_from(root_ct_ref);
+            debug!("balloon holds {} of {} requested pages", balloon.actual(), balloon.target());
+        });
+    });
+
     // Send "tasks"
     (0..0).for_each(|_| tx.send(()));
 
@@ -475,7 +546,7 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
     //      so that it can manage this time slice (can manage)
 
 
-    let (slice_tx, slice_rx) = lfchannel::channel::<()>();
+    let (slice_tx, slice_rx) = lfchannel::channel_in::<(), _>(root_ct_ref.meta().alloc.clone());
 
     // let some_scheduler = thread::spawn_raw(ct_ref, "gongqi,gongqi", move || {
         // for _ in 0..2 {
@@ -501,69 +572,71 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree, _start_addr: u64, _
 
         let default_time_quota = 2;
 
-        // create the first resource block
-        let rb = ResourceBlock {
-            holder: ct_ref,
-            time_quota: default_time_quota,
-        };
-
-        // init time slices if none
-        if ct_ref.as_mut().time_slices.is_none() {
-            let alloc = ct_ref.meta().alloc.clone();
-            ct_ref.as_mut().time_slices = Some(Vec::new_in(alloc))
-        }
-
-        if let Some(slices) = ct_ref.as_mut().time_slices.as_mut() {
-            (0..rb.time_quota).for_each(|_| {
-                slices.push(TimeSlice::Routine) // this modifies ct_ref, must be an async operation
-                                             // otherwise leaks (?)
-                                             // To fix it, how to bootstrap this? 2 ways
-                                             // 1. every rb belongs the root ct, the root ct
-                                             //     add slices to itself, redirect some to target
-                                             //     ct, the target ct's scheduler runs and reads
-                                             //     updates from the lf channel
-                                             // 2. ???
-            });
-        } else {
-            panic!("WTF");
-        }
+        for ct in [ct_ref, ct_ref2] {
+            // Pools restored from a snapshot come back with their time
+            if RESBLOCKS.lock().as_ref().map_or(false, |(rbs, _)| rbs.iter().any(|rb| rb.holder == ct)) {
+                continue
+            }
 
-        TS.map(|ts| ts.push((ct_ref, 0)));
-        // create the first resource block end
+            // create a resource block
+            let rb = ResourceBlock {
+                holder: ct,
+                time_quota: default_time_quota,
+            };
 
+            // init time slices if none
+            if ct.as_mut().time_slices.is_none() {
+                let alloc = ct.meta().alloc.clone();
+                ct.as_mut().time_slices = Some(Vec::new_in(alloc))
+            }
 
-        // create another resource block
-        let rb2 = ResourceBlock {
-            holder: ct_ref2,
-            time_quota: default_time_quota,
-        };
+            if let Some(slices) = ct.as_mut().time_slices.as_mut() {
+                (0..rb.time_quota).for_each(|_| {
+                    slices.push(TimeSlice::Routine) // this modifies ct_ref, must be an async operation
+                                                 // otherwise leaks (?)
+                                                 // To fix it, how to bootstrap this? 2 ways
+                                                 // 1. every rb belongs the root ct, the root ct
+                                                 //     add slices to itself, redirect some to target
+                                                 //     ct, the target ct's scheduler runs and reads
+                                                 //     updates from the lf channel
+                                                 // 2. ???
+                });
+            } else {
+                panic!("WTF");
+            }
 
-        // init time slices if none
-        if ct_ref2.as_mut().time_slices.is_none() {
-            let alloc = ct_ref2.meta().alloc.clone();
-            ct_ref2.as_mut().time_slices = Some(Vec::new_in(alloc))
+            TS.map(|ts| ts.push((ct, 0)));
+            RESBLOCKS.map(|(rbs, _)| rbs.push(rb));
+            // create a resource block end
         }
 
-        if let Some(slices) = ct_ref2.as_mut().time_slices.as_mut() {
-            (0..rb2.time_quota).for_each(|_| {
-                slices.push(TimeSlice::Routine)
-            });
-        } else {
-            panic!("WTF");
-        }
+        // READY_LIST.map(|l| { (0..2).for_each(|i| l.push_back(rb.time_slices[i].as_ref().unwrap().clone())) });
+    });
 
-        TS.map(|ts| ts.push((ct_ref2, 0)));
-        // create the first resource block end
+    // Serve the shell over TCP from the root's own time, in the kernel's
+    // namespace
+    let remote_shell = thread::spawn_raw(root_ct_ref, "T,F", move || {
+        let mut shell = apps::shell::Shell {
+            blk: &BLK,
+            root: root_ct_ref,
+            host: &NINEP,
+            balloon: &BALLOON,
+            net: &NET,
+        };
+        apps::net::Net { iface: &NET, ns: net::NamespaceId::DEFAULT }.run_tcp(&mut shell);
+    });
+    exception::with_intr_disabled(move || {
+        use kobject::TimeSlice;
 
-        RESBLOCKS.map(|(rbs, _)| {
-            rbs.push(rb);
-            rbs.push(rb2);
-        });
+        let alloc = root_ct_ref.meta().alloc.clone();
+        let mut slices = Vec::new_in(alloc);
+        slices.push(TimeSlice::Execute(remote_shell));
+        root_ct_ref.as_mut().time_slices = Some(slices);
 
-        // READY_LIST.map(|l| { (0..2).for_each(|i| l.push_back(rb.time_slices[i].as_ref().unwrap().clone())) });
+        TS.map(|ts| ts.push((root_ct_ref, 0)));
+        RESBLOCKS.map(|(rbs, _)| rbs.push(ResourceBlock { holder: root_ct_ref, time_quota: 1 }));
     });
 
-
     cpu_idle!("idling in main");
 
 }
@@ -73,20 +73,41 @@ impl ChunkList {
         }
     }
 
+    /// Frees `start..start+size`, merging it with the chunks on either side.
+    /// The list stays in address order.
     pub unsafe fn push(&mut self, start: usize, size: usize) {
-        let next = Self::init_chunk(start, size, self.head.next);
-        self.head.next = Some(next);
-    }
+        let end = align_down(start + size, mem::align_of::<Chunk>());
+        let start = align_up(start, mem::align_of::<Chunk>());
+        if end <= start || end - start < mem::size_of::<Chunk>() {
+            return
+        }
 
-    pub unsafe fn append(&mut self, start: usize, size: usize) {
-        let next = Self::init_chunk(start, size, None);
-        if let Some(last) = self.last() {
-            last.next = Some(next);
+        let head = &mut self.head as *mut Chunk;
+        let mut prev = head;
+        while let Some(next_ptr) = (*prev).next.filter(|n| (n.as_ptr() as usize) < start) {
+            prev = next_ptr.as_ptr();
+        }
+        let next = (*prev).next;
+
+        let chunk = if prev != head && prev as usize + (*prev).size == start {
+            (*prev).size += end - start;
+            prev
         } else {
-            self.head.next = Some(next);
+            let chunk = Self::init_chunk(start, end - start, next);
+            (*prev).next = Some(chunk);
+            chunk.as_ptr()
+        };
+        if let Some(next_ptr) = next.filter(|n| n.as_ptr() as usize == end) {
+            (*chunk).size += next_ptr.as_ref().size;
+            (*chunk).next = next_ptr.as_ref().next;
         }
     }
 
+    /// Adds more memory to allocate from
+    pub unsafe fn append(&mut self, start: usize, size: usize) {
+        self.push(start, size)
+    }
+
     pub fn pop_first_fit(&mut self, layout: Layout) -> Option<NonNull<u8>> {
         self.head.next.and_then(|_| {
             let mut cur = (&mut self.head, None); // current, previous
@@ -147,20 +168,26 @@ impl ChunkList {
         })
     }
 
+    /// Drops every free chunk starting in `start..end`
+    pub fn remove_range(&mut self, start: usize, end: usize) {
+        let mut cur = &mut self.head;
+        while let Some(mut next_ptr) = cur.next {
+            let addr = next_ptr.as_ptr() as usize;
+            if addr >= start && addr < end {
+                cur.next = unsafe { next_ptr.as_ref() }.next;
+            } else {
+                cur = unsafe { next_ptr.as_mut() };
+            }
+        }
+    }
+
     fn _first(&self) -> Option<&mut Chunk> {
         self.head.next.map(|mut c| unsafe { c.as_mut() })
     }
 
-    fn last(&mut self) -> Option<&mut Chunk> {
-        if self.head.next.is_none() {
-            None
-        } else {
-            let mut cur = &mut self.head;
-            while let Some(mut next_ptr) = cur.next {
-                cur = unsafe { next_ptr.as_mut() };
-            }
-            Some(cur)
-        }
+    // The smallest chunk `layout` is sure to fit in, whatever its alignment
+    pub fn fit_size(layout: Layout) -> usize {
+        Self::align_layout(layout).size() + layout.align() + mem::size_of::<Chunk>()
     }
 
     pub fn align_layout(layout: Layout) -> Layout {
@@ -1,55 +1,153 @@
 use core::ptr::NonNull;
+use core::mem::{align_of, size_of};
 use core::alloc::{Layout, Allocator, AllocError};
 
 use super::yaarena::Arena;
+use super::chunk_list::ChunkList;
+use super::{align_up, page_align_up, PAGE_SIZE};
+use crate::kobject::{Container, KObjectKind, KObjectRef};
 use crate::mutex::Mutex;
 
+// Pages an arena took from its container, headed by this
+struct Extent {
+    page: usize,
+    npages: usize,
+    // Bytes allocated from it and not yet given back
+    live: usize,
+    next: Option<NonNull<Extent>>,
+}
+
 #[derive(Debug)]
+struct Inner {
+    arena: Arena,
+    // The kobject whose meta data holds the arena, if it may grow
+    owner: Option<usize>,
+    // Bytes allocated and not yet given back
+    bytes: usize,
+    extents: Option<NonNull<Extent>>,
+}
+
+unsafe impl Send for Inner {}
+
+// Where a kobject's arena gets more pages: its parent, or the root
+// container itself
+unsafe fn page_source(owner: usize) -> Option<KObjectRef<Container>> {
+    let ko_ref = KObjectRef::<Container>::new(owner);
+    match (ko_ref.meta().parent, ko_ref.meta().kind) {
+        (Some(parent), _) => Some(parent),
+        (None, KObjectKind::Container) => Some(ko_ref),
+        _ => None,
+    }
+}
+
+impl Inner {
+    // Takes enough pages for `layout` from the owner's container
+    unsafe fn grow(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let ct_ref = self.owner.and_then(|owner| page_source(owner))?;
+        let needed = ChunkList::fit_size(Arena::backing_layout(layout));
+        let npages = page_align_up(size_of::<Extent>() + needed) / PAGE_SIZE;
+        let page = ct_ref.get_pages(npages)?;
+
+        let ptr = (page * PAGE_SIZE) as *mut Extent;
+        ptr.write(Extent { page, npages, live: 0, next: self.extents });
+        self.extents = Some(NonNull::new_unchecked(ptr));
+        // The header keeps this free space from merging with a neighbour's
+        self.arena.append(ptr as usize + size_of::<Extent>(), npages * PAGE_SIZE - size_of::<Extent>());
+        self.arena.allocate(layout)
+    }
+
+    // Counts `size` bytes at `addr` in or out of the extent holding them,
+    // handing the extent back once nothing in it is allocated
+    unsafe fn account(&mut self, addr: usize, size: usize, allocated: bool) {
+        if allocated {
+            self.bytes += size;
+        } else {
+            self.bytes -= size;
+        }
+
+        let mut link = &mut self.extents;
+        while let Some(mut ptr) = *link {
+            let extent = ptr.as_mut();
+            let (start, end) = (extent.page * PAGE_SIZE, (extent.page + extent.npages) * PAGE_SIZE);
+            if addr < start || addr >= end {
+                link = &mut extent.next;
+                continue;
+            }
+
+            if allocated {
+                extent.live += size;
+            } else {
+                extent.live -= size;
+            }
+            if extent.live == 0 {
+                *link = extent.next;
+                self.arena.remove(start, end);
+                if let Some(ct_ref) = self.owner.and_then(|owner| page_source(owner)) {
+                    ct_ref.put_pages(extent.page, extent.npages);
+                }
+            }
+            return;
+        }
+    }
+}
+
+/// A kobject's allocator. It starts out with the space left in the
+/// kobject's own pages and grows a page or more at a time from its
+/// container, giving pages back once they are empty. Its state sits at the
+/// front of that space, so nothing of it is on the global heap.
+#[derive(Debug, Clone)]
 pub struct KObjectArena {
-    arena: alloc::sync::Arc<Mutex<Arena>>, // TODO arc uses global heap
+    inner: Option<NonNull<Mutex<Inner>>>,
 }
 
+unsafe impl Send for KObjectArena {}
+unsafe impl Sync for KObjectArena {}
+
 impl KObjectArena {
     pub fn empty() -> Self {
-        KObjectArena {
-            arena: alloc::sync::Arc::new(Mutex::new(Arena::empty()))
-        }
+        KObjectArena { inner: None }
     }
 
-    pub unsafe fn new(start: usize, size: usize) -> Self {
-        KObjectArena {
-            arena: alloc::sync::Arc::new(Mutex::new(Arena::new(start, size)))
-        }
+    /// An arena over `start..start+size` for the kobject at page `owner`
+    pub unsafe fn new(owner: usize, start: usize, size: usize) -> Self {
+        let ptr = align_up(start, align_of::<Mutex<Inner>>()) as *mut Mutex<Inner>;
+        let used = ptr as usize + size_of::<Mutex<Inner>>() - start;
+        ptr.write(Mutex::new(Inner {
+            arena: Arena::new(start + used, size - used),
+            owner: Some(owner),
+            bytes: 0,
+            extents: None,
+        }));
+        KObjectArena { inner: Some(NonNull::new_unchecked(ptr)) }
     }
 
-    pub fn as_ref(&self) -> &alloc::sync::Arc<Mutex<Arena>> {
-        &self.arena
+    /// Bytes allocated from the arena and not yet given back
+    pub fn bytes(&self) -> usize {
+        self.inner().map_or(0, |inner| inner.lock().bytes)
     }
 
-    pub fn as_mut(&mut self) -> &mut alloc::sync::Arc<Mutex<Arena>> {
-        &mut self.arena
+    fn inner(&self) -> Option<&Mutex<Inner>> {
+        self.inner.map(|ptr| unsafe { &*ptr.as_ptr() })
     }
 }
 
 unsafe impl<'a> Allocator for KObjectArena {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        self.arena
-            .lock()
-            .allocate(layout)
-            .map(|p| NonNull::slice_from_raw_parts(p, layout.size()))
-            .ok_or_else(|| AllocError)
+        let mut inner = self.inner().ok_or(AllocError)?.lock();
+        let ptr = match inner.arena.allocate(layout) {
+            Some(ptr) => ptr,
+            None => unsafe { inner.grow(layout) }.ok_or(AllocError)?,
+        };
+        unsafe { inner.account(ptr.as_ptr() as usize, ChunkList::align_layout(layout).size(), true) };
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        self.arena
-            .lock()
-            .deallocate(ptr, layout)
-    }
-}
-
-impl Clone for KObjectArena {
-    fn clone(&self) -> Self {
-        KObjectArena { arena: alloc::sync::Arc::clone(&self.arena) }
+        if let Some(inner) = self.inner() {
+            let mut inner = inner.lock();
+            inner.arena.deallocate(ptr, layout);
+            inner.account(ptr.as_ptr() as usize, ChunkList::align_layout(layout).size(), false);
+        }
     }
 }
 
@@ -97,5 +195,21 @@ mod test {
             let _ = Box::leak(Box::new_in([0u8; 4096], &arena));
         }
     }
-}
 
+    #[test_case]
+    fn test_arena_grows_from_its_container() {
+        use alloc::vec::Vec;
+        use crate::kobject::fixtures::container;
+
+        let ct_ref = container(None, "T,T", 8);
+
+        // More slots than fit in the container's own page
+        for _ in 0..1024 {
+            ct_ref.as_mut().get_slot().unwrap();
+        }
+        assert!(ct_ref.meta().free_pages.len() < 8);
+
+        ct_ref.as_mut().slots = Vec::new_in(ct_ref.meta().alloc.clone());
+        assert_eq!(ct_ref.meta().free_pages.len(), 8);
+    }
+}
@@ -0,0 +1,102 @@
+//! The physical memory map, gathered before there is a heap to keep it in.
+
+use heapless::Vec;
+
+const MAX_RAM: usize = 16;
+const MAX_RESERVED: usize = 64;
+const MAX_AVAILABLE: usize = MAX_RAM + MAX_RESERVED;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Region {
+    pub fn new(start: usize, size: usize) -> Region {
+        Region { start, end: start.saturating_add(size) }
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// RAM, and the parts of it that are already spoken for
+pub struct MemoryMap {
+    ram: Vec<Region, MAX_RAM>,
+    reserved: Vec<Region, MAX_RESERVED>,
+}
+
+impl MemoryMap {
+    pub const fn new() -> MemoryMap {
+        MemoryMap { ram: Vec::new(), reserved: Vec::new() }
+    }
+
+    pub fn add_ram(&mut self, region: Region) {
+        if region.size() > 0 {
+            self.ram.push(region).expect("too many memory regions");
+        }
+    }
+
+    pub fn reserve(&mut self, region: Region) {
+        if region.size() > 0 {
+            self.reserved.push(region).expect("too many reserved regions");
+        }
+    }
+
+    pub fn ram(&self) -> impl Iterator<Item = &Region> {
+        self.ram.iter()
+    }
+
+    /// The RAM containing `addr`
+    pub fn ram_at(&self, addr: usize) -> Option<Region> {
+        self.ram.iter().find(|r| r.start <= addr && addr < r.end).copied()
+    }
+
+    /// RAM nothing has reserved, in address order
+    pub fn available(&mut self) -> Vec<Region, MAX_AVAILABLE> {
+        self.ram.sort_unstable_by_key(|r| r.start);
+        self.reserved.sort_unstable_by_key(|r| r.start);
+
+        let mut available = Vec::new();
+        for ram in self.ram.iter() {
+            let mut cursor = ram.start;
+            for reserved in self.reserved.iter().filter(|r| r.end > ram.start && r.start < ram.end) {
+                if reserved.start > cursor {
+                    available.push(Region { start: cursor, end: reserved.start }).unwrap();
+                }
+                cursor = cursor.max(reserved.end);
+            }
+            if cursor < ram.end {
+                available.push(Region { start: cursor, end: ram.end }).unwrap();
+            }
+        }
+        available
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_available_skips_reserved() {
+        let mut map = MemoryMap::new();
+        map.add_ram(Region::new(0x8000_0000, 0x1000_0000));
+        map.add_ram(Region::new(0x4000_0000, 0x1000_0000));
+        // Overlapping, touching both ends, and outside RAM altogether
+        map.reserve(Region::new(0x4100_0000, 0x10_0000));
+        map.reserve(Region::new(0x4000_0000, 0x1000));
+        map.reserve(Region::new(0x4108_0000, 0x10_0000));
+        map.reserve(Region::new(0x8fff_f000, 0x1000));
+        map.reserve(Region::new(0x9000_0000, 0x1000));
+
+        assert_eq!(&*map.available(), [
+            Region { start: 0x4000_1000, end: 0x4100_0000 },
+            Region { start: 0x4118_0000, end: 0x5000_0000 },
+            Region { start: 0x8000_0000, end: 0x8fff_f000 },
+        ]);
+        assert_eq!(map.ram_at(0x4800_0000), Some(Region::new(0x4000_0000, 0x1000_0000)));
+    }
+}
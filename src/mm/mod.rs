@@ -2,6 +2,7 @@
 pub mod page;
 pub mod page_tree;
 pub mod koarena;
+pub mod memmap;
 mod yaarena;
 mod chunk_list;
 
@@ -1,3 +1,4 @@
+use core::marker::PhantomData;
 use core::ptr::NonNull;
 
 use super::{page_align_up, page_align_down, PAGE_SIZE};
@@ -10,8 +11,12 @@ enum Color {
 
 type PageLink = Option<NonNull<PageNode>>;
 
+// A run of free pages, kept in its own first page
 struct PageNode {
     n: usize,
+    len: usize,
+    // The longest run in this subtree
+    max: usize,
     parent: PageLink,
     left: PageLink,
     right: PageLink,
@@ -24,6 +29,8 @@ trait IsPageLink {
     unsafe fn parent(&self) -> Option<NonNull<PageNode>>;
     unsafe fn left(&self) -> Option<NonNull<PageNode>>;
     unsafe fn right(&self) -> Option<NonNull<PageNode>>;
+    unsafe fn color(&self) -> Color;
+    unsafe fn longest(&self) -> usize;
 }
 
 impl IsPageLink for PageLink {
@@ -46,17 +53,29 @@ impl IsPageLink for PageLink {
     unsafe fn right(&self) -> Option<NonNull<PageNode>> {
         self.node().and_then(|n| n.right)
     }
+
+    // Leaves are black
+    unsafe fn color(&self) -> Color {
+        self.node().map_or(Color::Black, |n| n.color)
+    }
+
+    unsafe fn longest(&self) -> usize {
+        self.node().map_or(0, |n| n.max)
+    }
 }
 
+/// Free pages as a red-black tree of runs, ordered by address. Each node
+/// also knows the longest run below it, so a fit is found in O(log n).
 pub struct PageTree {
     root: PageLink,
+    npages: usize,
 }
 
 unsafe impl Send for PageTree {}
 
 impl PageTree {
     pub fn empty() -> PageTree {
-        PageTree { root: None }
+        PageTree { root: None, npages: 0 }
     }
 
     pub unsafe fn new(start: usize, size: usize) -> PageTree {
@@ -67,14 +86,68 @@ impl PageTree {
 
     pub unsafe fn init(&mut self, start: usize, size: usize) {
         let base = page_align_up(start) / PAGE_SIZE;
-        let npages = page_align_down(size) / PAGE_SIZE;
+        let end = page_align_down(start + size) / PAGE_SIZE;
 
-        (base..base+npages)
-            .for_each(|n| self.insert(n))
+        self.put_multiple(base, end.saturating_sub(base))
     }
 
     // Safety: depends on the given page number
     pub unsafe fn insert(&mut self, n: usize) {
+        self.put_multiple(n, 1)
+    }
+
+    /// Frees `count` pages from page `start`, merging them into the runs on
+    /// either side
+    // Safety: the pages must be unused and not already in a tree
+    pub unsafe fn put_multiple(&mut self, start: usize, count: usize) {
+        if count == 0 {
+            return
+        }
+        self.npages += count;
+
+        let (prev, next) = self.neighbors(start);
+        let joins_prev = prev.node().map_or(false, |p| p.n + p.len == start);
+        let joins_next = next.node().map_or(false, |n| n.n == start + count);
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                let next_len = next.node().unwrap().len;
+                self.delete(next);
+                self.grow(prev, count + next_len);
+            }
+            (true, false) => self.grow(prev, count),
+            (false, true) => {
+                let next_len = next.node().unwrap().len;
+                self.delete(next);
+                self.link(start, count + next_len);
+            }
+            (false, false) => self.link(start, count),
+        }
+    }
+
+    // The runs just before and just after page `n`
+    unsafe fn neighbors(&self, n: usize) -> (PageLink, PageLink) {
+        let (mut prev, mut next) = (None, None);
+        let mut cur = self.root;
+        while let Some(node) = cur.node() {
+            if node.n < n {
+                prev = cur;
+                cur = node.right;
+            } else {
+                next = cur;
+                cur = node.left;
+            }
+        }
+        (prev, next)
+    }
+
+    unsafe fn grow(&mut self, mut link: PageLink, count: usize) {
+        if let Some(n) = link.node_mut() {
+            n.len += count;
+        }
+        Self::update_upward(link);
+    }
+
+    unsafe fn link(&mut self, n: usize, len: usize) {
         let mut cur = (self.root, None); // (curr, prev)
         while let Some(current) = cur.0.map(|n| n.as_ref()) {
             cur.1 = cur.0;
@@ -88,7 +161,7 @@ impl PageTree {
         let ptr = unsafe {
             let ptr = (PAGE_SIZE * n) as *mut PageNode;
             ptr.write(PageNode {
-                n, parent, left: None, right: None, color: Color::Red,
+                n, len, max: len, parent, left: None, right: None, color: Color::Red,
             });
             Some(NonNull::new_unchecked(ptr))
         };
@@ -101,6 +174,7 @@ impl PageTree {
         } else {
             self.root = ptr;
         }
+        Self::update_upward(parent);
         unsafe { self.insert_fixup(ptr) };
     }
 
@@ -169,42 +243,75 @@ impl PageTree {
         self.get_multiple(1)
     }
 
+    /// Takes `npages` contiguous pages from the lowest run long enough
     pub fn get_multiple(&mut self, npages: usize) -> Option<usize> {
-        let found = unsafe {
-            let mut cur = Self::min_link(self.root);
-            while cur.is_some() && !Self::is_n_consecutive_link(cur, npages) {
-                cur = Self::next_link(cur);
+        unsafe {
+            let found = self.first_fit(npages)?;
+            let (n, len) = (found.as_ref().n, found.as_ref().len);
+            self.delete(Some(found));
+            if len > npages {
+                self.link(n + npages, len - npages);
             }
-            cur.node().map(|n| n.n)
-        };
+            self.npages -= npages;
+            Some(n)
+        }
+    }
 
-        if let Some(start) = found {
-            (start..(start+npages))
-                .for_each(|n| unsafe { self.delete(n) })
+    unsafe fn first_fit(&self, npages: usize) -> PageLink {
+        if npages == 0 || self.root.longest() < npages {
+            return None
+        }
+        let mut cur = self.root;
+        loop {
+            let node = cur.node().unwrap();
+            if node.left.longest() >= npages {
+                cur = node.left;
+            } else if node.len >= npages {
+                return cur
+            } else {
+                cur = node.right;
+            }
         }
+    }
 
-        found
+    pub fn len(&self) -> usize {
+        self.npages
     }
 
-    // Safety: depends on the given page number
-    unsafe fn delete(&mut self, n: usize) {
-        let clink = NonNull::new((PAGE_SIZE * n) as *mut PageNode);
+    /// The free runs in address order, as `(first page, number of pages)`
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            next: unsafe { Self::min_link(self.root) },
+            _tree: PhantomData,
+        }
+    }
+
+    /// Every free page number, in order
+    pub fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.iter().flat_map(|(n, len)| n..n+len)
+    }
+
+    // Safety: `clink` must be in this tree
+    unsafe fn delete(&mut self, clink: PageLink) {
         let mut blink = clink;
-        let mut alink: PageLink;
-        let mut bcolor = blink.node().map(|b| b.color).unwrap_or(Color::Red);
+        let mut bcolor = blink.color();
+        let alink: PageLink;
+        // Where `alink` ends up hanging, which it can't say itself when it is a leaf
+        let aparent: PageLink;
         if clink.left().is_none() {
             alink = clink.right();
+            aparent = clink.parent();
             self.transplant(clink, clink.right());
         } else if clink.right().is_none() {
             alink = clink.left();
+            aparent = clink.parent();
             self.transplant(clink, clink.left());
         } else {
             blink = Self::min_link(clink.right());
-            if let Some(c) = blink.node().map(|n| n.color) {
-                bcolor = c;
-            }
+            bcolor = blink.color();
             alink = blink.right();
             if blink != clink.right() {
+                aparent = blink.parent();
                 self.transplant(blink, blink.right());
                 if let Some(n) = blink.node_mut() {
                     n.right = clink.right();
@@ -213,47 +320,45 @@ impl PageTree {
                     n.parent = blink;
                 }
             } else {
-                if let Some(n) = alink.node_mut() {
-                    n.parent = blink;
-                }
+                aparent = blink;
             }
             self.transplant(clink, blink);
             if let Some(n) = blink.node_mut() {
                 n.left = clink.left();
-                n.color = clink.node().unwrap().color;
+                n.color = clink.color();
             }
             if let Some(n) = blink.left().node_mut() {
                 n.parent = blink;
             }
         }
+        Self::update_upward(aparent);
         if bcolor == Color::Black {
-            unsafe { self.delete_fixup(alink) }
+            unsafe { self.delete_fixup(alink, aparent) }
         }
     }
 
-    unsafe fn delete_fixup(&mut self, mut alink: Option<NonNull<PageNode>>) {
-        while alink != self.root && alink.node().map(|n| n.color) == Some(Color::Black) {
-            if alink == alink.parent().left() {
-                let mut blink = alink.parent().right();
-                if let Some(Color::Red) = blink.node().map(|n| n.color) {
+    unsafe fn delete_fixup(&mut self, mut alink: PageLink, mut aparent: PageLink) {
+        while alink != self.root && alink.color() == Color::Black {
+            if alink == aparent.left() {
+                let mut blink = aparent.right();
+                if blink.color() == Color::Red {
                     if let Some(n) = blink.node_mut() {
                         n.color = Color::Black;
                     }
-                    if let Some(n) = alink.parent().node_mut() {
+                    if let Some(n) = aparent.node_mut() {
                         n.color = Color::Red;
                     }
-                    self.left_rotate(alink.parent().node_mut().unwrap());
-                    blink = alink.parent().right();
+                    self.left_rotate(aparent.node_mut().unwrap());
+                    blink = aparent.right();
                 }
-                if blink.left().node().unwrap().color == Color::Black
-                   && blink.right().node().unwrap().color == Color::Black
-                {
+                if blink.left().color() == Color::Black && blink.right().color() == Color::Black {
                     if let Some(n) = blink.node_mut() {
                         n.color = Color::Red;
                     }
-                    alink = alink.parent();
+                    alink = aparent;
+                    aparent = alink.parent();
                 } else {
-                    if blink.right().node().unwrap().color == Color::Black {
+                    if blink.right().color() == Color::Black {
                         if let Some(n) = blink.left().node_mut() {
                             n.color = Color::Black;
                         }
@@ -261,41 +366,41 @@ impl PageTree {
                             n.color = Color::Red;
                         }
                         self.right_rotate(blink.node_mut().unwrap());
-                        blink = alink.parent().right();
+                        blink = aparent.right();
                     }
                     if let Some(n) = blink.node_mut() {
-                        n.color = alink.parent().node().unwrap().color;
+                        n.color = aparent.color();
                     }
-                    if let Some(n) = alink.parent().node_mut() {
+                    if let Some(n) = aparent.node_mut() {
                         n.color = Color::Black;
                     }
                     if let Some(n) = blink.right().node_mut() {
                         n.color = Color::Black;
                     }
-                    self.left_rotate(alink.parent().node_mut().unwrap());
+                    self.left_rotate(aparent.node_mut().unwrap());
                     alink = self.root;
+                    aparent = None;
                 }
             } else {
-                let mut blink = alink.parent().left();
-                if let Some(Color::Red) = blink.node().map(|n| n.color) {
+                let mut blink = aparent.left();
+                if blink.color() == Color::Red {
                     if let Some(n) = blink.node_mut() {
                         n.color = Color::Black;
                     }
-                    if let Some(n) = alink.parent().node_mut() {
+                    if let Some(n) = aparent.node_mut() {
                         n.color = Color::Red;
                     }
-                    self.right_rotate(alink.parent().node_mut().unwrap());
-                    blink = alink.parent().left();
+                    self.right_rotate(aparent.node_mut().unwrap());
+                    blink = aparent.left();
                 }
-                if blink.right().node().unwrap().color == Color::Black
-                   && blink.left().node().unwrap().color == Color::Black
-                {
+                if blink.right().color() == Color::Black && blink.left().color() == Color::Black {
                     if let Some(n) = blink.node_mut() {
                         n.color = Color::Red;
                     }
-                    alink = alink.parent();
+                    alink = aparent;
+                    aparent = alink.parent();
                 } else {
-                    if blink.left().node().unwrap().color == Color::Black {
+                    if blink.left().color() == Color::Black {
                         if let Some(n) = blink.right().node_mut() {
                             n.color = Color::Black;
                         }
@@ -303,19 +408,20 @@ impl PageTree {
                             n.color = Color::Red;
                         }
                         self.left_rotate(blink.node_mut().unwrap());
-                        blink = alink.parent().left();
+                        blink = aparent.left();
                     }
                     if let Some(n) = blink.node_mut() {
-                        n.color = alink.parent().node().unwrap().color;
+                        n.color = aparent.color();
                     }
-                    if let Some(n) = alink.parent().node_mut() {
+                    if let Some(n) = aparent.node_mut() {
                         n.color = Color::Black;
                     }
                     if let Some(n) = blink.left().node_mut() {
                         n.color = Color::Black;
                     }
-                    self.right_rotate(alink.parent().node_mut().unwrap());
+                    self.right_rotate(aparent.node_mut().unwrap());
                     alink = self.root;
+                    aparent = None;
                 }
             }
         }
@@ -324,30 +430,19 @@ impl PageTree {
         }
     }
 
-    // FIXME: failed after exceeding limits, support custom allocator or impl Iterator instead
-    // Safety: calling it may corrupt the stack
-    pub unsafe fn traverse(&mut self) -> heapless::Vec<usize, 1024> {
-        let mut stack = heapless::Vec::<PageLink, 1024>::new();
-        let mut items = heapless::Vec::<usize, 1024>::new();
-        let mut visited = heapless::LinearMap::<PageLink, (), 1024>::new();
-        if self.root.is_some() {
-            stack.push(self.root).unwrap();
+    // Recomputes the longest run under `link` from its children
+    unsafe fn update(mut link: PageLink) {
+        let (left, right) = (link.left().longest(), link.right().longest());
+        if let Some(n) = link.node_mut() {
+            n.max = n.len.max(left).max(right);
         }
-        unsafe {
-            while let Some(link) = stack.pop() {
-                if link.left().is_some() && !visited.contains_key(&link.left()) {
-                    stack.push(link).unwrap();
-                    stack.push(link.left()).unwrap();
-                } else if link.left().is_none() || visited.contains_key(&link.left()) {
-                    items.push(link.node().unwrap().n).unwrap();
-                    visited.insert(link, ()).unwrap();
-                    if link.right().is_some() {
-                        stack.push(link.right()).unwrap();
-                    }
-                }
-            }
+    }
+
+    unsafe fn update_upward(mut link: PageLink) {
+        while link.is_some() {
+            Self::update(link);
+            link = link.parent();
         }
-        items
     }
 
     fn left_rotate(&mut self, a: &mut PageNode) {
@@ -370,6 +465,10 @@ impl PageTree {
         }
         b.left = a_ptr;
         a.parent = b_ptr;
+        unsafe {
+            Self::update(a_ptr);
+            Self::update(b_ptr);
+        }
     }
 
     fn right_rotate(&mut self, b: &mut PageNode) {
@@ -392,6 +491,10 @@ impl PageTree {
         }
         a.right = b_ptr;
         b.parent = a_ptr;
+        unsafe {
+            Self::update(b_ptr);
+            Self::update(a_ptr);
+        }
     }
 
     unsafe fn transplant(&mut self, alink: PageLink, mut blink: PageLink) {
@@ -440,34 +543,56 @@ impl PageTree {
             cur.parent()
         }
     }
+}
 
-    unsafe fn is_n_consecutive_link(start: PageLink, npages: usize) -> bool {
-        let mut cur = start;
-        for i in 1..npages {
-            let next = Self::next_link(cur);
-            if next.node().map(|n| n.n) == start.node().map(|n| n.n + i) {
-                cur = next;
-            } else {
-                return false
-            }
+pub struct Iter<'a> {
+    next: PageLink,
+    _tree: PhantomData<&'a PageTree>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        unsafe {
+            let item = self.next.node().map(|n| (n.n, n.len))?;
+            self.next = PageTree::next_link(self.next);
+            Some(item)
         }
-        true
     }
-
 }
 
-
 #[cfg(test)]
 mod test {
     use super::*;
+    use alloc::vec::Vec;
     use crate::HEAP_START;
     const SIZE: usize = 500_000_000;
 
+    fn base() -> usize {
+        let base = unsafe { &HEAP_START } as *const _ as usize + SIZE;
+        page_align_up(base) / PAGE_SIZE
+    }
+
+    // Checks the red-black and longest-run invariants, returning the black height
+    unsafe fn check(link: PageLink) -> usize {
+        let node = match link.node() {
+            Some(node) => node,
+            None => return 1,
+        };
+        if node.color == Color::Red {
+            assert_eq!((node.left.color(), node.right.color()), (Color::Black, Color::Black));
+        }
+        assert_eq!(node.max, node.len.max(node.left.longest()).max(node.right.longest()));
+        let height = check(node.left);
+        assert_eq!(height, check(node.right));
+        height + if node.color == Color::Black { 1 } else { 0 }
+    }
+
     #[test_case]
     fn test_page_tree_primitives() {
         let mut pt = PageTree::empty();
-        let base = unsafe { &HEAP_START } as *const _ as usize + SIZE;
-        let base = page_align_up(base) / PAGE_SIZE;
+        let base = base();
 
         unsafe {
             pt.insert(base+2);
@@ -475,23 +600,18 @@ mod test {
             pt.insert(base);
             pt.insert(base+9);
             pt.insert(base+8);
-            assert_eq!(&*pt.traverse(), [base, base+1, base+2, base+8, base+9]);
+            assert_eq!(pt.iter().collect::<Vec<_>>(), [(base, 3), (base+8, 2)]);
 
             pt.insert(base+5);
-            assert_eq!(&*pt.traverse(), [base, base+1, base+2, base+5, base+8, base+9]);
-
-            pt.delete(base+2);
-            assert_eq!(&*pt.traverse(), [base, base+1, base+5, base+8, base+9]);
-            pt.delete(base);
-            assert_eq!(&*pt.traverse(), [base+1, base+5, base+8, base+9]);
-            pt.delete(base+9);
-            assert_eq!(&*pt.traverse(), [base+1, base+5, base+8]);
-            pt.delete(base+5);
-            assert_eq!(&*pt.traverse(), [base+1, base+8]);
-            pt.delete(base+1);
-            assert_eq!(&*pt.traverse(), [base+8]);
-            pt.delete(base+8);
-            assert_eq!(&*pt.traverse(), []);
+            assert_eq!(pt.iter().collect::<Vec<_>>(), [(base, 3), (base+5, 1), (base+8, 2)]);
+            assert_eq!(pt.len(), 6);
+
+            // Joins the runs on both sides
+            pt.put_multiple(base+3, 2);
+            assert_eq!(pt.iter().collect::<Vec<_>>(), [(base, 6), (base+8, 2)]);
+            pt.put_multiple(base+6, 2);
+            assert_eq!(pt.iter().collect::<Vec<_>>(), [(base, 10)]);
+            check(pt.root);
         }
     }
 
@@ -505,45 +625,62 @@ mod test {
             pt.init(start, PAGE_SIZE * 3);
 
             assert_eq!(pt.get_multiple(2), Some(base));
-            assert_eq!(&*pt.traverse(), [base+2]);
+            assert_eq!(pt.pages().collect::<Vec<_>>(), [base+2]);
 
             assert_eq!(pt.get(), Some(base+2));
-            assert_eq!(&*pt.traverse(), []);
+            assert_eq!(pt.pages().collect::<Vec<_>>(), []);
 
             assert_eq!(pt.get(), None);
-            assert_eq!(&*pt.traverse(), []);
+            assert_eq!(pt.len(), 0);
         }
     }
 
     #[test_case]
     fn test_page_tree_remove_multiple() {
         let mut pt = PageTree::empty();
-        let base = unsafe { &HEAP_START } as *const _ as usize + SIZE;
-        let base = page_align_up(base) / PAGE_SIZE;
+        let base = base();
 
         unsafe {
             pt.insert(base+2);
-            pt.insert(base+1);
             pt.insert(base);
-            let start = PageTree::min_link(pt.root);
-            assert_eq!(start.node().map(|n| n.n), Some(base));
-            let next = PageTree::next_link(start);
-            assert_eq!(next.node().map(|n| n.n), Some(base+1));
-            let next = PageTree::next_link(next);
-            assert_eq!(next.node().map(|n| n.n), Some(base+2));
-            let next = PageTree::next_link(next);
-            assert_eq!(next.node().map(|n| n.n), None);
-
-
             pt.insert(base+9);
             pt.insert(base+8);
+            pt.insert(base+1);
 
             assert_eq!(pt.get_multiple(1), Some(base));
             assert_eq!(pt.get_multiple(2), Some(base+1));
             assert_eq!(pt.get_multiple(3), None);
             assert_eq!(pt.get_multiple(4), None);
             assert_eq!(pt.get_multiple(2), Some(base+8));
+            assert_eq!(pt.iter().next(), None);
         }
+    }
 
+    #[test_case]
+    fn test_page_tree_many_runs() {
+        let mut pt = PageTree::empty();
+        let base = base();
+
+        unsafe {
+            // Every other page, so nothing joins up
+            (0..2048).step_by(2).for_each(|i| pt.insert(base+i));
+            assert_eq!((pt.len(), pt.iter().count()), (1024, 1024));
+            assert_eq!(pt.get_multiple(2), None);
+            check(pt.root);
+
+            (1..2048).step_by(2).rev().for_each(|i| pt.insert(base+i));
+            assert_eq!(pt.iter().collect::<Vec<_>>(), [(base, 2048)]);
+
+            let taken = (0..100).map(|_| pt.get_multiple(3).unwrap()).collect::<Vec<_>>();
+            assert_eq!(taken[99], base+297);
+            check(pt.root);
+
+            // Handing back every third one leaves runs of 3, so 6 comes from the end
+            taken.iter().step_by(3).for_each(|&p| pt.put_multiple(p, 3));
+            assert_eq!(pt.get_multiple(6), Some(base+297));
+            taken.iter().enumerate().filter(|(i, _)| i % 3 != 0).for_each(|(_, &p)| pt.put_multiple(p, 3));
+            check(pt.root);
+            assert_eq!(pt.iter().collect::<Vec<_>>(), [(base, 297), (base+303, 2048-303)]);
+        }
     }
 }
@@ -1,39 +1,160 @@
 use core::ptr::NonNull;
 use core::alloc::{Layout, Allocator, AllocError};
+use core::mem::size_of;
 
 use super::chunk_list::ChunkList;
 use crate::mutex::Mutex;
 
+// Requests up to the largest class come from slabs: SLAB_SIZE blocks, aligned
+// to their size, cut into objects of one class. Anything bigger comes from
+// the free list.
+const SLAB_SIZE: usize = 1024;
+const MIN_CLASS: usize = 16;
+const NCLASSES: usize = 4; // 16, 32, 64 and 128 bytes
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+// At the front of every slab. Only slabs with free objects are linked in.
+struct Slab {
+    free: Option<NonNull<FreeObject>>,
+    used: usize,
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+}
+
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_CLASS).next_power_of_two();
+    let class = (size / MIN_CLASS).trailing_zeros() as usize;
+    Some(class).filter(|&class| class < NCLASSES)
+}
+
+fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
 #[derive(Debug)]
 pub struct Arena {
     chunks: ChunkList,
+    // Per class, slabs that have room
+    slabs: [Option<NonNull<Slab>>; NCLASSES],
 }
 
 unsafe impl Send for Arena {}
 
 impl Arena {
     pub const fn empty() -> Arena {
-        Self { chunks: ChunkList::empty() }
+        Self { chunks: ChunkList::empty(), slabs: [None; NCLASSES] }
     }
 
     pub unsafe fn new(start: usize, size: usize) -> Arena {
-        Self { chunks: ChunkList::new(start, size) }
+        Self { chunks: ChunkList::new(start, size), slabs: [None; NCLASSES] }
     }
 
     pub unsafe fn append(&mut self, start: usize, size: usize) {
         self.chunks.append(start, size)
     }
 
+    /// Forgets the free space in `start..end`, which must have nothing
+    /// allocated from it
+    pub fn remove(&mut self, start: usize, end: usize) {
+        self.chunks.remove_range(start, end)
+    }
+
+    /// What the arena takes from its free list to satisfy `layout`
+    pub fn backing_layout(layout: Layout) -> Layout {
+        match size_class(layout) {
+            Some(_) => slab_layout(),
+            None => ChunkList::align_layout(layout),
+        }
+    }
+
     pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
-        let layout = ChunkList::align_layout(layout);
-        self.chunks.pop_first_fit(layout)
+        match size_class(layout) {
+            Some(class) => unsafe { self.allocate_small(class) },
+            None => self.chunks.pop_first_fit(ChunkList::align_layout(layout)),
+        }
     }
 
     pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        self.chunks.push(
-            ptr.as_ptr() as usize,
-            ChunkList::align_layout(layout).size(),
-        )
+        match size_class(layout) {
+            Some(class) => self.deallocate_small(class, ptr),
+            None => self.chunks.push(
+                ptr.as_ptr() as usize,
+                ChunkList::align_layout(layout).size(),
+            ),
+        }
+    }
+
+    unsafe fn allocate_small(&mut self, class: usize) -> Option<NonNull<u8>> {
+        let mut slab = match self.slabs[class] {
+            Some(slab) => slab,
+            None => self.new_slab(class)?,
+        };
+        let object = slab.as_ref().free.unwrap();
+        let slab = slab.as_mut();
+        slab.free = object.as_ref().next;
+        slab.used += 1;
+        if slab.free.is_none() {
+            self.unlink(class, slab);
+        }
+        Some(object.cast())
+    }
+
+    unsafe fn deallocate_small(&mut self, class: usize, ptr: NonNull<u8>) {
+        let slab = &mut *((ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut Slab);
+        let was_full = slab.free.is_none();
+        let object = ptr.cast::<FreeObject>();
+        object.as_ptr().write(FreeObject { next: slab.free });
+        slab.free = Some(object);
+        slab.used -= 1;
+
+        // Empty slabs go straight back, so that the pages under them can too
+        if slab.used == 0 {
+            if !was_full {
+                self.unlink(class, slab);
+            }
+            self.chunks.push(slab as *mut Slab as usize, SLAB_SIZE);
+        } else if was_full {
+            self.link(class, slab);
+        }
+    }
+
+    unsafe fn new_slab(&mut self, class: usize) -> Option<NonNull<Slab>> {
+        let start = self.chunks.pop_first_fit(slab_layout())?.as_ptr() as usize;
+        let size = MIN_CLASS << class;
+        let first = start + super::align_up(size_of::<Slab>(), size);
+
+        let mut free = None;
+        for object in (first..start + SLAB_SIZE - size + 1).step_by(size).rev() {
+            let object = object as *mut FreeObject;
+            object.write(FreeObject { next: free });
+            free = Some(NonNull::new_unchecked(object));
+        }
+        let slab = start as *mut Slab;
+        slab.write(Slab { free, used: 0, prev: None, next: None });
+        self.link(class, &mut *slab);
+        Some(NonNull::new_unchecked(slab))
+    }
+
+    unsafe fn link(&mut self, class: usize, slab: &mut Slab) {
+        slab.prev = None;
+        slab.next = self.slabs[class];
+        if let Some(mut next) = slab.next {
+            next.as_mut().prev = Some(NonNull::from(&mut *slab));
+        }
+        self.slabs[class] = Some(NonNull::from(slab));
+    }
+
+    unsafe fn unlink(&mut self, class: usize, slab: &mut Slab) {
+        match slab.prev {
+            Some(mut prev) => prev.as_mut().next = slab.next,
+            None => self.slabs[class] = slab.next,
+        }
+        if let Some(mut next) = slab.next {
+            next.as_mut().prev = slab.prev;
+        }
     }
 }
 
@@ -82,5 +203,56 @@ mod test {
             let _ = Box::leak(Box::new_in([0u8; 4096], &arena));
         }
     }
-}
 
+    fn region(size: usize) -> Mutex<Arena> {
+        unsafe {
+            let start = &HEAP_START as *const _ as usize + SIZE;
+            Mutex::new(Arena::new(start, size))
+        }
+    }
+
+    // Whether the arena could hand out `size` bytes in one piece
+    fn fits(arena: &Mutex<Arena>, size: usize) -> bool {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = arena.lock().allocate(layout);
+        match ptr {
+            Some(ptr) => {
+                unsafe { arena.lock().deallocate(ptr, layout) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test_case]
+    fn test_free_list_coalesces() {
+        let arena = region(64 * 1024);
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let mut blocks = alloc::vec::Vec::new();
+        while let Some(ptr) = arena.lock().allocate(layout) {
+            blocks.push(ptr);
+        }
+        assert!(!fits(&arena, 512));
+
+        // Every other block back leaves holes too small for 1K
+        blocks.iter().step_by(2).for_each(|&ptr| unsafe { arena.lock().deallocate(ptr, layout) });
+        assert!(fits(&arena, 512) && !fits(&arena, 1024));
+
+        blocks.iter().skip(1).step_by(2).for_each(|&ptr| unsafe { arena.lock().deallocate(ptr, layout) });
+        assert!(fits(&arena, 60 * 1024));
+    }
+
+    #[test_case]
+    fn test_slabs() {
+        let arena = region(16 * 1024);
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let objects = (0..200).map(|_| arena.lock().allocate(small).unwrap()).collect::<alloc::vec::Vec<_>>();
+        // 24 bytes rounds up to the 32-byte class
+        assert!(objects.iter().all(|p| p.as_ptr() as usize % 32 == 0));
+        assert!(!fits(&arena, 12 * 1024));
+
+        // Emptied slabs go back to the free list
+        objects.iter().for_each(|&ptr| unsafe { arena.lock().deallocate(ptr, small) });
+        assert!(fits(&arena, 15 * 1024));
+    }
+}
@@ -1,10 +1,15 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+// No interrupt waiting on the lock
+const NO_IRQ: u32 = !0;
 
 #[derive(Debug)]
 pub struct Mutex<T: ?Sized> {
     locked: AtomicBool,
+    // Masked by `try_lock_or_defer`, unmasked when the holder lets go
+    deferred: AtomicU32,
     data: UnsafeCell<T>,
 }
 
@@ -18,6 +23,10 @@ pub struct MutexGuard<'a, T: ?Sized + 'a> {
 impl<'a, T: ?Sized + 'a> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
         self.lock.locked.store(false, Ordering::Relaxed);
+        let irq = self.lock.deferred.swap(NO_IRQ, Ordering::SeqCst);
+        if irq != NO_IRQ {
+            crate::gic::enable(irq);
+        }
     }
 }
 
@@ -38,6 +47,7 @@ impl<T> Mutex<T> {
     pub const fn new(data: T) -> Mutex<T> {
         Mutex {
             locked: AtomicBool::new(false),
+            deferred: AtomicU32::new(NO_IRQ),
             data: UnsafeCell::new(data),
         }
     }
@@ -51,6 +61,24 @@ impl<T> Mutex<T> {
         MutexGuard { lock: self }
     }
 
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| MutexGuard { lock: self })
+    }
+
+    /// For interrupt handlers: if the lock is taken, masks `irq` until
+    /// whoever holds it lets go, so the handler runs again then
+    pub fn try_lock_or_defer(&self, irq: u32) -> Option<MutexGuard<T>> {
+        self.try_lock().or_else(|| {
+            crate::gic::disable(irq);
+            self.deferred.store(irq, Ordering::SeqCst);
+            // The holder may have let go before it could see `irq`
+            self.try_lock()
+        })
+    }
+
     pub fn into_inner(self) -> T {
         self.data.into_inner()
     }
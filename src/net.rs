@@ -0,0 +1,997 @@
+pub mod arp;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod icmpv6;
+pub mod ipv4;
+pub mod ipv6;
+pub mod pcap;
+pub mod tcp;
+pub mod udp;
+
+use alloc::vec::Vec;
+
+use crate::kobject::{Container, KObjectRef, Label};
+use crate::timer;
+use crate::virtio::{SendError, VirtIONet};
+
+use arp::{ArpCache, ArpPacket};
+use dhcp::Dhcp;
+use ethernet::EthernetHeader;
+use icmpv6::Ndp;
+use ipv4::{Ipv4Header, Reassembler};
+use ipv6::Ipv6Header;
+use tcp::{Outgoing, TcpSockets};
+use udp::{Datagram, UdpHeader, UdpSockets};
+
+pub use ipv4::{Ipv4Addr, Ipv4Config};
+pub use ipv6::Ipv6Addr;
+pub use tcp::State as TcpState;
+
+pub const MTU: usize = 1500;
+const MAX_FRAME: usize = ethernet::HEADER_LEN + MTU;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Nothing on the link leads to the destination
+    NoRoute,
+    AddrInUse,
+    BadHandle,
+    TooLong,
+    /// The NIC had no room; try again later
+    QueueFull,
+    /// The connection is gone, or the peer is done sending
+    Closed,
+    /// The sender's label may not flow to the network's
+    Denied,
+    LinkDown,
+}
+
+/// Which namespace a socket lives in. Each has its own address and ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceId(usize);
+
+impl NamespaceId {
+    /// The kernel's own, which DHCP configures
+    pub const DEFAULT: NamespaceId = NamespaceId(0);
+}
+
+/// A socket handle, and the namespace it was opened in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle<H> {
+    ns: NamespaceId,
+    inner: H,
+}
+
+pub type UdpHandle = Handle<udp::UdpHandle>;
+pub type TcpHandle = Handle<tcp::TcpHandle>;
+
+// A container's share of the interface. Packets it sends carry its owner's
+// label.
+struct Namespace {
+    owner: Option<KObjectRef<Container>>,
+    config: Ipv4Config,
+    ipv6: Ipv6Addr,
+    // Takes the default namespace's netmask and gateway as they change
+    shares_subnet: bool,
+    udp: UdpSockets,
+    tcp: TcpSockets,
+}
+
+impl Namespace {
+    fn new(owner: Option<KObjectRef<Container>>, config: Ipv4Config, ipv6: Ipv6Addr) -> Namespace {
+        Namespace {
+            owner,
+            config,
+            ipv6,
+            shares_subnet: false,
+            udp: UdpSockets::new(),
+            tcp: TcpSockets::new(),
+        }
+    }
+}
+
+// Each namespace's link-local address: the EUI-64 one, with the namespace
+// folded into its ff:fe filler so they don't collide. No duplicate
+// detection is done for them.
+fn link_local(mac: [u8; 6], ns: NamespaceId) -> Ipv6Addr {
+    let mut addr = Ipv6Addr::link_local(mac);
+    addr.0[12] ^= ns.0 as u8;
+    addr
+}
+
+/// What the stack needs from a NIC
+pub trait Device {
+    fn mac(&self) -> [u8; 6];
+    fn recv(&mut self, frame: &mut [u8]) -> Option<usize>;
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error>;
+
+    fn link_up(&self) -> bool {
+        true
+    }
+}
+
+impl<'a> Device for VirtIONet<'a> {
+    fn mac(&self) -> [u8; 6] {
+        VirtIONet::mac(self)
+    }
+
+    fn recv(&mut self, frame: &mut [u8]) -> Option<usize> {
+        self.poll_recv(frame)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.try_send(frame).map_err(|e| match e {
+            SendError::QueueFull => Error::QueueFull,
+            SendError::TooLong => Error::TooLong,
+            SendError::LinkDown => Error::LinkDown,
+        })
+    }
+
+    fn link_up(&self) -> bool {
+        VirtIONet::link_up(self)
+    }
+}
+
+/// A NIC and the IPv4 and IPv6 state that goes with it. Containers share
+/// it through namespaces, and apps through their UDP and TCP sockets.
+pub struct Interface<D: Device> {
+    dev: D,
+    mac: [u8; 6],
+    namespaces: Vec<Namespace>,
+    // What a namespace's owner has to flow to before its packets go out
+    network_label: Option<KObjectRef<Label>>,
+    arp: ArpCache,
+    neighbors: ArpCache<Ipv6Addr>,
+    fragments: Reassembler,
+    dhcp: Option<Dhcp>,
+    next_id: u16,
+    link_up: bool,
+}
+
+impl<D: Device> Interface<D> {
+    pub fn new(dev: D, config: Ipv4Config) -> Interface<D> {
+        let mac = dev.mac();
+        Interface {
+            dev,
+            mac,
+            namespaces: alloc::vec![Namespace::new(None, config, link_local(mac, NamespaceId::DEFAULT))],
+            network_label: None,
+            arp: ArpCache::new(),
+            neighbors: ArpCache::new(),
+            fragments: Reassembler::new(),
+            dhcp: None,
+            next_id: 0,
+            link_up: true,
+        }
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    /// Whether the NIC had a link at the last poll. Nothing is sent while
+    /// it doesn't.
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// The default namespace's configuration
+    pub fn config(&self) -> Ipv4Config {
+        self.namespaces[NamespaceId::DEFAULT.0].config
+    }
+
+    /// The default namespace's link-local address
+    pub fn ipv6_addr(&self) -> Ipv6Addr {
+        self.namespaces[NamespaceId::DEFAULT.0].ipv6
+    }
+
+    pub fn set_config(&mut self, config: Ipv4Config) {
+        self.namespaces[NamespaceId::DEFAULT.0].config = config;
+        for ns in self.namespaces.iter_mut().filter(|ns| ns.shares_subnet) {
+            ns.config = Ipv4Config { addr: ns.config.addr, ..config };
+        }
+    }
+
+    /// Gives `owner` its own address and ports on this interface
+    pub fn add_namespace(&mut self, owner: KObjectRef<Container>, config: Ipv4Config) -> NamespaceId {
+        let ns = NamespaceId(self.namespaces.len());
+        self.namespaces.push(Namespace::new(Some(owner), config, link_local(self.mac, ns)));
+        ns
+    }
+
+    /// Gives `owner` the address `addr` on whatever subnet the default
+    /// namespace is on, now or once DHCP finds one
+    pub fn add_namespace_on_subnet(&mut self, owner: KObjectRef<Container>, addr: Ipv4Addr) -> NamespaceId {
+        let ns = self.add_namespace(owner, Ipv4Config { addr, ..self.config() });
+        self.namespaces[ns.0].shares_subnet = true;
+        ns
+    }
+
+    pub fn namespace_of(&self, owner: KObjectRef<Container>) -> Option<NamespaceId> {
+        self.namespaces.iter().position(|ns| ns.owner == Some(owner)).map(NamespaceId)
+    }
+
+    pub fn set_network_label(&mut self, label: KObjectRef<Label>) {
+        self.network_label = Some(label);
+    }
+
+    fn ns(&mut self, ns: NamespaceId) -> Result<&mut Namespace, Error> {
+        self.namespaces.get_mut(ns.0).ok_or(Error::BadHandle)
+    }
+
+    // Containers only reach the wire once a network label is set, and only
+    // if theirs flows to it
+    fn may_send(&self, ns: NamespaceId) -> bool {
+        match self.namespaces.get(ns.0).map(|ns| ns.owner) {
+            Some(None) => true,
+            Some(Some(owner)) => match (owner.label(), self.network_label) {
+                (Some(label), Some(network)) => label.can_flow_to(&network),
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    // Ports in a namespace are its owner's to open. The default namespace is
+    // the kernel's, and the root container's.
+    fn may_bind(&self, ns: NamespaceId) -> Result<(), Error> {
+        let owner = self.namespaces.get(ns.0).ok_or(Error::BadHandle)?.owner;
+        let caller = match crate::thread::current_thread_koref().and_then(|th| th.meta().parent) {
+            Some(ct_ref) => ct_ref,
+            // Boot, before threads live in containers
+            None => return Ok(()),
+        };
+        let allowed = match owner {
+            Some(owner) => owner == caller,
+            None => caller.meta().parent.is_none(),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::Denied)
+        }
+    }
+
+    // Whose a datagram sent to `dst` is. Broadcasts go to the first that
+    // takes them, the default namespace if it does.
+    fn namespace_for(&self, dst: Ipv4Addr) -> Option<NamespaceId> {
+        self.namespaces
+            .iter()
+            .position(|ns| !ns.config.addr.is_unspecified() && ns.config.addr == dst)
+            .or_else(|| self.namespaces.iter().position(|ns| ns.config.accepts(dst)))
+            .map(NamespaceId)
+    }
+
+    /// Asks for an address over DHCP and keeps renewing it. If no server
+    /// answers, the interface takes `fallback` instead.
+    pub fn start_dhcp(&mut self, fallback: Option<Ipv4Config>) {
+        self.dhcp = Dhcp::start(self, fallback);
+        if self.dhcp.is_none() {
+            if let Some(fallback) = fallback {
+                self.set_config(fallback);
+            }
+        }
+    }
+
+    /// Handles every frame that has arrived, then whatever DHCP and TCP
+    /// timers ran out since the last call
+    pub fn poll(&mut self) {
+        let now = timer::current_ticks();
+        let link_up = self.dev.link_up();
+        if link_up != self.link_up {
+            self.link_up = link_up;
+            crate::debug!("net: link {}", if link_up { "up" } else { "down" });
+        }
+        let mut frame = [0; MAX_FRAME];
+        while let Some(len) = self.dev.recv(&mut frame) {
+            self.receive(&frame[..len], now);
+        }
+        self.arp.expire(now);
+        self.neighbors.expire(now);
+
+        if let Some(mut dhcp) = self.dhcp.take() {
+            dhcp.poll(self, now);
+            if !dhcp.is_done() {
+                self.dhcp = Some(dhcp);
+            }
+        }
+
+        for i in 0..self.namespaces.len() {
+            let mut out = Vec::new();
+            self.namespaces[i].tcp.on_timer(now, &mut out);
+            self.send_tcp(NamespaceId(i), out);
+        }
+    }
+
+    fn receive(&mut self, frame: &[u8], now: u64) {
+        let eth = match EthernetHeader::parse(frame) {
+            Some(eth)
+                if eth.dst == self.mac || eth.dst == ethernet::BROADCAST || ethernet::is_ipv6_multicast(eth.dst) =>
+            {
+                eth
+            }
+            _ => return,
+        };
+        let payload = &frame[ethernet::HEADER_LEN..];
+        match eth.ethertype {
+            ethernet::ETHERTYPE_ARP => self.receive_arp(payload, now),
+            ethernet::ETHERTYPE_IPV4 => self.receive_ipv4(payload, now),
+            ethernet::ETHERTYPE_IPV6 => self.receive_ipv6(payload, now),
+            _ => {}
+        }
+    }
+
+    fn send_frame(&mut self, dst: [u8; 6], ethertype: u16, payload: &[u8]) -> Result<(), Error> {
+        let mut frame = [0; MAX_FRAME];
+        let len = ethernet::HEADER_LEN + payload.len();
+        if len > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+        EthernetHeader { dst, src: self.mac, ethertype }.emit(&mut frame);
+        frame[ethernet::HEADER_LEN..len].copy_from_slice(payload);
+        self.dev.send(&frame[..len])
+    }
+
+    fn receive_arp(&mut self, packet: &[u8], now: u64) {
+        let arp = match ArpPacket::parse(packet) {
+            Some(arp) => arp,
+            None => return,
+        };
+        let for_us = self
+            .namespaces
+            .iter()
+            .position(|ns| !ns.config.addr.is_unspecified() && ns.config.addr == arp.target_addr)
+            .map(NamespaceId);
+        if for_us.is_some() || self.arp.contains(arp.sender_addr) {
+            self.arp.insert(arp.sender_addr, arp.sender_mac, now);
+            for mut frame in self.arp.take_pending(arp.sender_addr) {
+                frame[0..6].copy_from_slice(&arp.sender_mac);
+                let _ = self.dev.send(&frame);
+            }
+        }
+        // Answering says the namespace exists, which is already too much for
+        // one that may not send
+        if for_us.map_or(false, |ns| self.may_send(ns)) && arp.operation == arp::OP_REQUEST {
+            let reply = ArpPacket {
+                operation: arp::OP_REPLY,
+                sender_mac: self.mac,
+                sender_addr: arp.target_addr,
+                target_mac: arp.sender_mac,
+                target_addr: arp.sender_addr,
+            };
+            let mut packet = [0; arp::PACKET_LEN];
+            reply.emit(&mut packet);
+            let _ = self.send_frame(arp.sender_mac, ethernet::ETHERTYPE_ARP, &packet);
+        }
+    }
+
+    fn request_mac(&mut self, src: Ipv4Addr, addr: Ipv4Addr) -> Result<(), Error> {
+        let request = ArpPacket {
+            operation: arp::OP_REQUEST,
+            sender_mac: self.mac,
+            sender_addr: src,
+            target_mac: [0; 6],
+            target_addr: addr,
+        };
+        let mut packet = [0; arp::PACKET_LEN];
+        request.emit(&mut packet);
+        self.send_frame(ethernet::BROADCAST, ethernet::ETHERTYPE_ARP, &packet)
+    }
+
+    fn receive_ipv4(&mut self, packet: &[u8], now: u64) {
+        let (header, ns) = match Ipv4Header::parse(packet) {
+            Some(header) => match self.namespace_for(header.dst) {
+                Some(ns) => (header, ns),
+                None => return,
+            },
+            None => return,
+        };
+        let payload = &packet[header.header_len..header.total_len];
+        if header.is_fragment() {
+            if let Some(whole) = self.fragments.add(&header, payload, now) {
+                self.deliver(ns, &header, &packet[..header.header_len], &whole);
+            }
+        } else {
+            self.deliver(ns, &header, &packet[..header.header_len], payload);
+        }
+    }
+
+    // `raw_header` is kept around to be quoted in ICMP errors
+    fn deliver(&mut self, ns: NamespaceId, header: &Ipv4Header, raw_header: &[u8], payload: &[u8]) {
+        match header.protocol {
+            ipv4::PROTO_ICMP => {
+                if let Some(reply) = icmp::echo_reply(payload) {
+                    let _ = self.send_ipv4(ns, header.src, ipv4::PROTO_ICMP, &reply);
+                }
+            }
+            ipv4::PROTO_TCP => {
+                let mut out = Vec::new();
+                let now = timer::current_ticks();
+                self.namespaces[ns.0].tcp.receive(header.src, header.dst, payload, now, &mut out);
+                self.send_tcp(ns, out);
+            }
+            ipv4::PROTO_UDP => {
+                let udp = match UdpHeader::parse(payload, header.src, header.dst) {
+                    Some(udp) => udp,
+                    None => return,
+                };
+                let datagram = Datagram {
+                    src: header.src,
+                    src_port: udp.src_port,
+                    data: payload[udp::HEADER_LEN..udp.len].to_vec(),
+                };
+                let space = &mut self.namespaces[ns.0];
+                if !space.udp.deliver(udp.dst_port, datagram) && header.dst == space.config.addr {
+                    let mut quoted = Vec::with_capacity(raw_header.len() + payload.len());
+                    quoted.extend_from_slice(raw_header);
+                    quoted.extend_from_slice(payload);
+                    let message = icmp::unreachable(icmp::CODE_PORT_UNREACHABLE, &quoted, raw_header.len());
+                    let _ = self.send_ipv4(ns, header.src, ipv4::PROTO_ICMP, &message);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Sends `payload` from `ns` to `dst`. If the next hop isn't resolved
+    /// yet, the datagram waits for it to answer ARP.
+    pub fn send_ipv4(&mut self, ns: NamespaceId, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), Error> {
+        let len = ethernet::HEADER_LEN + ipv4::HEADER_LEN + payload.len();
+        if len > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+        let config = self.ns(ns)?.config;
+        if !self.may_send(ns) {
+            return Err(Error::Denied);
+        }
+        let next_hop = config.next_hop(dst).ok_or(Error::NoRoute)?;
+
+        let mut frame = [0; MAX_FRAME];
+        let header = Ipv4Header::new(config.addr, dst, protocol, payload.len(), self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        header.emit(&mut frame[ethernet::HEADER_LEN..]);
+        frame[ethernet::HEADER_LEN + ipv4::HEADER_LEN..len].copy_from_slice(payload);
+
+        let now = timer::current_ticks();
+        let dst_mac = if dst.is_broadcast() || dst == config.subnet_broadcast() {
+            Some(ethernet::BROADCAST)
+        } else {
+            self.arp.lookup(next_hop, now)
+        };
+        let mut eth = EthernetHeader {
+            dst: [0; 6],
+            src: self.mac,
+            ethertype: ethernet::ETHERTYPE_IPV4,
+        };
+        match dst_mac {
+            Some(mac) => {
+                eth.dst = mac;
+                eth.emit(&mut frame);
+                self.dev.send(&frame[..len])
+            }
+            None => {
+                eth.emit(&mut frame);
+                if self.arp.queue(next_hop, frame[..len].to_vec(), now) {
+                    self.request_mac(config.addr, next_hop)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn receive_ipv6(&mut self, packet: &[u8], now: u64) {
+        let header = match Ipv6Header::parse(packet) {
+            Some(header)
+                if header.dst == Ipv6Addr::ALL_NODES
+                    || self
+                        .namespaces
+                        .iter()
+                        .any(|ns| header.dst == ns.ipv6 || header.dst == ns.ipv6.solicited_node()) =>
+            {
+                header
+            }
+            _ => return,
+        };
+        let payload = &packet[ipv6::HEADER_LEN..ipv6::HEADER_LEN + header.payload_len];
+        if header.next_header != ipv6::NEXT_ICMPV6 || !icmpv6::verify(header.src, header.dst, payload) {
+            return;
+        }
+
+        if payload[0] == icmpv6::TYPE_ECHO_REQUEST {
+            if let Some(ns) = self.namespace_for_ipv6(header.dst) {
+                if let Some(reply) = icmpv6::echo_reply(header.dst, header.src, payload) {
+                    let _ = self.send_ipv6(ns, header.src, ipv6::NEXT_ICMPV6, ipv6::DEFAULT_HOP_LIMIT, &reply);
+                }
+            }
+            return;
+        }
+        // Neighbour Discovery from off the link is forged
+        if header.hop_limit != 255 {
+            return;
+        }
+        match icmpv6::parse_ndp(payload) {
+            Some(Ndp::Solicit { target, source_mac }) => {
+                let ns = match self.namespace_for_ipv6(target) {
+                    Some(ns) if self.may_send(ns) => ns,
+                    _ => return,
+                };
+                // A solicitation from the unspecified address is someone
+                // checking for duplicates, and gets told on all-nodes
+                let (dst, solicited) = match source_mac {
+                    Some(mac) if !header.src.is_unspecified() => {
+                        self.learn_neighbor(header.src, mac, now);
+                        (header.src, true)
+                    }
+                    _ => (Ipv6Addr::ALL_NODES, false),
+                };
+                let advert = icmpv6::advert(target, dst, target, self.mac, solicited);
+                let _ = self.send_ipv6(ns, dst, ipv6::NEXT_ICMPV6, 255, &advert);
+            }
+            Some(Ndp::Advert { target, target_mac: Some(mac) })
+                if self.neighbors.contains(target) || target == header.src =>
+            {
+                self.learn_neighbor(target, mac, now);
+            }
+            _ => {}
+        }
+    }
+
+    fn namespace_for_ipv6(&self, addr: Ipv6Addr) -> Option<NamespaceId> {
+        self.namespaces.iter().position(|ns| ns.ipv6 == addr).map(NamespaceId)
+    }
+
+    fn learn_neighbor(&mut self, addr: Ipv6Addr, mac: [u8; 6], now: u64) {
+        self.neighbors.insert(addr, mac, now);
+        for mut frame in self.neighbors.take_pending(addr) {
+            frame[0..6].copy_from_slice(&mac);
+            let _ = self.dev.send(&frame);
+        }
+    }
+
+    /// Sends `payload` from `ns` to `dst` on the link. Unicasts wait for the
+    /// neighbour to answer a solicitation first.
+    pub fn send_ipv6(
+        &mut self,
+        ns: NamespaceId,
+        dst: Ipv6Addr,
+        next_header: u8,
+        hop_limit: u8,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        if !self.may_send(ns) {
+            return Err(Error::Denied);
+        }
+        let src = self.ns(ns)?.ipv6;
+        let len = ethernet::HEADER_LEN + ipv6::HEADER_LEN + payload.len();
+        if len > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+        let mut frame = [0; MAX_FRAME];
+        Ipv6Header {
+            payload_len: payload.len(),
+            next_header,
+            hop_limit,
+            src,
+            dst,
+        }
+        .emit(&mut frame[ethernet::HEADER_LEN..]);
+        frame[ethernet::HEADER_LEN + ipv6::HEADER_LEN..len].copy_from_slice(payload);
+
+        let now = timer::current_ticks();
+        let dst_mac = if dst.is_multicast() {
+            Some(dst.multicast_mac())
+        } else {
+            self.neighbors.lookup(dst, now)
+        };
+        EthernetHeader {
+            dst: dst_mac.unwrap_or([0; 6]),
+            src: self.mac,
+            ethertype: ethernet::ETHERTYPE_IPV6,
+        }
+        .emit(&mut frame);
+        if dst_mac.is_some() {
+            return self.dev.send(&frame[..len]);
+        }
+
+        if self.neighbors.queue(dst, frame[..len].to_vec(), now) {
+            // The solicited-node group is multicast, so this goes straight out
+            let group = dst.solicited_node();
+            let solicit = icmpv6::solicit(src, group, dst, self.mac);
+            self.send_ipv6(ns, group, ipv6::NEXT_ICMPV6, 255, &solicit)?;
+        }
+        Ok(())
+    }
+
+    fn send_tcp(&mut self, ns: NamespaceId, out: Vec<Outgoing>) {
+        for segment in out {
+            let _ = self.send_ipv4(ns, segment.dst, ipv4::PROTO_TCP, &segment.segment);
+        }
+    }
+
+    pub fn tcp_listen(&mut self, ns: NamespaceId, port: u16) -> Result<TcpHandle, Error> {
+        self.may_bind(ns)?;
+        let space = self.ns(ns)?;
+        let inner = space.tcp.listen(space.config.addr, port)?;
+        Ok(Handle { ns, inner })
+    }
+
+    /// Takes a connection that came in on `listener`, if one has
+    pub fn tcp_accept(&mut self, listener: TcpHandle) -> Result<Option<TcpHandle>, Error> {
+        self.poll();
+        let accepted = self.ns(listener.ns)?.tcp.accept(listener.inner)?;
+        Ok(accepted.map(|inner| Handle { ns: listener.ns, inner }))
+    }
+
+    /// Starts connecting from `ns` to `dst`. The handle is usable once
+    /// `tcp_state` says it is established.
+    pub fn tcp_connect(&mut self, ns: NamespaceId, dst: Ipv4Addr, port: u16) -> Result<TcpHandle, Error> {
+        if !self.may_send(ns) {
+            return Err(Error::Denied);
+        }
+        let space = self.ns(ns)?;
+        space.config.next_hop(dst).ok_or(Error::NoRoute)?;
+        let mut out = Vec::new();
+        let inner = space.tcp.connect(space.config.addr, dst, port, timer::current_ticks(), &mut out)?;
+        self.send_tcp(ns, out);
+        Ok(Handle { ns, inner })
+    }
+
+    /// Queues as much of `data` as the send buffer takes, and returns how
+    /// much that was
+    pub fn tcp_send(&mut self, handle: TcpHandle, data: &[u8]) -> Result<usize, Error> {
+        self.poll();
+        if !self.may_send(handle.ns) {
+            return Err(Error::Denied);
+        }
+        let mut out = Vec::new();
+        let sent = self.ns(handle.ns)?.tcp.send(handle.inner, data, timer::current_ticks(), &mut out);
+        self.send_tcp(handle.ns, out);
+        sent
+    }
+
+    /// Copies what has arrived on `handle` into `buf`. Returns 0 if nothing
+    /// has.
+    pub fn tcp_recv(&mut self, handle: TcpHandle, buf: &mut [u8]) -> Result<usize, Error> {
+        self.poll();
+        let mut out = Vec::new();
+        let received = self.ns(handle.ns)?.tcp.recv(handle.inner, buf, timer::current_ticks(), &mut out);
+        self.send_tcp(handle.ns, out);
+        received
+    }
+
+    /// Closes our side of the connection, or stops listening
+    pub fn tcp_close(&mut self, handle: TcpHandle) -> Result<(), Error> {
+        let mut out = Vec::new();
+        let closed = self.ns(handle.ns)?.tcp.close(handle.inner, timer::current_ticks(), &mut out);
+        self.send_tcp(handle.ns, out);
+        closed
+    }
+
+    pub fn tcp_state(&mut self, handle: TcpHandle) -> Result<TcpState, Error> {
+        self.ns(handle.ns)?.tcp.state(handle.inner)
+    }
+
+    /// Opens a UDP port in `ns` for the caller. Port 0 picks a free one.
+    pub fn udp_bind(&mut self, ns: NamespaceId, port: u16) -> Result<UdpHandle, Error> {
+        self.may_bind(ns)?;
+        let inner = self.ns(ns)?.udp.bind(port)?;
+        Ok(Handle { ns, inner })
+    }
+
+    pub fn udp_close(&mut self, handle: UdpHandle) {
+        if let Ok(space) = self.ns(handle.ns) {
+            space.udp.close(handle.inner)
+        }
+    }
+
+    pub fn udp_send_to(&mut self, handle: UdpHandle, dst: Ipv4Addr, port: u16, data: &[u8]) -> Result<(), Error> {
+        let space = self.ns(handle.ns)?;
+        let src_port = space.udp.port(handle.inner)?;
+        let src = space.config.addr;
+        let len = udp::HEADER_LEN + data.len();
+        if len > MTU - ipv4::HEADER_LEN {
+            return Err(Error::TooLong);
+        }
+        let mut datagram = [0; MTU - ipv4::HEADER_LEN];
+        datagram[udp::HEADER_LEN..len].copy_from_slice(data);
+        UdpHeader { src_port, dst_port: port, len }.emit(&mut datagram, src, dst);
+        self.send_ipv4(handle.ns, dst, ipv4::PROTO_UDP, &datagram[..len])
+    }
+
+    // Takes the oldest datagram sent to `handle` without polling first
+    fn udp_pop(&mut self, handle: UdpHandle) -> Result<Option<Datagram>, Error> {
+        self.ns(handle.ns)?.udp.pop(handle.inner)
+    }
+
+    /// Copies the oldest datagram sent to `handle` into `buf`. Returns the
+    /// sender and how much was copied.
+    pub fn udp_recv_from(&mut self, handle: UdpHandle, buf: &mut [u8]) -> Result<Option<(Ipv4Addr, u16, usize)>, Error> {
+        self.poll();
+        Ok(self.udp_pop(handle)?.map(|datagram| {
+            let len = core::cmp::min(buf.len(), datagram.data.len());
+            buf[..len].copy_from_slice(&datagram.data[..len]);
+            (datagram.src, datagram.src_port, len)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use crate::kobject::fixtures::{container, pages};
+    use crate::kobject::KOBJ_NPAGES;
+
+    // Frames handed in by the test, and those the stack sent
+    struct Loopback {
+        rx: VecDeque<Vec<u8>>,
+        tx: Vec<Vec<u8>>,
+    }
+
+    impl Device for Loopback {
+        fn mac(&self) -> [u8; 6] {
+            [2, 0, 0, 0, 0, 4]
+        }
+
+        fn recv(&mut self, frame: &mut [u8]) -> Option<usize> {
+            self.rx.pop_front().map(|f| {
+                frame[..f.len()].copy_from_slice(&f);
+                f.len()
+            })
+        }
+
+        fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+            self.tx.push(frame.to_vec());
+            Ok(())
+        }
+    }
+
+    const HOST_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const HOST: Ipv4Addr = Ipv4Addr([192, 168, 14, 1]);
+
+    fn interface() -> Interface<Loopback> {
+        let config = Ipv4Config {
+            addr: Ipv4Addr([192, 168, 14, 4]),
+            netmask: Ipv4Addr([255, 255, 255, 0]),
+            gateway: Some(HOST),
+        };
+        Interface::new(Loopback { rx: VecDeque::new(), tx: Vec::new() }, config)
+    }
+
+    fn udp_frame(iface: &Interface<Loopback>, src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8> {
+        udp_frame_to(iface, iface.config().addr, src_port, dst_port, data)
+    }
+
+    fn udp_frame_to(iface: &Interface<Loopback>, dst: Ipv4Addr, src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8> {
+        let len = udp::HEADER_LEN + data.len();
+        let mut frame = alloc::vec![0; ethernet::HEADER_LEN + ipv4::HEADER_LEN + len];
+        EthernetHeader { dst: iface.mac(), src: HOST_MAC, ethertype: ethernet::ETHERTYPE_IPV4 }.emit(&mut frame);
+        Ipv4Header::new(HOST, dst, ipv4::PROTO_UDP, len, 9).emit(&mut frame[ethernet::HEADER_LEN..]);
+        let datagram = &mut frame[ethernet::HEADER_LEN + ipv4::HEADER_LEN..];
+        datagram[udp::HEADER_LEN..].copy_from_slice(data);
+        UdpHeader { src_port, dst_port, len }.emit(datagram, HOST, dst);
+        frame
+    }
+
+    #[test_case]
+    fn test_namespaces_keep_their_ports_and_labels() {
+        let mut iface = interface();
+        iface.arp.insert(HOST, HOST_MAC, 0);
+        let subnet = iface.config();
+        let public = iface.add_namespace(container(None, "T,T", 0), Ipv4Config { addr: Ipv4Addr([192, 168, 14, 5]), ..subnet });
+        let secret = iface.add_namespace(container(None, "gongqi,T", 0), Ipv4Config { addr: Ipv4Addr([192, 168, 14, 6]), ..subnet });
+
+        // The same port is free in every namespace
+        let a = iface.udp_bind(public, 44).unwrap();
+        let b = iface.udp_bind(secret, 44).unwrap();
+        assert!(iface.udp_bind(NamespaceId::DEFAULT, 44).is_ok());
+
+        let frame = udp_frame_to(&iface, Ipv4Addr([192, 168, 14, 6]), 1000, 44, b"secret");
+        iface.device_mut().rx.push_back(frame);
+        let mut buf = [0; 16];
+        assert_eq!(iface.udp_recv_from(a, &mut buf), Ok(None));
+        assert_eq!(iface.udp_recv_from(b, &mut buf), Ok(Some((HOST, 1000, 6))));
+
+        // Nothing leaves a container until there is a label to check against
+        assert_eq!(iface.udp_send_to(a, HOST, 1000, b"hi"), Err(Error::Denied));
+        iface.set_network_label(unsafe { Label::create(pages(KOBJ_NPAGES), "T,T") });
+        assert_eq!(iface.udp_send_to(a, HOST, 1000, b"hi"), Ok(()));
+        assert_eq!(iface.udp_send_to(b, HOST, 1000, b"leak"), Err(Error::Denied));
+        assert_eq!(iface.tcp_connect(secret, HOST, 80), Err(Error::Denied));
+
+        let sent = iface.device_mut().tx.pop().unwrap();
+        assert!(iface.device_mut().tx.is_empty());
+        let ip = Ipv4Header::parse(&sent[ethernet::HEADER_LEN..]).unwrap();
+        assert_eq!(ip.src, Ipv4Addr([192, 168, 14, 5]));
+    }
+
+    #[test_case]
+    fn test_namespaces_follow_the_leased_subnet() {
+        let mut iface = interface();
+        iface.set_config(Ipv4Config::UNCONFIGURED);
+        let ns = iface.add_namespace_on_subnet(container(None, "T,T", 0), Ipv4Addr([10, 0, 2, 16]));
+        let fixed = iface.add_namespace(container(None, "T,T", 0), Ipv4Config { addr: Ipv4Addr([10, 0, 3, 16]), ..interface().config() });
+
+        let leased = Ipv4Config {
+            addr: Ipv4Addr([10, 0, 2, 15]),
+            netmask: Ipv4Addr([255, 255, 255, 0]),
+            gateway: Some(Ipv4Addr([10, 0, 2, 2])),
+        };
+        iface.set_config(leased);
+        assert_eq!(iface.namespaces[ns.0].config, Ipv4Config { addr: Ipv4Addr([10, 0, 2, 16]), ..leased });
+        assert_eq!(iface.namespaces[fixed.0].config.gateway, Some(HOST));
+
+        // And lose it with the lease
+        iface.set_config(Ipv4Config::UNCONFIGURED);
+        assert_eq!(iface.namespaces[ns.0].config.gateway, None);
+        assert_eq!(iface.namespaces[ns.0].config.addr, Ipv4Addr([10, 0, 2, 16]));
+    }
+
+    #[test_case]
+    fn test_udp_sockets_share_the_interface() {
+        let mut iface = interface();
+        let a = iface.udp_bind(NamespaceId::DEFAULT, 44).unwrap();
+        let b = iface.udp_bind(NamespaceId::DEFAULT, 0).unwrap();
+        assert_eq!(iface.udp_bind(NamespaceId::DEFAULT, 44), Err(Error::AddrInUse));
+
+        let frame = udp_frame(&iface, 1000, 44, b"hello");
+        iface.device_mut().rx.push_back(frame);
+        let mut buf = [0; 16];
+        assert_eq!(iface.udp_recv_from(b, &mut buf), Ok(None));
+        assert_eq!(iface.udp_recv_from(a, &mut buf), Ok(Some((HOST, 1000, 5))));
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test_case]
+    fn test_send_waits_for_arp() {
+        let mut iface = interface();
+        let sock = iface.udp_bind(NamespaceId::DEFAULT, 0).unwrap();
+        iface.udp_send_to(sock, Ipv4Addr([8, 8, 8, 8]), 53, b"query").unwrap();
+
+        // Only the request for the gateway goes out
+        let request = iface.device_mut().tx.pop().unwrap();
+        assert!(iface.device_mut().tx.is_empty());
+        let eth = EthernetHeader::parse(&request).unwrap();
+        assert_eq!((eth.dst, eth.ethertype), (ethernet::BROADCAST, ethernet::ETHERTYPE_ARP));
+        let arp = ArpPacket::parse(&request[ethernet::HEADER_LEN..]).unwrap();
+        assert_eq!(arp.target_addr, HOST);
+
+        let mut reply = alloc::vec![0; ethernet::HEADER_LEN + arp::PACKET_LEN];
+        EthernetHeader { dst: iface.mac(), src: HOST_MAC, ethertype: ethernet::ETHERTYPE_ARP }.emit(&mut reply);
+        ArpPacket {
+            operation: arp::OP_REPLY,
+            sender_mac: HOST_MAC,
+            sender_addr: HOST,
+            target_mac: iface.mac(),
+            target_addr: iface.config().addr,
+        }
+        .emit(&mut reply[ethernet::HEADER_LEN..]);
+        iface.device_mut().rx.push_back(reply);
+        iface.poll();
+
+        let sent = iface.device_mut().tx.pop().unwrap();
+        let eth = EthernetHeader::parse(&sent).unwrap();
+        assert_eq!((eth.dst, eth.ethertype), (HOST_MAC, ethernet::ETHERTYPE_IPV4));
+        let ip = Ipv4Header::parse(&sent[ethernet::HEADER_LEN..]).unwrap();
+        assert_eq!(ip.dst, Ipv4Addr([8, 8, 8, 8]));
+    }
+
+    fn ipv6_frame(iface: &Interface<Loopback>, dst: Ipv6Addr, hop_limit: u8, message: &[u8]) -> Vec<u8> {
+        let mut frame = alloc::vec![0; ethernet::HEADER_LEN + ipv6::HEADER_LEN];
+        EthernetHeader { dst: iface.mac(), src: HOST_MAC, ethertype: ethernet::ETHERTYPE_IPV6 }.emit(&mut frame);
+        Ipv6Header {
+            payload_len: message.len(),
+            next_header: ipv6::NEXT_ICMPV6,
+            hop_limit,
+            src: Ipv6Addr::link_local(HOST_MAC),
+            dst,
+        }
+        .emit(&mut frame[ethernet::HEADER_LEN..]);
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    #[test_case]
+    fn test_ipv6_neighbor_discovery_and_echo() {
+        let mut iface = interface();
+        let host = Ipv6Addr::link_local(HOST_MAC);
+        let us = iface.ipv6_addr();
+
+        let solicit = icmpv6::solicit(host, us.solicited_node(), us, HOST_MAC);
+        let frame = ipv6_frame(&iface, us.solicited_node(), 255, &solicit);
+        iface.device_mut().rx.push_back(frame);
+        iface.poll();
+        let sent = iface.device_mut().tx.pop().unwrap();
+        assert_eq!(EthernetHeader::parse(&sent).unwrap().dst, HOST_MAC);
+        let advert = &sent[ethernet::HEADER_LEN + ipv6::HEADER_LEN..];
+        assert!(icmpv6::verify(us, host, advert));
+        assert_eq!(icmpv6::parse_ndp(advert), Some(Ndp::Advert { target: us, target_mac: Some(iface.mac()) }));
+
+        // The solicitation taught us where the host is, so the reply needn't wait
+        let mut request = alloc::vec![icmpv6::TYPE_ECHO_REQUEST, 0, 0, 0, 0, 1, 0, 1, b'h', b'i'];
+        let sum = ipv4::fold(ipv4::sum_words(&request, ipv6::pseudo_header_sum(host, us, ipv6::NEXT_ICMPV6, 10)));
+        request[2..4].copy_from_slice(&sum.to_be_bytes());
+        let frame = ipv6_frame(&iface, us, 64, &request);
+        iface.device_mut().rx.push_back(frame);
+        iface.poll();
+        let sent = iface.device_mut().tx.pop().unwrap();
+        let ip = Ipv6Header::parse(&sent[ethernet::HEADER_LEN..]).unwrap();
+        assert_eq!((ip.src, ip.dst), (us, host));
+        let reply = &sent[ethernet::HEADER_LEN + ipv6::HEADER_LEN..];
+        assert!(icmpv6::verify(us, host, reply));
+        assert_eq!((reply[0], &reply[8..]), (icmpv6::TYPE_ECHO_REPLY, &b"hi"[..]));
+    }
+
+    fn arp_request(target_addr: Ipv4Addr) -> Vec<u8> {
+        let mut frame = alloc::vec![0; ethernet::HEADER_LEN + arp::PACKET_LEN];
+        EthernetHeader { dst: ethernet::BROADCAST, src: HOST_MAC, ethertype: ethernet::ETHERTYPE_ARP }.emit(&mut frame);
+        ArpPacket {
+            operation: arp::OP_REQUEST,
+            sender_mac: HOST_MAC,
+            sender_addr: HOST,
+            target_mac: [0; 6],
+            target_addr,
+        }
+        .emit(&mut frame[ethernet::HEADER_LEN..]);
+        frame
+    }
+
+    #[test_case]
+    fn test_namespaces_answer_only_if_they_may_send() {
+        let mut iface = interface();
+        iface.set_network_label(unsafe { Label::create(pages(KOBJ_NPAGES), "T,T") });
+        let public = iface.add_namespace_on_subnet(container(None, "T,T", 0), Ipv4Addr([192, 168, 14, 5]));
+        let secret = iface.add_namespace_on_subnet(container(None, "gongqi,T", 0), Ipv4Addr([192, 168, 14, 6]));
+        let host = Ipv6Addr::link_local(HOST_MAC);
+
+        let frame = arp_request(Ipv4Addr([192, 168, 14, 6]));
+        iface.device_mut().rx.push_back(frame);
+        iface.poll();
+        assert!(iface.device_mut().tx.is_empty());
+        let frame = arp_request(Ipv4Addr([192, 168, 14, 5]));
+        iface.device_mut().rx.push_back(frame);
+        iface.poll();
+        let sent = iface.device_mut().tx.pop().unwrap();
+        let arp = ArpPacket::parse(&sent[ethernet::HEADER_LEN..]).unwrap();
+        assert_eq!((arp.operation, arp.sender_addr), (arp::OP_REPLY, Ipv4Addr([192, 168, 14, 5])));
+
+        // Every namespace has its own link-local address, under the same rule
+        let public_ipv6 = iface.namespaces[public.0].ipv6;
+        let secret_ipv6 = iface.namespaces[secret.0].ipv6;
+        assert!(public_ipv6 != iface.ipv6_addr() && public_ipv6 != secret_ipv6);
+        let solicit = icmpv6::solicit(host, secret_ipv6.solicited_node(), secret_ipv6, HOST_MAC);
+        let frame = ipv6_frame(&iface, secret_ipv6.solicited_node(), 255, &solicit);
+        iface.device_mut().rx.push_back(frame);
+        iface.poll();
+        assert!(iface.device_mut().tx.is_empty());
+        assert_eq!(iface.send_ipv6(secret, Ipv6Addr::ALL_NODES, ipv6::NEXT_ICMPV6, 255, b"x"), Err(Error::Denied));
+
+        let solicit = icmpv6::solicit(host, public_ipv6.solicited_node(), public_ipv6, HOST_MAC);
+        let frame = ipv6_frame(&iface, public_ipv6.solicited_node(), 255, &solicit);
+        iface.device_mut().rx.push_back(frame);
+        iface.poll();
+        let sent = iface.device_mut().tx.pop().unwrap();
+        let ip = Ipv6Header::parse(&sent[ethernet::HEADER_LEN..]).unwrap();
+        assert_eq!((ip.src, ip.dst), (public_ipv6, host));
+    }
+
+    #[test_case]
+    fn test_unbound_port_is_unreachable() {
+        let mut iface = interface();
+        iface.arp.insert(HOST, HOST_MAC, 0);
+        let frame = udp_frame(&iface, 1000, 45, b"x");
+        iface.device_mut().rx.push_back(frame);
+        iface.poll();
+
+        let sent = iface.device_mut().tx.pop().unwrap();
+        let ip = Ipv4Header::parse(&sent[ethernet::HEADER_LEN..]).unwrap();
+        assert_eq!(ip.protocol, ipv4::PROTO_ICMP);
+        let icmp = &sent[ethernet::HEADER_LEN + ipv4::HEADER_LEN..];
+        assert_eq!((icmp[0], icmp[1]), (icmp::TYPE_DEST_UNREACHABLE, icmp::CODE_PORT_UNREACHABLE));
+    }
+}
@@ -0,0 +1,196 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::ethernet::ETHERTYPE_IPV4;
+use super::ipv4::Ipv4Addr;
+
+pub const PACKET_LEN: usize = 28;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY: u16 = 2;
+
+const HW_ETHERNET: u16 = 1;
+
+const CACHE_ENTRIES: usize = 16;
+const ENTRY_TIMEOUT: u64 = 600; // ticks
+const MAX_PENDING: usize = 16;
+const PENDING_TIMEOUT: u64 = 30;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_addr: Ipv4Addr,
+    pub target_mac: [u8; 6],
+    pub target_addr: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Reads an Ethernet/IPv4 ARP packet
+    pub fn parse(packet: &[u8]) -> Option<ArpPacket> {
+        if packet.len() < PACKET_LEN
+            || u16::from_be_bytes([packet[0], packet[1]]) != HW_ETHERNET
+            || u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4
+            || (packet[4], packet[5]) != (6, 4)
+        {
+            return None;
+        }
+        let mut arp = ArpPacket {
+            operation: u16::from_be_bytes([packet[6], packet[7]]),
+            sender_mac: [0; 6],
+            sender_addr: Ipv4Addr::UNSPECIFIED,
+            target_mac: [0; 6],
+            target_addr: Ipv4Addr::UNSPECIFIED,
+        };
+        arp.sender_mac.copy_from_slice(&packet[8..14]);
+        arp.sender_addr.0.copy_from_slice(&packet[14..18]);
+        arp.target_mac.copy_from_slice(&packet[18..24]);
+        arp.target_addr.0.copy_from_slice(&packet[24..28]);
+        Some(arp)
+    }
+
+    pub fn emit(&self, packet: &mut [u8]) {
+        packet[0..2].copy_from_slice(&HW_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac);
+        packet[14..18].copy_from_slice(&self.sender_addr.0);
+        packet[18..24].copy_from_slice(&self.target_mac);
+        packet[24..28].copy_from_slice(&self.target_addr.0);
+    }
+}
+
+struct Entry<A> {
+    addr: A,
+    mac: [u8; 6],
+    expires: u64,
+}
+
+// A frame held back until its next hop's MAC is known
+struct Pending<A> {
+    next_hop: A,
+    frame: Vec<u8>,
+    queued: u64,
+}
+
+/// Recently resolved neighbours, and the frames waiting on the others.
+/// IPv6 keeps its neighbours in one too.
+pub struct ArpCache<A = Ipv4Addr> {
+    entries: Vec<Entry<A>>,
+    pending: VecDeque<Pending<A>>,
+}
+
+impl<A: Copy + PartialEq> ArpCache<A> {
+    pub fn new() -> ArpCache<A> {
+        ArpCache {
+            entries: Vec::with_capacity(CACHE_ENTRIES),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn lookup(&self, addr: A, now: u64) -> Option<[u8; 6]> {
+        self.entries
+            .iter()
+            .find(|e| e.addr == addr && e.expires > now)
+            .map(|e| e.mac)
+    }
+
+    /// Whether `addr` is already known, so its entry should be refreshed
+    pub fn contains(&self, addr: A) -> bool {
+        self.entries.iter().any(|e| e.addr == addr)
+    }
+
+    pub fn insert(&mut self, addr: A, mac: [u8; 6], now: u64) {
+        let expires = now + ENTRY_TIMEOUT;
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.addr == addr) {
+            entry.mac = mac;
+            entry.expires = expires;
+            return;
+        }
+        if self.entries.len() == CACHE_ENTRIES {
+            // Evict whichever goes stale first
+            if let Some(i) = (0..self.entries.len()).min_by_key(|&i| self.entries[i].expires) {
+                self.entries.swap_remove(i);
+            }
+        }
+        self.entries.push(Entry { addr, mac, expires });
+    }
+
+    /// Holds `frame` back until `next_hop` resolves. Returns whether a
+    /// request still has to be sent for it.
+    pub fn queue(&mut self, next_hop: A, frame: Vec<u8>, now: u64) -> bool {
+        let requested = self
+            .pending
+            .iter()
+            .any(|p| p.next_hop == next_hop && p.queued + PENDING_TIMEOUT / 3 > now);
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(Pending { next_hop, frame, queued: now });
+        !requested
+    }
+
+    /// Takes the frames that were waiting on `addr`
+    pub fn take_pending(&mut self, addr: A) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].next_hop == addr {
+                frames.extend(self.pending.remove(i).map(|p| p.frame));
+            } else {
+                i += 1;
+            }
+        }
+        frames
+    }
+
+    /// Forgets stale entries and drops frames nobody answered for
+    pub fn expire(&mut self, now: u64) {
+        self.entries.retain(|e| e.expires > now);
+        self.pending.retain(|p| p.queued + PENDING_TIMEOUT > now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_arp_cache() {
+        let mut cache = ArpCache::new();
+        let gateway = Ipv4Addr([192, 168, 14, 1]);
+        let mac = [2, 0, 0, 0, 0, 1];
+
+        assert!(cache.queue(gateway, alloc::vec![1], 0));
+        // Only the first frame asks
+        assert!(!cache.queue(gateway, alloc::vec![2], 1));
+        assert_eq!(cache.lookup(gateway, 1), None);
+
+        cache.insert(gateway, mac, 2);
+        assert_eq!(cache.lookup(gateway, 2), Some(mac));
+        assert_eq!(cache.take_pending(gateway), alloc::vec![alloc::vec![1], alloc::vec![2]]);
+        assert!(cache.take_pending(gateway).is_empty());
+
+        cache.expire(2 + ENTRY_TIMEOUT);
+        assert_eq!(cache.lookup(gateway, 2 + ENTRY_TIMEOUT), None);
+    }
+
+    #[test_case]
+    fn test_arp_packet_roundtrip() {
+        let arp = ArpPacket {
+            operation: OP_REQUEST,
+            sender_mac: [2, 0, 0, 0, 0, 1],
+            sender_addr: Ipv4Addr([192, 168, 14, 1]),
+            target_mac: [0; 6],
+            target_addr: Ipv4Addr([192, 168, 14, 4]),
+        };
+        let mut packet = [0u8; PACKET_LEN];
+        arp.emit(&mut packet);
+        let parsed = ArpPacket::parse(&packet).unwrap();
+        assert_eq!(parsed.operation, OP_REQUEST);
+        assert_eq!(parsed.sender_addr, arp.sender_addr);
+        assert_eq!(parsed.target_addr, arp.target_addr);
+    }
+}
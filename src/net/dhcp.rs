@@ -0,0 +1,395 @@
+use core::time::Duration;
+
+use alloc::vec::Vec;
+
+use super::ipv4::{Ipv4Addr, Ipv4Config};
+use super::{Device, Interface, NamespaceId, UdpHandle};
+use crate::timer;
+
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const FLAG_BROADCAST: u16 = 1 << 15;
+const MAGIC: [u8; 4] = [99, 130, 83, 99];
+// Everything up to and including the magic cookie
+const FIXED_LEN: usize = 240;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETERS: u8 = 55;
+const OPT_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const RETRY_SECS: u64 = 1;
+const MAX_RETRIES: u32 = 3;
+
+/// The parts of a server's reply the client cares about
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reply {
+    pub xid: u32,
+    pub message_type: u8,
+    pub yiaddr: Ipv4Addr,
+    pub netmask: Option<Ipv4Addr>,
+    pub router: Option<Ipv4Addr>,
+    pub server: Option<Ipv4Addr>,
+    pub lease_secs: Option<u32>,
+}
+
+fn addr_option(value: &[u8]) -> Option<Ipv4Addr> {
+    let mut addr = [0; 4];
+    addr.copy_from_slice(value.get(..4)?);
+    Some(Ipv4Addr(addr))
+}
+
+impl Reply {
+    pub fn parse(message: &[u8]) -> Option<Reply> {
+        if message.len() < FIXED_LEN || message[0] != OP_REPLY || message[236..240] != MAGIC {
+            return None;
+        }
+        let mut reply = Reply {
+            xid: u32::from_be_bytes([message[4], message[5], message[6], message[7]]),
+            yiaddr: addr_option(&message[16..20])?,
+            ..Reply::default()
+        };
+
+        let mut options = &message[FIXED_LEN..];
+        while let Some(&code) = options.first() {
+            match code {
+                OPT_END => break,
+                OPT_PAD => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    let value = options.get(2..2 + len)?;
+                    match code {
+                        OPT_MESSAGE_TYPE => reply.message_type = *value.first()?,
+                        OPT_SUBNET_MASK => reply.netmask = addr_option(value),
+                        OPT_ROUTER => reply.router = addr_option(value),
+                        OPT_SERVER_ID => reply.server = addr_option(value),
+                        OPT_LEASE_TIME => {
+                            reply.lease_secs = value.get(..4).map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+                        }
+                        _ => {}
+                    }
+                    options = &options[2 + len..];
+                }
+            }
+        }
+        Some(reply)
+    }
+}
+
+/// Builds a DISCOVER, a REQUEST for `requested` from `server`, or, given
+/// `ciaddr`, a REQUEST renewing the lease on it
+fn build(
+    message_type: u8,
+    xid: u32,
+    mac: [u8; 6],
+    requested: Option<(Ipv4Addr, Option<Ipv4Addr>)>,
+    ciaddr: Option<Ipv4Addr>,
+) -> Vec<u8> {
+    let mut message = alloc::vec![0; FIXED_LEN];
+    message[0] = OP_REQUEST;
+    message[1] = 1; // Ethernet
+    message[2] = 6;
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    match ciaddr {
+        Some(ciaddr) => message[12..16].copy_from_slice(&ciaddr.0),
+        // We can't take unicast replies before we have an address
+        None => message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes()),
+    }
+    message[28..34].copy_from_slice(&mac);
+    message[236..240].copy_from_slice(&MAGIC);
+
+    message.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, message_type]);
+    if let Some((addr, server)) = requested {
+        message.extend_from_slice(&[OPT_REQUESTED_IP, 4]);
+        message.extend_from_slice(&addr.0);
+        if let Some(server) = server {
+            message.extend_from_slice(&[OPT_SERVER_ID, 4]);
+            message.extend_from_slice(&server.0);
+        }
+    }
+    message.extend_from_slice(&[OPT_PARAMETERS, 3, OPT_SUBNET_MASK, OPT_ROUTER, OPT_LEASE_TIME]);
+    message.push(OPT_END);
+    message
+}
+
+/// What `/chosen/bootargs` says about the network, in the kernel's
+/// `ip=<client>:<server>:<gateway>:<netmask>:<host>:<device>:<autoconf>` form.
+/// `netns=<addr>,<addr>` lists the addresses handed to container namespaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootConfig {
+    pub fallback: Option<Ipv4Config>,
+    pub dhcp: bool,
+    pub namespaces: Vec<Ipv4Addr>,
+}
+
+impl BootConfig {
+    pub fn parse(bootargs: &[u8]) -> BootConfig {
+        let arg = |key: &[u8]| {
+            bootargs
+                .split(|&c| c == b' ')
+                .find_map(|arg| arg.strip_prefix(key))
+        };
+        let namespaces = arg(b"netns=")
+            .map(|addrs| addrs.split(|&c| c == b',').filter_map(Ipv4Addr::parse).collect())
+            .unwrap_or_default();
+        let ip = match arg(b"ip=") {
+            Some(ip) => ip,
+            None => return BootConfig { fallback: None, dhcp: true, namespaces },
+        };
+        let fields: Vec<&[u8]> = ip.split(|&c| c == b':').collect();
+        let off = |field: &[u8]| matches!(field, b"off" | b"none" | b"static");
+
+        let fallback = Ipv4Addr::parse(fields[0]).map(|addr| Ipv4Config {
+            addr,
+            netmask: fields
+                .get(3)
+                .and_then(|mask| Ipv4Addr::parse(mask))
+                .unwrap_or(Ipv4Addr([255, 255, 255, 0])),
+            gateway: fields.get(2).and_then(|gateway| Ipv4Addr::parse(gateway)),
+        });
+        let dhcp = match fallback {
+            Some(_) => !fields.get(6).map_or(false, |autoconf| off(autoconf)),
+            None => !off(fields[0]),
+        };
+        BootConfig { fallback, dhcp, namespaces }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Done,
+}
+
+/// Gets the interface an address, and renews it halfway through the lease
+pub struct Dhcp {
+    phase: Phase,
+    sock: UdpHandle,
+    xid: u32,
+    // The address offered, or leased, and who from
+    offer: Option<(Ipv4Addr, Option<Ipv4Addr>)>,
+    deadline: u64,
+    // When the lease runs out
+    expires: u64,
+    retries: u32,
+    fallback: Option<Ipv4Config>,
+}
+
+fn ticks(secs: u64) -> u64 {
+    timer::convert_to_ticks(Duration::from_secs(secs))
+}
+
+impl Dhcp {
+    /// Starts asking for an address. `fallback` is used if nobody answers.
+    pub fn start<D: Device>(iface: &mut Interface<D>, fallback: Option<Ipv4Config>) -> Option<Dhcp> {
+        let sock = iface.udp_bind(NamespaceId::DEFAULT, CLIENT_PORT).ok()?;
+        let mac = iface.mac();
+        let mut dhcp = Dhcp {
+            phase: Phase::Selecting,
+            sock,
+            xid: u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]) ^ timer::current_ticks() as u32,
+            offer: None,
+            deadline: 0,
+            expires: 0,
+            retries: 0,
+            fallback,
+        };
+        dhcp.send(iface);
+        Some(dhcp)
+    }
+
+    /// Whether the client is still of any use
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    fn send<D: Device>(&mut self, iface: &mut Interface<D>) {
+        let (message, dst) = match (self.phase, self.offer) {
+            // RFC 2131 4.3.2: renewals go straight to the server that
+            // leased the address, which goes in ciaddr alone
+            (Phase::Renewing, Some((addr, server))) => (
+                build(REQUEST, self.xid, iface.mac(), None, Some(addr)),
+                server.unwrap_or(Ipv4Addr::BROADCAST),
+            ),
+            (Phase::Selecting, _) => (build(DISCOVER, self.xid, iface.mac(), None, None), Ipv4Addr::BROADCAST),
+            _ => (build(REQUEST, self.xid, iface.mac(), self.offer, None), Ipv4Addr::BROADCAST),
+        };
+        let _ = iface.udp_send_to(self.sock, dst, SERVER_PORT, &message);
+        self.deadline = timer::current_ticks() + ticks(RETRY_SECS << self.retries);
+        if self.phase == Phase::Renewing {
+            self.deadline = core::cmp::min(self.deadline, self.expires);
+        }
+    }
+
+    fn restart<D: Device>(&mut self, iface: &mut Interface<D>) {
+        self.phase = Phase::Selecting;
+        self.xid = self.xid.wrapping_add(1);
+        self.offer = None;
+        self.retries = 0;
+        self.send(iface);
+    }
+
+    // Only ever called without an address: before the first lease, or
+    // after one was lost
+    fn give_up<D: Device>(&mut self, iface: &mut Interface<D>) {
+        if let Some(fallback) = self.fallback {
+            iface.set_config(fallback);
+        }
+        iface.udp_close(self.sock);
+        self.phase = Phase::Done;
+    }
+
+    // The lease ran out, or the server took it back. Stop using the address
+    // and start over.
+    fn drop_lease<D: Device>(&mut self, iface: &mut Interface<D>) {
+        crate::debug!("dhcp: lost {:?}", iface.config().addr);
+        iface.set_config(Ipv4Config::UNCONFIGURED);
+        self.restart(iface);
+    }
+
+    fn bind<D: Device>(&mut self, iface: &mut Interface<D>, reply: &Reply, now: u64) {
+        iface.set_config(Ipv4Config {
+            addr: reply.yiaddr,
+            netmask: reply.netmask.unwrap_or(Ipv4Addr([255, 255, 255, 0])),
+            gateway: reply.router,
+        });
+        let lease_secs = reply.lease_secs.unwrap_or(3600) as u64;
+        self.phase = Phase::Bound;
+        // A renewal's ACK may leave the server out
+        self.offer = Some((reply.yiaddr, reply.server.or(self.offer.and_then(|(_, server)| server))));
+        self.retries = 0;
+        self.deadline = now + ticks(lease_secs / 2);
+        self.expires = now + ticks(lease_secs);
+    }
+
+    /// Handles the server's replies and whatever timed out
+    pub fn poll<D: Device>(&mut self, iface: &mut Interface<D>, now: u64) {
+        if self.phase == Phase::Done {
+            return;
+        }
+        while let Ok(Some(datagram)) = iface.udp_pop(self.sock) {
+            let reply = match Reply::parse(&datagram.data) {
+                Some(reply) if reply.xid == self.xid => reply,
+                _ => continue,
+            };
+            match (self.phase, reply.message_type) {
+                (Phase::Selecting, OFFER) => {
+                    self.phase = Phase::Requesting;
+                    self.offer = Some((reply.yiaddr, reply.server));
+                    self.retries = 0;
+                    self.send(iface);
+                }
+                (Phase::Requesting, ACK) | (Phase::Renewing, ACK) => self.bind(iface, &reply, now),
+                (Phase::Requesting, NAK) => self.restart(iface),
+                (Phase::Renewing, NAK) => self.drop_lease(iface),
+                _ => {}
+            }
+        }
+
+        if now < self.deadline {
+            return;
+        }
+        match self.phase {
+            // Time to renew
+            Phase::Bound => {
+                self.phase = Phase::Renewing;
+                self.xid = self.xid.wrapping_add(1);
+                self.send(iface);
+            }
+            Phase::Renewing if now >= self.expires => self.drop_lease(iface),
+            // Keep asking until the lease runs out
+            Phase::Renewing => {
+                self.retries = core::cmp::min(self.retries + 1, MAX_RETRIES);
+                self.send(iface);
+            }
+            _ if self.retries == MAX_RETRIES => self.give_up(iface),
+            _ => {
+                self.retries += 1;
+                self.send(iface);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_bootargs() {
+        let boot = BootConfig::parse(b"console=ttyAMA0 ip=192.168.14.4::192.168.14.1:255.255.255.0::eth0:off");
+        assert_eq!(
+            boot,
+            BootConfig {
+                fallback: Some(Ipv4Config {
+                    addr: Ipv4Addr([192, 168, 14, 4]),
+                    netmask: Ipv4Addr([255, 255, 255, 0]),
+                    gateway: Some(Ipv4Addr([192, 168, 14, 1])),
+                }),
+                dhcp: false,
+                namespaces: Vec::new(),
+            }
+        );
+
+        let boot = BootConfig::parse(b"ip=10.0.2.15");
+        assert!(boot.dhcp);
+        assert_eq!(boot.fallback.unwrap().gateway, None);
+
+        assert!(!BootConfig::parse(b"ip=off").dhcp);
+        assert_eq!(BootConfig::parse(b"").fallback, None);
+
+        let boot = BootConfig::parse(b"netns=192.168.14.5,192.168.14.6 ip=dhcp");
+        assert!(boot.dhcp);
+        assert_eq!(boot.namespaces, alloc::vec![Ipv4Addr([192, 168, 14, 5]), Ipv4Addr([192, 168, 14, 6])]);
+    }
+
+    #[test_case]
+    fn test_reply_parse() {
+        // A server's answer looks like our request with the op flipped
+        let mut message = build(OFFER, 0x1234, [2, 0, 0, 0, 0, 4], None, None);
+        message[0] = OP_REPLY;
+        message[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        message.pop();
+        message.extend_from_slice(&[OPT_ROUTER, 4, 10, 0, 2, 2, OPT_LEASE_TIME, 4, 0, 0, 0x0e, 0x10, OPT_END]);
+
+        let reply = Reply::parse(&message).unwrap();
+        assert_eq!((reply.xid, reply.message_type), (0x1234, OFFER));
+        assert_eq!(reply.yiaddr, Ipv4Addr([10, 0, 2, 15]));
+        assert_eq!(reply.router, Some(Ipv4Addr([10, 0, 2, 2])));
+        assert_eq!(reply.lease_secs, Some(3600));
+        assert_eq!(reply.netmask, None);
+    }
+    #[test_case]
+    fn test_renewal_request() {
+        let ciaddr = Ipv4Addr([10, 0, 2, 15]);
+        let message = build(REQUEST, 7, [2, 0, 0, 0, 0, 4], None, Some(ciaddr));
+        assert_eq!(message[12..16], ciaddr.0);
+        assert_eq!(message[10..12], [0, 0]);
+        // Only the message type and the parameters we want
+        assert_eq!(
+            message[FIXED_LEN..],
+            [OPT_MESSAGE_TYPE, 1, REQUEST, OPT_PARAMETERS, 3, OPT_SUBNET_MASK, OPT_ROUTER, OPT_LEASE_TIME, OPT_END]
+        );
+
+        let message = build(REQUEST, 7, [2, 0, 0, 0, 0, 4], Some((ciaddr, None)), None);
+        assert_eq!(message[12..16], [0; 4]);
+        assert_eq!(message[10..12], FLAG_BROADCAST.to_be_bytes());
+    }
+}
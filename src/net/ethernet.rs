@@ -0,0 +1,38 @@
+pub const HEADER_LEN: usize = 14;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+pub const BROADCAST: [u8; 6] = [0xff; 6];
+
+/// Whether `mac` is one of the addresses IPv6 multicast maps to
+pub fn is_ipv6_multicast(mac: [u8; 6]) -> bool {
+    mac[..2] == [0x33, 0x33]
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EthernetHeader {
+    pub dst: [u8; 6],
+    pub src: [u8; 6],
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    pub fn parse(frame: &[u8]) -> Option<EthernetHeader> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+        let mut header = EthernetHeader { dst: [0; 6], src: [0; 6], ethertype: 0 };
+        header.dst.copy_from_slice(&frame[0..6]);
+        header.src.copy_from_slice(&frame[6..12]);
+        header.ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        Some(header)
+    }
+
+    pub fn emit(&self, frame: &mut [u8]) {
+        frame[0..6].copy_from_slice(&self.dst);
+        frame[6..12].copy_from_slice(&self.src);
+        frame[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
@@ -0,0 +1,36 @@
+use alloc::vec::Vec;
+
+use super::ipv4::checksum;
+
+pub const HEADER_LEN: usize = 8;
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DEST_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+
+/// The reply to `message` if it is a well-formed echo request
+pub fn echo_reply(message: &[u8]) -> Option<Vec<u8>> {
+    if message.len() < HEADER_LEN || message[0] != TYPE_ECHO_REQUEST || checksum(message) != 0 {
+        return None;
+    }
+    let mut reply = message.to_vec();
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let sum = checksum(&reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    Some(reply)
+}
+
+/// An error quoting the offending datagram's header and the start of its
+/// payload
+pub fn unreachable(code: u8, datagram: &[u8], header_len: usize) -> Vec<u8> {
+    let quoted = core::cmp::min(datagram.len(), header_len + 8);
+    let mut message = Vec::with_capacity(HEADER_LEN + quoted);
+    message.extend_from_slice(&[TYPE_DEST_UNREACHABLE, code, 0, 0, 0, 0, 0, 0]);
+    message.extend_from_slice(&datagram[..quoted]);
+    let sum = checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
@@ -0,0 +1,120 @@
+use alloc::vec::Vec;
+
+use super::ipv4::{fold, sum_words};
+use super::ipv6::{pseudo_header_sum, Ipv6Addr, NEXT_ICMPV6};
+
+pub const HEADER_LEN: usize = 4;
+
+pub const TYPE_ECHO_REQUEST: u8 = 128;
+pub const TYPE_ECHO_REPLY: u8 = 129;
+pub const TYPE_NEIGHBOR_SOLICIT: u8 = 135;
+pub const TYPE_NEIGHBOR_ADVERT: u8 = 136;
+
+// Neighbour Discovery messages: header, 4 reserved or flag bytes, target
+const NDP_LEN: usize = HEADER_LEN + 4 + 16;
+const OPT_SOURCE_MAC: u8 = 1;
+const OPT_TARGET_MAC: u8 = 2;
+
+const FLAG_SOLICITED: u32 = 1 << 30;
+const FLAG_OVERRIDE: u32 = 1 << 29;
+
+/// Whether `message`'s checksum is right for a packet from `src` to `dst`
+pub fn verify(src: Ipv6Addr, dst: Ipv6Addr, message: &[u8]) -> bool {
+    let sum = pseudo_header_sum(src, dst, NEXT_ICMPV6, message.len());
+    message.len() >= HEADER_LEN && fold(sum_words(message, sum)) == 0
+}
+
+fn finish(src: Ipv6Addr, dst: Ipv6Addr, mut message: Vec<u8>) -> Vec<u8> {
+    message[2..4].copy_from_slice(&[0, 0]);
+    let sum = fold(sum_words(&message, pseudo_header_sum(src, dst, NEXT_ICMPV6, message.len())));
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
+
+/// The reply, sent from `src` to `dst`, if `message` is an echo request
+pub fn echo_reply(src: Ipv6Addr, dst: Ipv6Addr, message: &[u8]) -> Option<Vec<u8>> {
+    if message[0] != TYPE_ECHO_REQUEST || message[1] != 0 {
+        return None;
+    }
+    let mut reply = message.to_vec();
+    reply[0] = TYPE_ECHO_REPLY;
+    Some(finish(src, dst, reply))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ndp {
+    Solicit { target: Ipv6Addr, source_mac: Option<[u8; 6]> },
+    Advert { target: Ipv6Addr, target_mac: Option<[u8; 6]> },
+}
+
+/// Reads a neighbour solicitation or advertisement
+pub fn parse_ndp(message: &[u8]) -> Option<Ndp> {
+    if message.len() < NDP_LEN || message[1] != 0 {
+        return None;
+    }
+    let mut target = Ipv6Addr::UNSPECIFIED;
+    target.0.copy_from_slice(&message[8..24]);
+
+    // Only the link-layer address options matter to us
+    let mut mac = None;
+    let mut options = &message[NDP_LEN..];
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        if options[0] == OPT_SOURCE_MAC || options[0] == OPT_TARGET_MAC {
+            let mut addr = [0; 6];
+            addr.copy_from_slice(&options[2..8]);
+            mac = Some(addr);
+        }
+        options = &options[len..];
+    }
+
+    match message[0] {
+        TYPE_NEIGHBOR_SOLICIT => Some(Ndp::Solicit { target, source_mac: mac }),
+        TYPE_NEIGHBOR_ADVERT => Some(Ndp::Advert { target, target_mac: mac }),
+        _ => None,
+    }
+}
+
+fn ndp(message_type: u8, flags: u32, target: Ipv6Addr, option: u8, mac: [u8; 6]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(NDP_LEN + 8);
+    message.extend_from_slice(&[message_type, 0, 0, 0]);
+    message.extend_from_slice(&flags.to_be_bytes());
+    message.extend_from_slice(&target.0);
+    message.extend_from_slice(&[option, 1]);
+    message.extend_from_slice(&mac);
+    message
+}
+
+/// Asks who has `target`, telling them our `mac`
+pub fn solicit(src: Ipv6Addr, dst: Ipv6Addr, target: Ipv6Addr, mac: [u8; 6]) -> Vec<u8> {
+    finish(src, dst, ndp(TYPE_NEIGHBOR_SOLICIT, 0, target, OPT_SOURCE_MAC, mac))
+}
+
+/// Says `target` is at `mac`
+pub fn advert(src: Ipv6Addr, dst: Ipv6Addr, target: Ipv6Addr, mac: [u8; 6], solicited: bool) -> Vec<u8> {
+    let flags = FLAG_OVERRIDE | if solicited { FLAG_SOLICITED } else { 0 };
+    finish(src, dst, ndp(TYPE_NEIGHBOR_ADVERT, flags, target, OPT_TARGET_MAC, mac))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_ndp_roundtrip() {
+        let mac = [2, 0, 0, 0, 0, 1];
+        let src = Ipv6Addr::link_local(mac);
+        let target = Ipv6Addr::link_local([2, 0, 0, 0, 0, 4]);
+        let message = solicit(src, target.solicited_node(), target, mac);
+        assert!(verify(src, target.solicited_node(), &message));
+        assert!(!verify(src, target, &message));
+        assert_eq!(parse_ndp(&message), Some(Ndp::Solicit { target, source_mac: Some(mac) }));
+
+        let message = advert(target, src, target, [2, 0, 0, 0, 0, 4], true);
+        assert!(verify(target, src, &message));
+        assert_eq!(parse_ndp(&message), Some(Ndp::Advert { target, target_mac: Some([2, 0, 0, 0, 0, 4]) }));
+    }
+}
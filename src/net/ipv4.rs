@@ -0,0 +1,354 @@
+use core::fmt;
+
+use alloc::vec::Vec;
+
+pub const HEADER_LEN: usize = 20;
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+const DEFAULT_TTL: u8 = 64;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const OFFSET_MASK: u16 = 0x1fff;
+
+const MAX_REASSEMBLIES: usize = 4;
+const REASSEMBLY_TIMEOUT: u64 = 300; // ticks
+const MAX_DATAGRAM: usize = 65535 - HEADER_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(addr: u32) -> Ipv4Addr {
+        Ipv4Addr(addr.to_be_bytes())
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Ipv4Addr::UNSPECIFIED
+    }
+
+    pub fn is_broadcast(self) -> bool {
+        self == Ipv4Addr::BROADCAST
+    }
+
+    /// Reads a dotted quad such as `192.168.14.4`
+    pub fn parse(text: &[u8]) -> Option<Ipv4Addr> {
+        let mut addr = [0; 4];
+        let mut parts = text.split(|&c| c == b'.');
+        for byte in addr.iter_mut() {
+            let part = core::str::from_utf8(parts.next()?).ok()?;
+            *byte = part.parse().ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Ipv4Addr(addr)),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+/// The interface's address and where to send what isn't on the link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    pub const UNCONFIGURED: Ipv4Config = Ipv4Config {
+        addr: Ipv4Addr::UNSPECIFIED,
+        netmask: Ipv4Addr::UNSPECIFIED,
+        gateway: None,
+    };
+
+    pub fn subnet_broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.addr.to_u32() | !self.netmask.to_u32())
+    }
+
+    pub fn on_link(&self, dst: Ipv4Addr) -> bool {
+        (dst.to_u32() ^ self.addr.to_u32()) & self.netmask.to_u32() == 0
+    }
+
+    /// The address on the link a datagram for `dst` is handed to
+    pub fn next_hop(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        if dst.is_broadcast() || self.on_link(dst) {
+            Some(dst)
+        } else {
+            self.gateway
+        }
+    }
+
+    /// Whether datagrams sent to `dst` are meant for this interface
+    pub fn accepts(&self, dst: Ipv4Addr) -> bool {
+        dst == self.addr || dst.is_broadcast() || dst == self.subnet_broadcast()
+    }
+}
+
+/// Adds `data` to a running ones' complement sum
+pub fn sum_words(data: &[u8], sum: u32) -> u32 {
+    data.chunks(2).fold(sum, |sum, word| {
+        sum + u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32
+    })
+}
+
+pub fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum_words(data, 0))
+}
+
+/// The sum of the pseudo-header TCP and UDP checksums cover
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let sum = sum_words(&src.0, 0);
+    let sum = sum_words(&dst.0, sum);
+    sum + protocol as u32 + len as u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Header {
+    pub header_len: usize,
+    pub total_len: usize,
+    pub id: u16,
+    pub flags_offset: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+}
+
+impl Ipv4Header {
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload_len: usize, id: u16) -> Ipv4Header {
+        Ipv4Header {
+            header_len: HEADER_LEN,
+            total_len: HEADER_LEN + payload_len,
+            id,
+            flags_offset: FLAG_DONT_FRAGMENT,
+            ttl: DEFAULT_TTL,
+            protocol,
+            src,
+            dst,
+        }
+    }
+
+    /// Reads and checks the header at the front of `packet`
+    pub fn parse(packet: &[u8]) -> Option<Ipv4Header> {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len() {
+            return None;
+        }
+        if checksum(&packet[..header_len]) != 0 {
+            return None;
+        }
+        let mut src = [0; 4];
+        let mut dst = [0; 4];
+        src.copy_from_slice(&packet[12..16]);
+        dst.copy_from_slice(&packet[16..20]);
+        Some(Ipv4Header {
+            header_len,
+            total_len,
+            id: u16::from_be_bytes([packet[4], packet[5]]),
+            flags_offset: u16::from_be_bytes([packet[6], packet[7]]),
+            ttl: packet[8],
+            protocol: packet[9],
+            src: Ipv4Addr(src),
+            dst: Ipv4Addr(dst),
+        })
+    }
+
+    /// Writes the header, without options, and its checksum
+    pub fn emit(&self, packet: &mut [u8]) {
+        packet[0] = 0x45;
+        packet[1] = 0;
+        packet[2..4].copy_from_slice(&(self.total_len as u16).to_be_bytes());
+        packet[4..6].copy_from_slice(&self.id.to_be_bytes());
+        packet[6..8].copy_from_slice(&self.flags_offset.to_be_bytes());
+        packet[8] = self.ttl;
+        packet[9] = self.protocol;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        packet[12..16].copy_from_slice(&self.src.0);
+        packet[16..20].copy_from_slice(&self.dst.0);
+        let sum = checksum(&packet[..HEADER_LEN]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags_offset & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// Where this fragment's payload goes in the datagram, in bytes
+    pub fn fragment_offset(&self) -> usize {
+        (self.flags_offset & OFFSET_MASK) as usize * 8
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+}
+
+struct Reassembly {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+    data: Vec<u8>,
+    // Byte ranges received so far, sorted and merged
+    filled: Vec<(usize, usize)>,
+    total: Option<usize>,
+    expires: u64,
+}
+
+/// Datagrams being put back together from their fragments
+pub struct Reassembler {
+    pending: Vec<Reassembly>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler { pending: Vec::new() }
+    }
+
+    /// Adds one fragment. Returns the whole payload once every piece is in.
+    pub fn add(&mut self, header: &Ipv4Header, payload: &[u8], now: u64) -> Option<Vec<u8>> {
+        self.pending.retain(|r| r.expires > now);
+
+        let start = header.fragment_offset();
+        let end = start + payload.len();
+        if end > MAX_DATAGRAM {
+            return None;
+        }
+
+        let i = match self.pending.iter().position(|r| {
+            (r.src, r.dst, r.protocol, r.id) == (header.src, header.dst, header.protocol, header.id)
+        }) {
+            Some(i) => i,
+            None => {
+                if self.pending.len() == MAX_REASSEMBLIES {
+                    // Give up on whichever has waited longest
+                    let oldest = (0..self.pending.len()).min_by_key(|&i| self.pending[i].expires)?;
+                    self.pending.swap_remove(oldest);
+                }
+                self.pending.push(Reassembly {
+                    src: header.src,
+                    dst: header.dst,
+                    protocol: header.protocol,
+                    id: header.id,
+                    data: Vec::new(),
+                    filled: Vec::new(),
+                    total: None,
+                    expires: now + REASSEMBLY_TIMEOUT,
+                });
+                self.pending.len() - 1
+            }
+        };
+
+        let r = &mut self.pending[i];
+        if !header.more_fragments() {
+            r.total = Some(end);
+        }
+        if r.data.len() < end {
+            r.data.resize(end, 0);
+        }
+        r.data[start..end].copy_from_slice(payload);
+
+        r.filled.push((start, end));
+        r.filled.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(r.filled.len());
+        for &(s, e) in r.filled.iter() {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = core::cmp::max(last.1, e),
+                _ => merged.push((s, e)),
+            }
+        }
+        r.filled = merged;
+
+        match r.total {
+            Some(total) if r.filled[..] == [(0, total)] => {
+                let mut r = self.pending.swap_remove(i);
+                r.data.truncate(total);
+                Some(r.data)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(offset: usize, more: bool) -> Ipv4Header {
+        let mut header = Ipv4Header::new(Ipv4Addr([10, 0, 0, 1]), Ipv4Addr([10, 0, 0, 2]), PROTO_UDP, 0, 7);
+        header.flags_offset = (offset / 8) as u16 | if more { FLAG_MORE_FRAGMENTS } else { 0 };
+        header
+    }
+
+    #[test_case]
+    fn test_reassembly_out_of_order() {
+        let mut reassembler = Reassembler::new();
+        let data: Vec<u8> = (0..40).collect();
+
+        assert_eq!(reassembler.add(&fragment(32, false), &data[32..], 0), None);
+        assert_eq!(reassembler.add(&fragment(0, true), &data[..16], 0), None);
+        // A duplicate changes nothing
+        assert_eq!(reassembler.add(&fragment(0, true), &data[..16], 0), None);
+        assert_eq!(reassembler.add(&fragment(16, true), &data[16..32], 0), Some(data));
+    }
+
+    #[test_case]
+    fn test_reassembly_timeout() {
+        let mut reassembler = Reassembler::new();
+        let data = [1u8; 16];
+        assert_eq!(reassembler.add(&fragment(0, true), &data, 0), None);
+        assert_eq!(reassembler.add(&fragment(16, false), &data, REASSEMBLY_TIMEOUT), None);
+    }
+
+    #[test_case]
+    fn test_header_roundtrip() {
+        let mut packet = [0u8; HEADER_LEN];
+        let header = Ipv4Header::new(Ipv4Addr([192, 168, 14, 4]), Ipv4Addr([192, 168, 14, 1]), PROTO_ICMP, 0, 1);
+        header.emit(&mut packet);
+        let parsed = Ipv4Header::parse(&packet).unwrap();
+        assert_eq!((parsed.src, parsed.dst, parsed.protocol), (header.src, header.dst, PROTO_ICMP));
+        assert!(!parsed.is_fragment());
+
+        packet[8] ^= 1;
+        assert!(Ipv4Header::parse(&packet).is_none());
+    }
+
+    #[test_case]
+    fn test_routing() {
+        let config = Ipv4Config {
+            addr: Ipv4Addr([192, 168, 14, 4]),
+            netmask: Ipv4Addr([255, 255, 255, 0]),
+            gateway: Some(Ipv4Addr([192, 168, 14, 1])),
+        };
+        assert_eq!(config.next_hop(Ipv4Addr([192, 168, 14, 9])), Some(Ipv4Addr([192, 168, 14, 9])));
+        assert_eq!(config.next_hop(Ipv4Addr([8, 8, 8, 8])), Some(Ipv4Addr([192, 168, 14, 1])));
+        assert!(config.accepts(Ipv4Addr([192, 168, 14, 255])));
+        assert!(!config.accepts(Ipv4Addr([192, 168, 14, 5])));
+    }
+}
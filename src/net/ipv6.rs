@@ -0,0 +1,163 @@
+use core::fmt;
+
+use super::ipv4::sum_words;
+
+pub const HEADER_LEN: usize = 40;
+
+pub const NEXT_ICMPV6: u8 = 58;
+
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ipv6Addr(pub [u8; 16]);
+
+impl Ipv6Addr {
+    pub const UNSPECIFIED: Ipv6Addr = Ipv6Addr([0; 16]);
+    pub const ALL_NODES: Ipv6Addr = Ipv6Addr([0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    /// The fe80::/64 address with the modified EUI-64 built from `mac`
+    pub fn link_local(mac: [u8; 6]) -> Ipv6Addr {
+        Ipv6Addr([
+            0xfe, 0x80, 0, 0, 0, 0, 0, 0,
+            mac[0] ^ 2, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5],
+        ])
+    }
+
+    /// The multicast group neighbour solicitations for `self` are sent to
+    pub fn solicited_node(self) -> Ipv6Addr {
+        let a = self.0;
+        Ipv6Addr([0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, a[13], a[14], a[15]])
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Ipv6Addr::UNSPECIFIED
+    }
+
+    pub fn is_multicast(self) -> bool {
+        self.0[0] == 0xff
+    }
+
+    /// The Ethernet address a multicast group is sent to
+    pub fn multicast_mac(self) -> [u8; 6] {
+        [0x33, 0x33, self.0[12], self.0[13], self.0[14], self.0[15]]
+    }
+
+    fn group(self, i: usize) -> u16 {
+        u16::from_be_bytes([self.0[2 * i], self.0[2 * i + 1]])
+    }
+}
+
+impl fmt::Display for Ipv6Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The longest run of zero groups is written as "::"
+        let (mut run, mut best) = ((0, 0), (0, 0));
+        for i in 0..8 {
+            if self.group(i) != 0 {
+                continue;
+            }
+            run = if run.1 == i { (run.0, i + 1) } else { (i, i + 1) };
+            if run.1 - run.0 > best.1 - best.0 {
+                best = run;
+            }
+        }
+        if best.1 - best.0 < 2 {
+            best = (8, 8);
+        }
+
+        for i in 0..8 {
+            if i == best.0 {
+                write!(f, "::")?;
+            }
+            if i >= best.0 && i < best.1 {
+                continue;
+            }
+            if i != 0 && i != best.1 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:x}", self.group(i))?;
+        }
+        Ok(())
+    }
+}
+
+/// The sum of the pseudo-header ICMPv6 and upper-layer checksums cover
+pub fn pseudo_header_sum(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, len: usize) -> u32 {
+    let sum = sum_words(&src.0, 0);
+    let sum = sum_words(&dst.0, sum);
+    sum + (len >> 16) as u32 + (len & 0xffff) as u32 + next_header as u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv6Header {
+    pub payload_len: usize,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+}
+
+impl Ipv6Header {
+    /// Reads the fixed header at the front of `packet`. Extension headers
+    /// are left to the caller, who will see them as `next_header`.
+    pub fn parse(packet: &[u8]) -> Option<Ipv6Header> {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 6 {
+            return None;
+        }
+        let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+        if HEADER_LEN + payload_len > packet.len() {
+            return None;
+        }
+        let mut header = Ipv6Header {
+            payload_len,
+            next_header: packet[6],
+            hop_limit: packet[7],
+            src: Ipv6Addr::UNSPECIFIED,
+            dst: Ipv6Addr::UNSPECIFIED,
+        };
+        header.src.0.copy_from_slice(&packet[8..24]);
+        header.dst.0.copy_from_slice(&packet[24..40]);
+        Some(header)
+    }
+
+    pub fn emit(&self, packet: &mut [u8]) {
+        // Traffic class and flow label are left at 0
+        packet[0..4].copy_from_slice(&[0x60, 0, 0, 0]);
+        packet[4..6].copy_from_slice(&(self.payload_len as u16).to_be_bytes());
+        packet[6] = self.next_header;
+        packet[7] = self.hop_limit;
+        packet[8..24].copy_from_slice(&self.src.0);
+        packet[24..40].copy_from_slice(&self.dst.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn test_link_local() {
+        let addr = Ipv6Addr::link_local([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        assert_eq!(format!("{}", addr), "fe80::5054:ff:fe12:3456");
+        assert_eq!(format!("{}", addr.solicited_node()), "ff02::1:ff12:3456");
+        assert_eq!(addr.solicited_node().multicast_mac(), [0x33, 0x33, 0xff, 0x12, 0x34, 0x56]);
+        assert_eq!(format!("{}", Ipv6Addr::UNSPECIFIED), "::");
+    }
+
+    #[test_case]
+    fn test_ipv6_header_roundtrip() {
+        let header = Ipv6Header {
+            payload_len: 8,
+            next_header: NEXT_ICMPV6,
+            hop_limit: 255,
+            src: Ipv6Addr::link_local([2, 0, 0, 0, 0, 1]),
+            dst: Ipv6Addr::ALL_NODES,
+        };
+        let mut packet = [0; HEADER_LEN + 8];
+        header.emit(&mut packet);
+        let parsed = Ipv6Header::parse(&packet).unwrap();
+        assert_eq!((parsed.payload_len, parsed.next_header, parsed.hop_limit), (8, NEXT_ICMPV6, 255));
+        assert_eq!((parsed.src, parsed.dst), (header.src, header.dst));
+        assert!(Ipv6Header::parse(&packet[..HEADER_LEN + 4]).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+use core::time::Duration;
+
+use alloc::vec::Vec;
+
+pub const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+const MAGIC: u32 = 0xa1b2c3d4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Frames the NIC sent and received, kept as pcap records in a ring. When
+/// it fills up, the oldest records make room.
+pub struct Capture {
+    ring: Vec<u8>,
+    // Where the oldest record starts, and how much the records take up
+    head: usize,
+    used: usize,
+    snaplen: usize,
+    records: usize,
+    dropped: usize,
+    paused: bool,
+}
+
+impl Capture {
+    /// A ring of `size` bytes keeping at most `snaplen` bytes of each frame
+    pub fn new(size: usize, snaplen: usize) -> Capture {
+        Capture {
+            ring: alloc::vec![0; size],
+            head: 0,
+            used: 0,
+            snaplen,
+            records: 0,
+            dropped: 0,
+            paused: false,
+        }
+    }
+
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// How many records were overwritten
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn read(&self, offset: usize, out: &mut [u8]) {
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = self.ring[(self.head + offset + i) % self.ring.len()];
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let size = self.ring.len();
+        for &b in data {
+            self.ring[(self.head + self.used) % size] = b;
+            self.used += 1;
+        }
+    }
+
+    // Forgets the oldest record
+    fn drop_oldest(&mut self) {
+        let mut incl_len = [0; 4];
+        self.read(8, &mut incl_len);
+        let len = RECORD_HEADER_LEN + u32::from_le_bytes(incl_len) as usize;
+        self.head = (self.head + len) % self.ring.len();
+        self.used -= len;
+        self.records -= 1;
+        self.dropped += 1;
+    }
+
+    /// Adds `frame`, seen `at` after boot
+    pub fn record(&mut self, frame: &[u8], at: Duration) {
+        let incl_len = core::cmp::min(frame.len(), self.snaplen);
+        let len = RECORD_HEADER_LEN + incl_len;
+        if self.paused || len > self.ring.len() {
+            return;
+        }
+        while self.ring.len() - self.used < len {
+            self.drop_oldest();
+        }
+
+        let mut header = [0; RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&(at.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&at.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(incl_len as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        self.append(&header);
+        self.append(&frame[..incl_len]);
+        self.records += 1;
+    }
+
+    /// Hands `f` the capture as a pcap file, a piece at a time
+    pub fn write_to<F: FnMut(&[u8])>(&self, mut f: F) {
+        let mut header = [0; FILE_HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        // Zone and accuracy stay 0
+        header[16..20].copy_from_slice(&(self.snaplen as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        f(&header);
+
+        let end = self.head + self.used;
+        if end <= self.ring.len() {
+            f(&self.ring[self.head..end]);
+        } else {
+            f(&self.ring[self.head..]);
+            f(&self.ring[..end - self.ring.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pcap(capture: &Capture) -> Vec<u8> {
+        let mut file = Vec::new();
+        capture.write_to(|piece| file.extend_from_slice(piece));
+        file
+    }
+
+    #[test_case]
+    fn test_capture_records() {
+        let mut capture = Capture::new(256, 4);
+        capture.record(&[1, 2, 3, 4, 5, 6], Duration::from_micros(1_000_002));
+
+        let file = pcap(&capture);
+        assert_eq!(file.len(), FILE_HEADER_LEN + RECORD_HEADER_LEN + 4);
+        assert_eq!(&file[0..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        let record = &file[FILE_HEADER_LEN..];
+        // 1s and 2us, 4 of 6 bytes kept
+        assert_eq!(&record[..16], &[1, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 6, 0, 0, 0]);
+        assert_eq!(&record[16..], &[1, 2, 3, 4]);
+    }
+
+    #[test_case]
+    fn test_capture_wraps() {
+        // Room for two 4-byte frames, not three
+        let mut capture = Capture::new(2 * (RECORD_HEADER_LEN + 4) + 5, 4);
+        for i in 0..3u8 {
+            capture.record(&[i; 4], Duration::from_secs(i as u64));
+        }
+        assert_eq!((capture.records(), capture.dropped()), (2, 1));
+
+        let file = pcap(&capture);
+        let records = &file[FILE_HEADER_LEN..];
+        assert_eq!(records.len(), 2 * (RECORD_HEADER_LEN + 4));
+        assert_eq!((records[0], &records[16..20]), (1, &[1u8; 4][..]));
+        assert_eq!((records[20], &records[36..40]), (2, &[2u8; 4][..]));
+
+        capture.set_paused(true);
+        capture.record(&[3; 4], Duration::from_secs(3));
+        assert_eq!(capture.records(), 2);
+    }
+}
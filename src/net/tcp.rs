@@ -0,0 +1,945 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::ipv4::{self, fold, pseudo_header_sum, sum_words, Ipv4Addr, PROTO_TCP};
+use super::{Error, MTU};
+use crate::random;
+
+pub const HEADER_LEN: usize = 20;
+
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+
+const OPT_END: u8 = 0;
+const OPT_NOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+
+const DEFAULT_MSS: usize = 536;
+const LOCAL_MSS: usize = MTU - ipv4::HEADER_LEN - HEADER_LEN;
+const RECV_BUF: usize = 8192;
+const SEND_BUF: usize = 16384;
+const BACKLOG: usize = 8;
+
+// In timer ticks
+const INITIAL_RTO: u64 = 10;
+const MAX_RTO: u64 = 600;
+const MAX_RETRIES: u32 = 8;
+const TIME_WAIT: u64 = 20;
+
+const EPHEMERAL_START: u16 = 49152;
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub header_len: usize,
+    pub mss: Option<u16>,
+}
+
+impl TcpHeader {
+    /// Reads the header, with its MSS option, and checks the segment
+    /// against its checksum
+    pub fn parse(segment: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> Option<TcpHeader> {
+        if segment.len() < HEADER_LEN {
+            return None;
+        }
+        let header_len = (segment[12] >> 4) as usize * 4;
+        if header_len < HEADER_LEN || header_len > segment.len() {
+            return None;
+        }
+        if fold(sum_words(segment, pseudo_header_sum(src, dst, PROTO_TCP, segment.len()))) != 0 {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &segment[HEADER_LEN..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPT_END => break,
+                OPT_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == OPT_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(TcpHeader {
+            src_port: u16::from_be_bytes([segment[0], segment[1]]),
+            dst_port: u16::from_be_bytes([segment[2], segment[3]]),
+            seq: u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]),
+            ack: u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]),
+            flags: segment[13],
+            window: u16::from_be_bytes([segment[14], segment[15]]),
+            header_len,
+            mss,
+        })
+    }
+
+    /// Writes the header in front of the payload already in `segment`, and
+    /// checksums both. `segment` must end where the payload does.
+    pub fn emit(&self, segment: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr) {
+        segment[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        segment[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        segment[8..12].copy_from_slice(&self.ack.to_be_bytes());
+        segment[12] = ((self.header_len / 4) as u8) << 4;
+        segment[13] = self.flags;
+        segment[14..16].copy_from_slice(&self.window.to_be_bytes());
+        segment[16..20].copy_from_slice(&[0; 4]);
+        if let Some(mss) = self.mss {
+            segment[20..22].copy_from_slice(&[OPT_MSS, 4]);
+            segment[22..24].copy_from_slice(&mss.to_be_bytes());
+        }
+        let sum = fold(sum_words(segment, pseudo_header_sum(src, dst, PROTO_TCP, segment.len())));
+        segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+/// A segment ready to go out
+pub struct Outgoing {
+    pub dst: Ipv4Addr,
+    pub segment: Vec<u8>,
+}
+
+fn build(
+    local: (Ipv4Addr, u16),
+    remote: (Ipv4Addr, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload: &[u8],
+) -> Outgoing {
+    let mss = if flags & SYN != 0 { Some(LOCAL_MSS as u16) } else { None };
+    let header_len = HEADER_LEN + if mss.is_some() { 4 } else { 0 };
+    let mut segment = alloc::vec![0; header_len + payload.len()];
+    segment[header_len..].copy_from_slice(payload);
+    let header = TcpHeader {
+        src_port: local.1,
+        dst_port: remote.1,
+        seq,
+        ack,
+        flags,
+        window,
+        header_len,
+        mss,
+    };
+    header.emit(&mut segment, local.0, remote.0);
+    Outgoing { dst: remote.0, segment }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// A listening or connected TCP socket, owned by whoever opened it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpHandle(usize);
+
+struct Socket {
+    state: State,
+    local: Ipv4Addr,
+    local_port: u16,
+    remote: Ipv4Addr,
+    remote_port: u16,
+    // Handshakes a listener finished, and the listener a connection came in on
+    backlog: VecDeque<usize>,
+    parent: Option<usize>,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32,
+    snd_wnd: usize,
+    mss: usize,
+    // Everything from snd_una on
+    send_buf: VecDeque<u8>,
+    // The owner is done sending, and where the FIN went once it did
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_seq: Option<u32>,
+
+    rcv_nxt: u32,
+    recv_buf: VecDeque<u8>,
+    ack_pending: bool,
+
+    rto: u64,
+    deadline: Option<u64>,
+    retries: u32,
+    // Nobody holds a handle; it goes away once closed
+    orphaned: bool,
+}
+
+impl Socket {
+    fn new(state: State, local: Ipv4Addr, local_port: u16, remote: Ipv4Addr, remote_port: u16, iss: u32) -> Socket {
+        Socket {
+            state,
+            local,
+            local_port,
+            remote,
+            remote_port,
+            backlog: VecDeque::new(),
+            parent: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            send_buf: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            fin_seq: None,
+            rcv_nxt: 0,
+            recv_buf: VecDeque::new(),
+            ack_pending: false,
+            rto: INITIAL_RTO,
+            deadline: None,
+            retries: 0,
+            orphaned: false,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        core::cmp::min(RECV_BUF - self.recv_buf.len(), u16::MAX as usize) as u16
+    }
+
+    fn send_segment(&self, flags: u8, seq: u32, payload: &[u8], out: &mut Vec<Outgoing>) {
+        out.push(build(
+            (self.local, self.local_port),
+            (self.remote, self.remote_port),
+            seq,
+            if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            self.window(),
+            payload,
+        ));
+    }
+
+    fn send_syn(&self, out: &mut Vec<Outgoing>) {
+        let flags = if self.state == State::SynReceived { SYN | ACK } else { SYN };
+        self.send_segment(flags, self.iss, &[], out);
+    }
+
+    fn is_synchronized(&self) -> bool {
+        !matches!(self.state, State::Listen | State::SynSent | State::Closed)
+    }
+
+    fn can_send(&self) -> bool {
+        matches!(
+            self.state,
+            State::Established | State::CloseWait | State::FinWait1 | State::Closing | State::LastAck
+        )
+    }
+
+    /// Sends whatever the peer's window lets through, then the FIN once
+    /// everything is out, or else a bare ACK if one is owed
+    fn transmit(&mut self, now: u64, out: &mut Vec<Outgoing>) {
+        let mut sent = false;
+        if self.can_send() {
+            while !self.fin_sent {
+                let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                let unsent = self.send_buf.len() - in_flight;
+                let room = self.snd_wnd.saturating_sub(in_flight);
+                let n = core::cmp::min(unsent, core::cmp::min(room, self.mss));
+                if n == 0 {
+                    if unsent > 0 && self.deadline.is_none() {
+                        // Probe the zero window when the timer goes off
+                        self.deadline = Some(now + self.rto);
+                    }
+                    break;
+                }
+                let payload: Vec<u8> = self.send_buf.iter().skip(in_flight).take(n).copied().collect();
+                let flags = if n == unsent { ACK | PSH } else { ACK };
+                self.send_segment(flags, self.snd_nxt, &payload, out);
+                self.snd_nxt = self.snd_nxt.wrapping_add(n as u32);
+                sent = true;
+            }
+
+            let all_sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize == self.send_buf.len();
+            if self.fin_queued && !self.fin_sent && all_sent {
+                self.send_segment(FIN | ACK, self.snd_nxt, &[], out);
+                self.fin_seq = Some(self.snd_nxt);
+                self.fin_sent = true;
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                sent = true;
+                if self.state == State::Established {
+                    self.state = State::FinWait1;
+                } else if self.state == State::CloseWait {
+                    self.state = State::LastAck;
+                }
+            }
+        }
+
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+        if sent {
+            self.ack_pending = false;
+            if self.deadline.is_none() {
+                self.deadline = Some(now + self.rto);
+            }
+        } else if self.ack_pending && self.is_synchronized() {
+            self.send_segment(ACK, self.snd_nxt, &[], out);
+            self.ack_pending = false;
+        }
+    }
+
+    fn on_timer(&mut self, now: u64, out: &mut Vec<Outgoing>) {
+        match self.deadline {
+            Some(deadline) if deadline <= now => self.deadline = None,
+            _ => return,
+        }
+        if self.state == State::TimeWait {
+            self.state = State::Closed;
+            return;
+        }
+
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            if self.is_synchronized() {
+                self.send_segment(RST | ACK, self.snd_nxt, &[], out);
+            }
+            self.state = State::Closed;
+            return;
+        }
+        self.rto = core::cmp::min(self.rto * 2, MAX_RTO);
+
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                self.send_syn(out);
+                self.deadline = Some(now + self.rto);
+            }
+            _ => {
+                // Go back to the oldest unacknowledged byte and resend from
+                // there; a closed window gets a one-byte probe
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+                let window = self.snd_wnd;
+                self.snd_wnd = core::cmp::max(window, 1);
+                self.transmit(now, out);
+                self.snd_wnd = window;
+                if self.deadline.is_none() && self.snd_una != self.snd_max {
+                    self.deadline = Some(now + self.rto);
+                }
+            }
+        }
+    }
+
+    /// Handles an acknowledgement that covers new data. Returns false if
+    /// it acks something that was never sent.
+    fn process_ack(&mut self, header: &TcpHeader, now: u64) -> bool {
+        if seq_lt(self.snd_max, header.ack) {
+            return false;
+        }
+        if seq_lt(header.ack, self.snd_una) {
+            // A duplicate; only its window is of interest
+            return true;
+        }
+
+        let acked = header.ack.wrapping_sub(self.snd_una) as usize;
+        let data = core::cmp::min(acked, self.send_buf.len());
+        self.send_buf.drain(..data);
+        self.snd_una = header.ack;
+        if seq_lt(self.snd_nxt, self.snd_una) {
+            self.snd_nxt = self.snd_una;
+        }
+        self.snd_wnd = header.window as usize;
+        if acked > 0 {
+            self.retries = 0;
+            self.rto = INITIAL_RTO;
+            self.deadline = if self.snd_una == self.snd_max { None } else { Some(now + self.rto) };
+        }
+
+        let fin_acked = self.fin_seq.map_or(false, |fin| header.ack == fin.wrapping_add(1));
+        if fin_acked {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => self.state = State::Closed,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = State::TimeWait;
+        self.deadline = Some(now + TIME_WAIT);
+    }
+
+    /// Handles a segment for a connection past SYN-SENT. Returns true once
+    /// a handshake it was in finishes.
+    fn process(&mut self, header: &TcpHeader, payload: &[u8], now: u64, out: &mut Vec<Outgoing>) -> bool {
+        let mut established = false;
+
+        if header.flags & RST != 0 {
+            // Only a reset right at the edge of the window is believed
+            if header.seq == self.rcv_nxt {
+                self.state = State::Closed;
+                self.deadline = None;
+            }
+            return false;
+        }
+        if header.flags & SYN != 0 {
+            if self.state == State::SynReceived && header.seq.wrapping_add(1) == self.rcv_nxt {
+                self.send_syn(out);
+            } else {
+                self.ack_pending = true;
+                self.transmit(now, out);
+            }
+            return false;
+        }
+        if header.flags & ACK == 0 {
+            return false;
+        }
+
+        if self.state == State::SynReceived {
+            if header.ack != self.snd_nxt {
+                out.push(build(
+                    (self.local, self.local_port),
+                    (self.remote, self.remote_port),
+                    header.ack,
+                    0,
+                    RST,
+                    0,
+                    &[],
+                ));
+                return false;
+            }
+            self.state = State::Established;
+            self.snd_una = header.ack;
+            self.snd_wnd = header.window as usize;
+            self.deadline = None;
+            self.retries = 0;
+            self.rto = INITIAL_RTO;
+            established = true;
+        } else if !self.process_ack(header, now) {
+            self.ack_pending = true;
+            self.transmit(now, out);
+            return false;
+        }
+
+        // Trim whatever was already received off the front
+        let mut payload = payload;
+        let mut fin = header.flags & FIN != 0;
+        let behind = self.rcv_nxt.wrapping_sub(header.seq) as i32;
+        if behind > 0 {
+            let behind = behind as usize;
+            if behind > payload.len() {
+                fin = false;
+            }
+            payload = &payload[core::cmp::min(behind, payload.len())..];
+            self.ack_pending = true;
+        } else if behind < 0 {
+            // Out of order: ask again for what's missing
+            payload = &[];
+            fin = false;
+            self.ack_pending = true;
+        }
+
+        if !payload.is_empty() {
+            if matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+                let taken = core::cmp::min(payload.len(), RECV_BUF - self.recv_buf.len());
+                self.recv_buf.extend(&payload[..taken]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(taken as u32);
+                if taken < payload.len() {
+                    fin = false;
+                }
+            } else {
+                fin = false;
+            }
+            self.ack_pending = true;
+        }
+
+        if fin {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.ack_pending = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 | State::TimeWait => self.enter_time_wait(now),
+                _ => {}
+            }
+        }
+
+        self.transmit(now, out);
+        established
+    }
+}
+
+pub struct TcpSockets {
+    sockets: Vec<Option<Socket>>,
+    next_ephemeral: u16,
+}
+
+impl TcpSockets {
+    pub fn new() -> TcpSockets {
+        TcpSockets {
+            sockets: Vec::new(),
+            next_ephemeral: EPHEMERAL_START,
+        }
+    }
+
+    fn add(&mut self, socket: Socket) -> usize {
+        match self.sockets.iter().position(|s| s.is_none()) {
+            Some(i) => {
+                self.sockets[i] = Some(socket);
+                i
+            }
+            None => {
+                self.sockets.push(Some(socket));
+                self.sockets.len() - 1
+            }
+        }
+    }
+
+    fn socket(&mut self, handle: TcpHandle) -> Result<&mut Socket, Error> {
+        match self.sockets.get_mut(handle.0) {
+            Some(Some(socket)) if !socket.orphaned => Ok(socket),
+            _ => Err(Error::BadHandle),
+        }
+    }
+
+    fn port_in_use(&self, port: u16) -> bool {
+        self.sockets.iter().flatten().any(|s| s.local_port == port)
+    }
+
+    // Drops closed connections nobody holds anymore
+    fn reap(&mut self) {
+        for slot in self.sockets.iter_mut() {
+            if let Some(socket) = slot {
+                if socket.orphaned && socket.state == State::Closed {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    pub fn listen(&mut self, local: Ipv4Addr, port: u16) -> Result<TcpHandle, Error> {
+        if self
+            .sockets
+            .iter()
+            .flatten()
+            .any(|s| {
+                s.state == State::Listen
+                    && s.local_port == port
+                    && (s.local == local || s.local.is_unspecified() || local.is_unspecified())
+            })
+        {
+            return Err(Error::AddrInUse);
+        }
+        let socket = Socket::new(State::Listen, local, port, Ipv4Addr::UNSPECIFIED, 0, 0);
+        Ok(TcpHandle(self.add(socket)))
+    }
+
+    /// Takes a connection that finished its handshake on `listener`
+    pub fn accept(&mut self, listener: TcpHandle) -> Result<Option<TcpHandle>, Error> {
+        if self.socket(listener)?.state != State::Listen {
+            return Err(Error::BadHandle);
+        }
+        while let Some(i) = self.socket(listener)?.backlog.pop_front() {
+            // Connections reset before being accepted are gone already
+            if let Some(child) = self.sockets[i].as_mut().filter(|c| c.parent == Some(listener.0)) {
+                child.orphaned = false;
+                child.parent = None;
+                return Ok(Some(TcpHandle(i)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn connect(
+        &mut self,
+        local: Ipv4Addr,
+        remote: Ipv4Addr,
+        port: u16,
+        now: u64,
+        out: &mut Vec<Outgoing>,
+    ) -> Result<TcpHandle, Error> {
+        let mut tries = 0;
+        while self.port_in_use(self.next_ephemeral) {
+            self.next_ephemeral = self.next_ephemeral.checked_add(1).unwrap_or(EPHEMERAL_START);
+            tries += 1;
+            if tries > u16::MAX - EPHEMERAL_START {
+                return Err(Error::AddrInUse);
+            }
+        }
+        let local_port = self.next_ephemeral;
+        // Unguessable so that off-path hosts can't inject segments
+        let iss = random::random_u32();
+        let mut socket = Socket::new(State::SynSent, local, local_port, remote, port, iss);
+        socket.send_syn(out);
+        socket.deadline = Some(now + socket.rto);
+        Ok(TcpHandle(self.add(socket)))
+    }
+
+    /// Buffers as much of `data` as fits and sends what the window allows.
+    /// Returns how much was taken.
+    pub fn send(&mut self, handle: TcpHandle, data: &[u8], now: u64, out: &mut Vec<Outgoing>) -> Result<usize, Error> {
+        let socket = self.socket(handle)?;
+        let open = matches!(
+            socket.state,
+            State::SynSent | State::SynReceived | State::Established | State::CloseWait
+        );
+        if !open || socket.fin_queued {
+            return Err(Error::Closed);
+        }
+        let n = core::cmp::min(data.len(), SEND_BUF - socket.send_buf.len());
+        socket.send_buf.extend(&data[..n]);
+        socket.transmit(now, out);
+        Ok(n)
+    }
+
+    /// Copies received data into `buf`. Returns 0 if nothing has arrived
+    /// yet, and `Error::Closed` once the peer is done sending.
+    pub fn recv(&mut self, handle: TcpHandle, buf: &mut [u8], now: u64, out: &mut Vec<Outgoing>) -> Result<usize, Error> {
+        let socket = self.socket(handle)?;
+        if socket.recv_buf.is_empty() {
+            return match socket.state {
+                State::Listen | State::SynSent | State::SynReceived | State::Established | State::FinWait1 | State::FinWait2 => Ok(0),
+                _ => Err(Error::Closed),
+            };
+        }
+        let closed_window = (socket.window() as usize) < socket.mss;
+        let n = core::cmp::min(buf.len(), socket.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(socket.recv_buf.drain(..n)) {
+            *dst = src;
+        }
+        if closed_window && socket.window() as usize >= socket.mss {
+            // Let the peer know there is room again
+            socket.ack_pending = true;
+            socket.transmit(now, out);
+        }
+        Ok(n)
+    }
+
+    /// Finishes sending and lets go of the handle. The connection goes
+    /// away once the peer is done too.
+    pub fn close(&mut self, handle: TcpHandle, now: u64, out: &mut Vec<Outgoing>) -> Result<(), Error> {
+        let socket = self.socket(handle)?;
+        socket.orphaned = true;
+        match socket.state {
+            State::Listen => {
+                let backlog: Vec<usize> = socket.backlog.drain(..).collect();
+                self.sockets[handle.0] = None;
+                // Turn away whatever hadn't been accepted yet
+                for i in 0..self.sockets.len() {
+                    let child = match self.sockets[i].as_mut() {
+                        Some(child) if child.parent == Some(handle.0) || backlog.contains(&i) => child,
+                        _ => continue,
+                    };
+                    child.send_segment(RST | ACK, child.snd_nxt, &[], out);
+                    self.sockets[i] = None;
+                }
+            }
+            State::SynSent => socket.state = State::Closed,
+            State::Closed | State::TimeWait | State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck => {}
+            State::SynReceived | State::Established | State::CloseWait => {
+                socket.fin_queued = true;
+                socket.transmit(now, out);
+            }
+        }
+        self.reap();
+        Ok(())
+    }
+
+    pub fn state(&mut self, handle: TcpHandle) -> Result<State, Error> {
+        self.socket(handle).map(|s| s.state)
+    }
+
+    /// Handles one incoming segment
+    pub fn receive(&mut self, src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8], now: u64, out: &mut Vec<Outgoing>) {
+        let header = match TcpHeader::parse(segment, src, dst) {
+            Some(header) => header,
+            None => return,
+        };
+        let payload = &segment[header.header_len..];
+
+        let conn = self.sockets.iter().position(|s| {
+            s.as_ref().map_or(false, |s| {
+                s.state != State::Listen
+                    && s.state != State::Closed
+                    && (s.local_port, s.remote, s.remote_port) == (header.dst_port, src, header.src_port)
+            })
+        });
+        match conn {
+            Some(i) => self.receive_on(i, &header, payload, now, out),
+            None => {
+                // A listener bound before its namespace had an address takes
+                // whatever address the namespace ends up with
+                let listener = self.sockets.iter().position(|s| {
+                    s.as_ref().map_or(false, |s| {
+                        s.state == State::Listen
+                            && s.local_port == header.dst_port
+                            && (s.local == dst || s.local.is_unspecified())
+                    })
+                });
+                match listener {
+                    Some(l) if header.flags & (SYN | ACK | RST) == SYN => {
+                        self.syn_received(l, src, dst, &header, now, out)
+                    }
+                    Some(_) if header.flags & (ACK | RST) != ACK => {}
+                    _ => refuse(src, dst, &header, payload.len(), out),
+                }
+            }
+        }
+        self.reap();
+    }
+
+    fn syn_received(
+        &mut self,
+        listener: usize,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        header: &TcpHeader,
+        now: u64,
+        out: &mut Vec<Outgoing>,
+    ) {
+        let waiting = self.sockets.iter().flatten().filter(|s| s.parent == Some(listener)).count();
+        if waiting >= BACKLOG {
+            return;
+        }
+        let iss = random::random_u32();
+        let mut socket = Socket::new(State::SynReceived, dst, header.dst_port, src, header.src_port, iss);
+        socket.parent = Some(listener);
+        socket.orphaned = true;
+        socket.rcv_nxt = header.seq.wrapping_add(1);
+        socket.snd_wnd = header.window as usize;
+        socket.mss = header.mss.map_or(DEFAULT_MSS, |mss| core::cmp::min(mss as usize, LOCAL_MSS));
+        socket.send_syn(out);
+        socket.deadline = Some(now + socket.rto);
+        self.add(socket);
+    }
+
+    fn receive_on(&mut self, i: usize, header: &TcpHeader, payload: &[u8], now: u64, out: &mut Vec<Outgoing>) {
+        let socket = self.sockets[i].as_mut().unwrap();
+        if socket.state != State::SynSent {
+            let parent = socket.parent;
+            if socket.process(header, payload, now, out) {
+                if let Some(Some(listener)) = parent.map(|p| self.sockets[p].as_mut()) {
+                    listener.backlog.push_back(i);
+                }
+            }
+            return;
+        }
+
+        let acceptable = header.ack == socket.snd_nxt;
+        if header.flags & ACK != 0 && !acceptable {
+            if header.flags & RST == 0 {
+                refuse(socket.remote, socket.local, header, payload.len(), out);
+            }
+            return;
+        }
+        if header.flags & RST != 0 {
+            if header.flags & ACK != 0 {
+                socket.state = State::Closed;
+                socket.deadline = None;
+            }
+            return;
+        }
+        if header.flags & SYN == 0 {
+            return;
+        }
+
+        socket.rcv_nxt = header.seq.wrapping_add(1);
+        socket.snd_wnd = header.window as usize;
+        socket.mss = header.mss.map_or(DEFAULT_MSS, |mss| core::cmp::min(mss as usize, LOCAL_MSS));
+        if header.flags & ACK != 0 {
+            socket.state = State::Established;
+            socket.snd_una = header.ack;
+            socket.deadline = None;
+            socket.retries = 0;
+            socket.ack_pending = true;
+            socket.transmit(now, out);
+        } else {
+            // Both sides opened at once
+            socket.state = State::SynReceived;
+            socket.send_syn(out);
+        }
+    }
+
+    /// Retransmits whatever timed out and ends finished TIME-WAITs
+    pub fn on_timer(&mut self, now: u64, out: &mut Vec<Outgoing>) {
+        for socket in self.sockets.iter_mut().flatten() {
+            socket.on_timer(now, out);
+        }
+        self.reap();
+    }
+}
+
+// Answers a segment no connection wants with a reset
+fn refuse(src: Ipv4Addr, dst: Ipv4Addr, header: &TcpHeader, payload_len: usize, out: &mut Vec<Outgoing>) {
+    if header.flags & RST != 0 {
+        return;
+    }
+    let local = (dst, header.dst_port);
+    let remote = (src, header.src_port);
+    if header.flags & ACK != 0 {
+        out.push(build(local, remote, header.ack, 0, RST, 0, &[]));
+    } else {
+        let len = payload_len as u32 + (header.flags & SYN != 0) as u32 + (header.flags & FIN != 0) as u32;
+        out.push(build(local, remote, 0, header.seq.wrapping_add(len), RST | ACK, 0, &[]));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr([192, 168, 14, 1]);
+    const SERVER: Ipv4Addr = Ipv4Addr([192, 168, 14, 4]);
+
+    // Hands every segment in `out` to `to`, as if sent by `from`
+    fn deliver(from: Ipv4Addr, out: &mut Vec<Outgoing>, to: &mut TcpSockets, to_addr: Ipv4Addr, now: u64) -> Vec<Outgoing> {
+        let mut replies = Vec::new();
+        for segment in out.drain(..) {
+            to.receive(from, to_addr, &segment.segment, now, &mut replies);
+        }
+        replies
+    }
+
+    fn connected() -> (TcpSockets, TcpHandle, TcpSockets, TcpHandle) {
+        let mut server = TcpSockets::new();
+        let mut client = TcpSockets::new();
+        let listener = server.listen(SERVER, 23).unwrap();
+
+        let mut out = Vec::new();
+        let conn = client.connect(CLIENT, SERVER, 23, 0, &mut out).unwrap();
+        let mut out = deliver(CLIENT, &mut out, &mut server, SERVER, 0);
+        let mut out = deliver(SERVER, &mut out, &mut client, CLIENT, 0);
+        deliver(CLIENT, &mut out, &mut server, SERVER, 0);
+
+        let accepted = server.accept(listener).unwrap().unwrap();
+        assert_eq!(client.state(conn), Ok(State::Established));
+        assert_eq!(server.state(accepted), Ok(State::Established));
+        (client, conn, server, accepted)
+    }
+
+    #[test_case]
+    fn test_tcp_handshake_and_data() {
+        let (mut client, conn, mut server, accepted) = connected();
+
+        let mut out = Vec::new();
+        assert_eq!(client.send(conn, b"ls\n", 0, &mut out), Ok(3));
+        let mut acks = deliver(CLIENT, &mut out, &mut server, SERVER, 0);
+        deliver(SERVER, &mut acks, &mut client, CLIENT, 0);
+
+        let mut buf = [0; 8];
+        assert_eq!(server.recv(accepted, &mut buf, 0, &mut out), Ok(3));
+        assert_eq!(&buf[..3], b"ls\n");
+        assert_eq!(client.sockets[conn.0].as_ref().unwrap().send_buf.len(), 0);
+    }
+
+    #[test_case]
+    fn test_tcp_retransmits_lost_segment() {
+        let (mut client, conn, mut server, accepted) = connected();
+
+        let mut lost = Vec::new();
+        client.send(conn, b"hello", 0, &mut lost).unwrap();
+        assert_eq!(lost.len(), 1);
+
+        // Nothing is resent before the timer runs out
+        let mut out = Vec::new();
+        client.on_timer(INITIAL_RTO - 1, &mut out);
+        assert!(out.is_empty());
+        client.on_timer(INITIAL_RTO, &mut out);
+        assert_eq!(out.len(), 1);
+
+        let mut acks = deliver(CLIENT, &mut out, &mut server, SERVER, INITIAL_RTO);
+        deliver(SERVER, &mut acks, &mut client, CLIENT, INITIAL_RTO);
+        let mut buf = [0; 8];
+        assert_eq!(server.recv(accepted, &mut buf, INITIAL_RTO, &mut out), Ok(5));
+        assert_eq!(client.sockets[conn.0].as_ref().unwrap().deadline, None);
+    }
+
+    #[test_case]
+    fn test_tcp_respects_window() {
+        let (mut client, conn, mut server, accepted) = connected();
+        let data = [7u8; RECV_BUF + 100];
+
+        // The client can't send past what the server advertised
+        let mut out = Vec::new();
+        assert_eq!(client.send(conn, &data, 0, &mut out), Ok(data.len()));
+        let sent: usize = out.iter().map(|s| s.segment.len() - HEADER_LEN).sum();
+        assert_eq!(sent, RECV_BUF);
+
+        let mut acks = deliver(CLIENT, &mut out, &mut server, SERVER, 0);
+        let mut more = deliver(SERVER, &mut acks, &mut client, CLIENT, 0);
+        assert!(more.is_empty());
+
+        // Reading reopens the window, and the rest follows
+        let mut buf = [0; RECV_BUF];
+        assert_eq!(server.recv(accepted, &mut buf, 0, &mut more), Ok(RECV_BUF));
+        let mut rest = deliver(SERVER, &mut more, &mut client, CLIENT, 0);
+        let sent: usize = rest.iter().map(|s| s.segment.len() - HEADER_LEN).sum();
+        assert_eq!(sent, 100);
+        deliver(CLIENT, &mut rest, &mut server, SERVER, 0);
+        assert_eq!(server.recv(accepted, &mut buf, 0, &mut more), Ok(100));
+    }
+
+    #[test_case]
+    fn test_tcp_close() {
+        let (mut client, conn, mut server, accepted) = connected();
+
+        let mut out = Vec::new();
+        client.close(conn, 0, &mut out).unwrap();
+        let mut acks = deliver(CLIENT, &mut out, &mut server, SERVER, 0);
+        let mut buf = [0; 8];
+        assert_eq!(server.recv(accepted, &mut buf, 0, &mut out), Err(Error::Closed));
+
+        server.close(accepted, 0, &mut acks).unwrap();
+        let mut last = deliver(SERVER, &mut acks, &mut client, CLIENT, 0);
+        deliver(CLIENT, &mut last, &mut server, SERVER, 0);
+        assert!(server.sockets.iter().flatten().all(|s| s.state == State::Listen));
+
+        // The client lingers in TIME-WAIT, then goes
+        assert!(client.sockets.iter().flatten().all(|s| s.state == State::TimeWait));
+        client.on_timer(TIME_WAIT, &mut out);
+        assert!(client.sockets.iter().flatten().next().is_none());
+    }
+
+    #[test_case]
+    fn test_tcp_refuses_closed_port() {
+        let mut server = TcpSockets::new();
+        let mut client = TcpSockets::new();
+        // Listening on the port, but at another address
+        server.listen(Ipv4Addr([192, 168, 14, 5]), 23).unwrap();
+        assert_eq!(server.listen(Ipv4Addr::UNSPECIFIED, 23), Err(Error::AddrInUse));
+        let mut out = Vec::new();
+        let conn = client.connect(CLIENT, SERVER, 23, 0, &mut out).unwrap();
+        let mut reset = deliver(CLIENT, &mut out, &mut server, SERVER, 0);
+        assert_eq!(reset.len(), 1);
+        deliver(SERVER, &mut reset, &mut client, CLIENT, 0);
+        assert_eq!(client.state(conn), Ok(State::Closed));
+    }
+}
@@ -0,0 +1,155 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::ipv4::{fold, pseudo_header_sum, sum_words, Ipv4Addr, PROTO_UDP};
+use super::Error;
+
+pub const HEADER_LEN: usize = 8;
+
+const MAX_QUEUED: usize = 32;
+const EPHEMERAL_START: u16 = 49152;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UdpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub len: usize,
+}
+
+impl UdpHeader {
+    /// Reads the header and checks the datagram against its checksum
+    pub fn parse(datagram: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> Option<UdpHeader> {
+        if datagram.len() < HEADER_LEN {
+            return None;
+        }
+        let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+        if len < HEADER_LEN || len > datagram.len() {
+            return None;
+        }
+        // A zero checksum means the sender didn't compute one
+        if datagram[6..8] != [0, 0] && fold(sum_words(&datagram[..len], pseudo_header_sum(src, dst, PROTO_UDP, len))) != 0 {
+            return None;
+        }
+        Some(UdpHeader {
+            src_port: u16::from_be_bytes([datagram[0], datagram[1]]),
+            dst_port: u16::from_be_bytes([datagram[2], datagram[3]]),
+            len,
+        })
+    }
+
+    /// Writes the header in front of the payload already in `datagram`, and
+    /// checksums both
+    pub fn emit(&self, datagram: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr) {
+        datagram[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        datagram[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        datagram[4..6].copy_from_slice(&(self.len as u16).to_be_bytes());
+        datagram[6..8].copy_from_slice(&[0, 0]);
+        let sum = match fold(sum_words(&datagram[..self.len], pseudo_header_sum(src, dst, PROTO_UDP, self.len))) {
+            0 => 0xffff,
+            sum => sum,
+        };
+        datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub data: Vec<u8>,
+}
+
+/// An open UDP port, owned by whoever bound it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHandle(usize);
+
+struct Socket {
+    port: u16,
+    received: VecDeque<Datagram>,
+}
+
+pub struct UdpSockets {
+    sockets: Vec<Option<Socket>>,
+    next_ephemeral: u16,
+}
+
+impl UdpSockets {
+    pub fn new() -> UdpSockets {
+        UdpSockets {
+            sockets: Vec::new(),
+            next_ephemeral: EPHEMERAL_START,
+        }
+    }
+
+    fn bound(&self, port: u16) -> bool {
+        self.sockets.iter().flatten().any(|s| s.port == port)
+    }
+
+    /// Opens `port`, or a free ephemeral port if it is 0
+    pub fn bind(&mut self, port: u16) -> Result<UdpHandle, Error> {
+        let port = if port == 0 {
+            let mut tries = 0;
+            while self.bound(self.next_ephemeral) {
+                self.next_ephemeral = self.next_ephemeral.checked_add(1).unwrap_or(EPHEMERAL_START);
+                tries += 1;
+                if tries > u16::MAX - EPHEMERAL_START {
+                    return Err(Error::AddrInUse);
+                }
+            }
+            self.next_ephemeral
+        } else if self.bound(port) {
+            return Err(Error::AddrInUse);
+        } else {
+            port
+        };
+
+        let socket = Socket { port, received: VecDeque::new() };
+        let i = match self.sockets.iter().position(|s| s.is_none()) {
+            Some(i) => {
+                self.sockets[i] = Some(socket);
+                i
+            }
+            None => {
+                self.sockets.push(Some(socket));
+                self.sockets.len() - 1
+            }
+        };
+        Ok(UdpHandle(i))
+    }
+
+    pub fn close(&mut self, handle: UdpHandle) {
+        if let Some(socket) = self.sockets.get_mut(handle.0) {
+            *socket = None;
+        }
+    }
+
+    pub fn port(&self, handle: UdpHandle) -> Result<u16, Error> {
+        self.socket(handle).map(|s| s.port)
+    }
+
+    fn socket(&self, handle: UdpHandle) -> Result<&Socket, Error> {
+        self.sockets.get(handle.0).and_then(|s| s.as_ref()).ok_or(Error::BadHandle)
+    }
+
+    /// Queues `datagram` on whichever socket has `port`. Returns false if
+    /// nobody has it open.
+    pub fn deliver(&mut self, port: u16, datagram: Datagram) -> bool {
+        match self.sockets.iter_mut().flatten().find(|s| s.port == port) {
+            Some(socket) => {
+                if socket.received.len() == MAX_QUEUED {
+                    socket.received.pop_front();
+                }
+                socket.received.push_back(datagram);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn pop(&mut self, handle: UdpHandle) -> Result<Option<Datagram>, Error> {
+        self.sockets
+            .get_mut(handle.0)
+            .and_then(|s| s.as_mut())
+            .map(|s| s.received.pop_front())
+            .ok_or(Error::BadHandle)
+    }
+}
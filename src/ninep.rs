@@ -0,0 +1,414 @@
+//! A 9P2000.L client over virtio-9p, used to share a host directory with the
+//! kernel under QEMU
+//!
+//! Requests are issued one at a time and each waits for its reply, so a
+//! single tag is enough.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::virtio::VirtIO9P;
+
+pub const MSIZE: usize = 8192;
+const VERSION: &str = "9P2000.L";
+
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+const TAG: u16 = 1;
+const ROOT_FID: u32 = 0;
+
+// size[4] type[1] tag[2]
+const HEADER_LEN: usize = 7;
+// Tread/Twrite header plus fid[4] offset[8] count[4]
+const IO_HEADER_LEN: usize = HEADER_LEN + 16;
+// Tlcreate header plus fid[4], then flags[4] mode[4] gid[4] after the name
+const CREATE_HEADER_LEN: usize = HEADER_LEN + 16;
+// Most names a single Twalk may carry
+const MAXWELEM: u16 = 16;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const GETATTR_SIZE: u64 = 0x200;
+
+// Linux open flags as used by Tlopen and Tlcreate
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_DIRECTORY: u32 = 0o200000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The server replied with Rlerror and this errno
+    Errno(u32),
+    UnexpectedReply(u8),
+    Malformed,
+    NameTooLong,
+    /// The path has more than MAXWELEM components
+    PathTooDeep,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fid(u32);
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8], kind: u8) -> Self {
+        let mut w = Writer { buf, pos: 4 };
+        w.u8(kind);
+        w.u16(TAG);
+        w
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.buf[self.pos..self.pos + b.len()].copy_from_slice(b);
+        self.pos += b.len();
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &[u8]) {
+        self.u16(s.len() as u16);
+        self.bytes(s);
+    }
+
+    // Writes the size field and returns the message
+    fn finish(self) -> &'a [u8] {
+        let len = self.pos;
+        self.buf[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        &self.buf[..len]
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let b = self.buf.get(self.pos..self.pos + n).ok_or(Error::Malformed)?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut v = [0; 4];
+        v.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(v))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut v = [0; 8];
+        v.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    fn str(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    fn qid(&mut self) -> Result<Qid, Error> {
+        Ok(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+}
+
+pub struct NinePClient<'a, 'b> {
+    dev: &'a mut VirtIO9P<'b>,
+    msize: usize,
+    tx: Vec<u8>,
+    rx: Vec<u8>,
+    free_fids: Vec<u32>,
+    next_fid: u32,
+}
+
+impl<'a, 'b> NinePClient<'a, 'b> {
+    /// Negotiates the protocol version and attaches to the share
+    pub fn mount(dev: &'a mut VirtIO9P<'b>, aname: &str) -> Result<Self, Error> {
+        let mut client = NinePClient {
+            dev,
+            msize: MSIZE,
+            tx: vec![0; MSIZE],
+            rx: vec![0; MSIZE],
+            free_fids: Vec::new(),
+            next_fid: ROOT_FID + 1,
+        };
+
+        let mut w = Writer::new(&mut client.tx, TVERSION);
+        w.u32(MSIZE as u32);
+        w.str(VERSION.as_bytes());
+        // Tversion is the one message that must carry NOTAG
+        let len = w.pos;
+        client.tx[5..7].copy_from_slice(&NOTAG.to_le_bytes());
+        client.tx[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        let mut r = client.call(len)?;
+        let msize = r.u32()? as usize;
+        if r.str()? != VERSION.as_bytes() {
+            return Err(Error::Malformed);
+        }
+        client.msize = core::cmp::min(msize, MSIZE);
+
+        let mut w = Writer::new(&mut client.tx, TATTACH);
+        w.u32(ROOT_FID);
+        w.u32(NOFID);
+        w.str(b"root");
+        w.str(aname.as_bytes());
+        w.u32(0); // n_uname
+        let len = w.finish().len();
+        client.call(len)?.qid()?;
+
+        Ok(client)
+    }
+
+    // Sends the first `len` bytes of tx and checks the reply type
+    fn call(&mut self, len: usize) -> Result<Reader<'_>, Error> {
+        let kind = self.tx[4];
+        let n = self.dev.transact(&self.tx[..len], &mut self.rx[..self.msize]);
+        let mut r = Reader { buf: &self.rx[..n], pos: 4 };
+        match r.u8()? {
+            RLERROR => {
+                r.u16()?;
+                Err(Error::Errno(r.u32()?))
+            }
+            reply if reply == kind + 1 => {
+                r.u16()?;
+                Ok(r)
+            }
+            reply => Err(Error::UnexpectedReply(reply)),
+        }
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        self.free_fids.pop().unwrap_or_else(|| {
+            self.next_fid += 1;
+            self.next_fid - 1
+        })
+    }
+
+    /// Walks from the root to `path`, components separated by '/'
+    pub fn walk(&mut self, path: &str) -> Result<Fid, Error> {
+        let fid = self.alloc_fid();
+        let names = path.split('/').filter(|n| !n.is_empty());
+        let mut w = Writer::new(&mut self.tx, TWALK);
+        w.u32(ROOT_FID);
+        w.u32(fid);
+        let nwname_pos = w.pos;
+        w.u16(0);
+        let mut nwname = 0u16;
+        for name in names {
+            if nwname == MAXWELEM {
+                self.free_fids.push(fid);
+                return Err(Error::PathTooDeep);
+            }
+            if w.pos + 2 + name.len() > self.msize {
+                self.free_fids.push(fid);
+                return Err(Error::NameTooLong);
+            }
+            w.str(name.as_bytes());
+            nwname += 1;
+        }
+        let len = w.finish().len();
+        self.tx[nwname_pos..nwname_pos + 2].copy_from_slice(&nwname.to_le_bytes());
+
+        let walked = self.call(len).and_then(|mut r| r.u16());
+        match walked {
+            Ok(n) if n == nwname => Ok(Fid(fid)),
+            // a partial walk leaves the fid unused
+            Ok(_) => {
+                self.free_fids.push(fid);
+                Err(Error::Errno(2)) // ENOENT
+            }
+            Err(e) => {
+                self.free_fids.push(fid);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn open(&mut self, path: &str, flags: u32) -> Result<Fid, Error> {
+        let fid = self.walk(path)?;
+        let mut w = Writer::new(&mut self.tx, TLOPEN);
+        w.u32(fid.0);
+        w.u32(flags);
+        let len = w.finish().len();
+        match self.call(len).and_then(|mut r| r.qid()) {
+            Ok(_) => Ok(fid),
+            Err(e) => {
+                self.clunk(fid);
+                Err(e)
+            }
+        }
+    }
+
+    /// Creates (or truncates) `name` in directory `dir` and opens it
+    pub fn create(&mut self, dir: &str, name: &str, flags: u32, mode: u32) -> Result<Fid, Error> {
+        if CREATE_HEADER_LEN + 2 + name.len() > self.msize {
+            return Err(Error::NameTooLong);
+        }
+        // Tlcreate turns the directory fid into the new file's fid
+        let fid = self.walk(dir)?;
+        let mut w = Writer::new(&mut self.tx, TLCREATE);
+        w.u32(fid.0);
+        w.str(name.as_bytes());
+        w.u32(flags);
+        w.u32(mode);
+        w.u32(0); // gid
+        let len = w.finish().len();
+        match self.call(len).and_then(|mut r| r.qid()) {
+            Ok(_) => Ok(fid),
+            Err(e) => {
+                self.clunk(fid);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn size(&mut self, fid: Fid) -> Result<u64, Error> {
+        let mut w = Writer::new(&mut self.tx, TGETATTR);
+        w.u32(fid.0);
+        w.u64(GETATTR_SIZE);
+        let len = w.finish().len();
+        let mut r = self.call(len)?;
+        r.u64()?; // valid
+        r.qid()?;
+        r.u32()?; // mode
+        r.u32()?; // uid
+        r.u32()?; // gid
+        r.u64()?; // nlink
+        r.u64()?; // rdev
+        r.u64()
+    }
+
+    pub fn read(&mut self, fid: Fid, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let count = core::cmp::min(buf.len(), self.msize - IO_HEADER_LEN);
+        let mut w = Writer::new(&mut self.tx, TREAD);
+        w.u32(fid.0);
+        w.u64(offset);
+        w.u32(count as u32);
+        let len = w.finish().len();
+        let mut r = self.call(len)?;
+        let n = r.u32()? as usize;
+        // never more than was asked for, whatever the server says
+        if n > count {
+            return Err(Error::Malformed);
+        }
+        let data = r.bytes(n)?;
+        buf[..n].copy_from_slice(data);
+        Ok(n)
+    }
+
+    pub fn write(&mut self, fid: Fid, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let count = core::cmp::min(data.len(), self.msize - IO_HEADER_LEN);
+        let mut w = Writer::new(&mut self.tx, TWRITE);
+        w.u32(fid.0);
+        w.u64(offset);
+        w.u32(count as u32);
+        w.bytes(&data[..count]);
+        let len = w.finish().len();
+        self.call(len)?.u32().map(|n| n as usize)
+    }
+
+    /// Calls `f` with the name of every entry of the open directory `fid`
+    pub fn readdir<F: FnMut(&[u8])>(&mut self, fid: Fid, mut f: F) -> Result<(), Error> {
+        let mut offset = 0;
+        loop {
+            let mut w = Writer::new(&mut self.tx, TREADDIR);
+            w.u32(fid.0);
+            w.u64(offset);
+            w.u32((self.msize - IO_HEADER_LEN) as u32);
+            let len = w.finish().len();
+            let mut r = self.call(len)?;
+            let count = r.u32()? as usize;
+            if count == 0 {
+                return Ok(());
+            }
+            let mut entries = Reader { buf: r.bytes(count)?, pos: 0 };
+            while entries.pos < count {
+                entries.qid()?;
+                offset = entries.u64()?;
+                entries.u8()?; // type
+                f(entries.str()?);
+            }
+        }
+    }
+
+    pub fn clunk(&mut self, fid: Fid) {
+        let mut w = Writer::new(&mut self.tx, TCLUNK);
+        w.u32(fid.0);
+        let len = w.finish().len();
+        // the fid is released even if the server complains
+        let _ = self.call(len);
+        self.free_fids.push(fid.0);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_ninep_message_encoding() {
+        let mut buf = [0u8; 64];
+        let mut w = Writer::new(&mut buf, TWALK);
+        w.u32(1);
+        w.u32(2);
+        w.u16(1);
+        w.str(b"tmp");
+        let msg = w.finish();
+        assert_eq!(msg.len(), HEADER_LEN + 4 + 4 + 2 + 2 + 3);
+        assert_eq!(&msg[..7], &[msg.len() as u8, 0, 0, 0, TWALK, TAG as u8, 0]);
+
+        let mut r = Reader { buf: msg, pos: HEADER_LEN };
+        assert_eq!(r.u32().unwrap(), 1);
+        assert_eq!(r.u32().unwrap(), 2);
+        assert_eq!(r.u16().unwrap(), 1);
+        assert_eq!(r.str().unwrap(), b"tmp");
+        assert!(r.u8().is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use alloc::vec::Vec;
+
+use crate::device_tree::{read_cells, DeviceTree, Node};
+
+const CFG_VENDOR_ID: usize = 0x00;
+const CFG_DEVICE_ID: usize = 0x02;
+const CFG_COMMAND: usize = 0x04;
+const CFG_STATUS: usize = 0x06;
+const CFG_HEADER_TYPE: usize = 0x0e;
+const CFG_BAR0: usize = 0x10;
+const CFG_SUBSYSTEM_ID: usize = 0x2e;
+const CFG_CAP_PTR: usize = 0x34;
+const CFG_INTERRUPT_PIN: usize = 0x3d;
+
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAP_LIST: u16 = 1 << 4;
+
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+
+const SPACE_MEM32: u32 = 0x2;
+
+fn be_cells(bytes: &[u8], ncells: usize) -> (u64, &[u8]) {
+    let (work, rest) = bytes.split_at(ncells * 4);
+    let value = work.chunks(4).fold(0, |acc, c| {
+        acc << 32 | u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as u64
+    });
+    (value, rest)
+}
+
+/// A `pci-host-ecam-generic` host bridge
+pub struct PciHost {
+    ecam: usize,
+    bus_start: u8,
+    bus_end: u8,
+    // 32-bit memory window BARs get carved out of
+    mem_pci: u64,
+    mem_cpu: u64,
+    mem_size: u64,
+    mem_next: u64,
+    // (child address, pin) mask, and each interrupt-map entry's child
+    // address and pin with the parent's (type, number) interrupt cells
+    map_mask: [u32; 4],
+    map: Vec<([u32; 4], usize, usize)>,
+}
+
+impl PciHost {
+    /// Builds the host bridge from its device tree node
+    pub fn from_node(node: &Node, tree: &DeviceTree) -> Option<PciHost> {
+        let (ecam, _) = tree.regs(node).next()?;
+        let address_cells = tree.parent_of(node).map_or(2, |bus| bus.cells().0);
+        let (_, size_cells) = node.cells();
+
+        let (bus_start, bus_end) = node
+            .prop_by_name("bus-range")
+            .map(|prop| {
+                let (start, rest) = be_cells(prop.value, 1);
+                let (end, _) = be_cells(rest, 1);
+                (start as u8, end as u8)
+            })
+            .unwrap_or((0, 0xff));
+
+        // Each range is a 3-cell PCI address, a CPU address and a size
+        let mut window = None;
+        let mut ranges = node.prop_by_name("ranges")?.value;
+        while ranges.len() >= (3 + address_cells + size_cells) * 4 {
+            let (hi, rest) = be_cells(ranges, 1);
+            let (pci, rest) = be_cells(rest, 2);
+            let (cpu, rest) = be_cells(rest, address_cells);
+            let (size, rest) = be_cells(rest, size_cells);
+            ranges = rest;
+            if (hi as u32 >> 24) & 0x3 == SPACE_MEM32 && window.is_none() {
+                window = Some((pci, cpu, size));
+            }
+        }
+        let (mem_pci, mem_cpu, mem_size) = window?;
+
+        let mut map_mask = [0; 4];
+        if let Some(mask) = node.prop_by_name("interrupt-map-mask") {
+            mask.value
+                .chunks(4)
+                .take(4)
+                .enumerate()
+                .for_each(|(i, c)| map_mask[i] = u32::from_be_bytes([c[0], c[1], c[2], c[3]]));
+        }
+        // The child is a function's address and pin. The parent's interrupt
+        // specifier starts with type and number.
+        let map = tree
+            .interrupt_map(node)
+            .filter(|&(child, _, spec)| child.len() == 4 * 4 && spec.len() >= 2 * 4)
+            .map(|(child, _, spec)| {
+                let mut cells = [0; 4];
+                cells.iter_mut().zip(child.chunks(4)).for_each(|(cell, c)| *cell = read_cells(c, 1).0 as u32);
+                let (irq_type, spec) = read_cells(spec, 1);
+                let (irq, _) = read_cells(spec, 1);
+                (cells, irq_type, irq)
+            })
+            .collect();
+
+        Some(PciHost {
+            ecam,
+            bus_start,
+            bus_end,
+            mem_pci,
+            mem_cpu,
+            mem_size,
+            mem_next: 0,
+            map_mask,
+            map,
+        })
+    }
+
+    /// Every function present behind the bridge
+    pub fn functions(&self) -> impl Iterator<Item = PciFunction> + '_ {
+        (self.bus_start as u32..=self.bus_end as u32)
+            .flat_map(|bus| (0..32u32).map(move |dev| (bus, dev)))
+            .flat_map(move |(bus, dev)| {
+                let multi = PciFunction::at(self.ecam, self.bus_start, bus as u8, dev as u8, 0)
+                    .map(|f| f.read_u8(CFG_HEADER_TYPE) & 0x80 != 0)
+                    .unwrap_or(false);
+                let nfuncs = if multi { 8 } else { 1 };
+                (0..nfuncs).filter_map(move |func| {
+                    PciFunction::at(self.ecam, self.bus_start, bus as u8, dev as u8, func)
+                })
+            })
+    }
+
+    fn alloc_mem(&mut self, size: u64) -> Option<u64> {
+        let start = ((self.mem_pci + self.mem_next + size - 1) & !(size - 1)) - self.mem_pci;
+        if start + size > self.mem_size {
+            return None;
+        }
+        self.mem_next = start + size;
+        Some(start)
+    }
+
+    /// Sizes and places every memory BAR of `func`, then turns on memory
+    /// decoding and bus mastering. Returns the CPU address of each BAR.
+    pub fn assign_bars(&mut self, func: &PciFunction) -> [Option<usize>; 6] {
+        let mut bars = [None; 6];
+        func.write_u16(CFG_COMMAND, func.read_u16(CFG_COMMAND) & !COMMAND_MEMORY);
+
+        let mut i = 0;
+        while i < 6 {
+            let off = CFG_BAR0 + 4 * i;
+            let orig = func.read_u32(off);
+            if orig & 0x1 != 0 {
+                // I/O space isn't used
+                i += 1;
+                continue;
+            }
+            let is64 = (orig >> 1) & 0x3 == 0x2;
+
+            let orig_high = if is64 { func.read_u32(off + 4) } else { 0 };
+            func.write_u32(off, 0xffff_ffff);
+            let mut mask = (func.read_u32(off) & !0xf) as u64;
+            if is64 {
+                func.write_u32(off + 4, 0xffff_ffff);
+                mask |= (func.read_u32(off + 4) as u64) << 32;
+            } else {
+                mask |= 0xffff_ffff_0000_0000;
+            }
+
+            let placed = Some(mask)
+                .filter(|&mask| mask != 0xffff_ffff_0000_0000)
+                .and_then(|mask| self.alloc_mem(!mask + 1));
+            match placed {
+                Some(offset) => {
+                    let pci = self.mem_pci + offset;
+                    func.write_u32(off, pci as u32);
+                    if is64 {
+                        func.write_u32(off + 4, (pci >> 32) as u32);
+                    }
+                    bars[i] = Some((self.mem_cpu + offset) as usize);
+                }
+                // Don't leave the sizing pattern behind
+                None => {
+                    func.write_u32(off, orig);
+                    if is64 {
+                        func.write_u32(off + 4, orig_high);
+                    }
+                }
+            }
+            i += if is64 { 2 } else { 1 };
+        }
+
+        func.write_u16(
+            CFG_COMMAND,
+            func.read_u16(CFG_COMMAND) | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        );
+        bars
+    }
+
+    /// Looks the function's INTx pin up in the interrupt-map. Returns the
+    /// parent's (type, number) interrupt cells.
+    pub fn irq_for(&self, func: &PciFunction) -> Option<(usize, usize)> {
+        let pin = func.read_u8(CFG_INTERRUPT_PIN) as u32;
+        if pin == 0 {
+            return None;
+        }
+        let child = [func.address_hi(), 0, 0, pin];
+        self.map.iter().find_map(|&(entry, irq_type, irq)| {
+            let matches = (0..4).all(|i| entry[i] == child[i] & self.map_mask[i]);
+            matches.then_some((irq_type, irq))
+        })
+    }
+}
+
+/// One function's configuration space
+pub struct PciFunction {
+    cfg: usize,
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+}
+
+impl PciFunction {
+    // The ECAM region starts at the bridge's first bus
+    fn at(ecam: usize, bus_start: u8, bus: u8, dev: u8, func: u8) -> Option<PciFunction> {
+        let cfg = ecam + (((bus - bus_start) as usize) << 20 | (dev as usize) << 15 | (func as usize) << 12);
+        let function = PciFunction { cfg, bus, dev, func };
+        if function.vendor_id() == 0xffff {
+            None
+        } else {
+            Some(function)
+        }
+    }
+
+    fn address_hi(&self) -> u32 {
+        (self.bus as u32) << 16 | (self.dev as u32) << 11 | (self.func as u32) << 8
+    }
+
+    pub fn read_u8(&self, off: usize) -> u8 {
+        unsafe { read_volatile((self.cfg + off) as *const u8) }
+    }
+
+    pub fn read_u16(&self, off: usize) -> u16 {
+        unsafe { u16::from_le(read_volatile((self.cfg + off) as *const u16)) }
+    }
+
+    pub fn read_u32(&self, off: usize) -> u32 {
+        unsafe { u32::from_le(read_volatile((self.cfg + off) as *const u32)) }
+    }
+
+    pub fn write_u16(&self, off: usize, value: u16) {
+        unsafe { write_volatile((self.cfg + off) as *mut u16, value.to_le()) }
+    }
+
+    pub fn write_u32(&self, off: usize, value: u32) {
+        unsafe { write_volatile((self.cfg + off) as *mut u32, value.to_le()) }
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(CFG_VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(CFG_DEVICE_ID)
+    }
+
+    pub fn subsystem_id(&self) -> u16 {
+        self.read_u16(CFG_SUBSYSTEM_ID)
+    }
+
+    /// Offsets of the capabilities with id `cap_id`
+    pub fn capabilities(&self, cap_id: u8) -> impl Iterator<Item = usize> + '_ {
+        let mut next = if self.read_u16(CFG_STATUS) & STATUS_CAP_LIST != 0 {
+            (self.read_u8(CFG_CAP_PTR) & !0x3) as usize
+        } else {
+            0
+        };
+        core::iter::from_fn(move || {
+            while next != 0 {
+                let cap = next;
+                next = (self.read_u8(cap + 1) & !0x3) as usize;
+                if self.read_u8(cap) == cap_id {
+                    return Some(cap);
+                }
+            }
+            None
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MEM_PCI: u64 = 0x1000_0000;
+    const MEM_CPU: u64 = 0x8000_0000;
+    // Behind a bridge whose buses don't start at 0
+    const BUS: u8 = 1;
+
+    // The first bus of an ECAM region in RAM. Absent functions read as all
+    // ones.
+    fn ecam() -> usize {
+        let layout = core::alloc::Layout::from_size_align(32 * 8 * 4096, 4096).unwrap();
+        let ecam = unsafe { alloc::alloc::alloc(layout) };
+        unsafe { core::ptr::write_bytes(ecam, 0xff, layout.size()) };
+        ecam as usize
+    }
+
+    fn function(ecam: usize, dev: u8, func: u8, header_type: u8, pin: u8) -> PciFunction {
+        let function = PciFunction { cfg: ecam + ((dev as usize) << 15 | (func as usize) << 12), bus: BUS, dev, func };
+        function.write_u16(CFG_VENDOR_ID, 0x1af4);
+        function.write_u16(CFG_DEVICE_ID, 0x1041);
+        function.write_u16(CFG_STATUS, 0);
+        unsafe { write_volatile((function.cfg + CFG_HEADER_TYPE) as *mut u8, header_type) };
+        unsafe { write_volatile((function.cfg + CFG_INTERRUPT_PIN) as *mut u8, pin) };
+        function
+    }
+
+    #[test_case]
+    fn test_pci_host() {
+        let ecam = ecam();
+        let dev0 = function(ecam, 0, 0, 0, 1);
+        function(ecam, 2, 0, 0x80, 2);
+        function(ecam, 2, 1, 0, 0);
+
+        // 32-bit, 64-bit over two BARs, I/O, then two more than the window holds
+        [0x0, 0x4, 0x0, 0x1, 0x0, 0x0]
+            .iter()
+            .enumerate()
+            .for_each(|(i, &bar)| dev0.write_u32(CFG_BAR0 + 4 * i, bar));
+
+        let entry = |dev: u32, pin: u32, irq: usize| ([dev << 11, 0, 0, pin], 0, irq);
+        let mut host = PciHost {
+            ecam,
+            bus_start: BUS,
+            bus_end: BUS,
+            mem_pci: MEM_PCI,
+            mem_cpu: MEM_CPU,
+            mem_size: 32,
+            mem_next: 0,
+            map_mask: [0x1800, 0, 0, 7],
+            map: alloc::vec![entry(0, 1, 3), entry(2, 2, 6)],
+        };
+
+        let functions: alloc::vec::Vec<(u8, u8)> = host.functions().map(|f| (f.dev, f.func)).collect();
+        assert_eq!(functions, [(0, 0), (2, 0), (2, 1)]);
+
+        let bars = host.assign_bars(&dev0);
+        let cpu = MEM_CPU as usize;
+        assert_eq!(bars, [Some(cpu), Some(cpu + 16), None, None, None, None]);
+        assert_eq!(dev0.read_u32(CFG_BAR0), MEM_PCI as u32);
+        // What didn't fit is left as it was
+        assert_eq!(dev0.read_u32(CFG_BAR0 + 16), 0);
+        assert_eq!(dev0.read_u32(CFG_BAR0 + 20), 0);
+
+        let irqs: alloc::vec::Vec<_> = host.functions().map(|f| host.irq_for(&f)).collect();
+        assert_eq!(irqs, [Some((0, 3)), Some((0, 6)), None]);
+    }
+}
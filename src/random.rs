@@ -0,0 +1,235 @@
+//! Kernel randomness: an entropy pool feeding a ChaCha20 generator.
+//!
+//! The generator rekeys itself after every block, so earlier output can't
+//! be worked out from its state. It reseeds from the pool, topped up from
+//! the rng device or, without one, from timer jitter.
+
+use crate::exception::{interrupt_mask_get, with_intr_disabled};
+use crate::mutex::Mutex;
+use crate::timer;
+
+const RESEED_BYTES: usize = 1 << 20;
+const RESEED_TICKS: u64 = 600;
+const SEED_LEN: usize = 32;
+// DAIF.I
+const IRQ_MASKED: usize = 1 << 7;
+
+// Keep the pool, reseeding and output streams apart
+const POOL_NONCE: [u32; 3] = [0, 0, 1];
+const RESEED_NONCE: [u32; 3] = [0, 0, 2];
+const OUTPUT_NONCE: [u32; 3] = [0, 0, 3];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// One 64-byte ChaCha20 block, as in RFC 8439
+pub fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter;
+    state[13..].copy_from_slice(nonce);
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut block = [0; 64];
+    for i in 0..16 {
+        block[4 * i..4 * i + 4].copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+fn words(bytes: &[u8]) -> [u32; 8] {
+    let mut words = [0; 8];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        let mut le = [0; 4];
+        le[..chunk.len()].copy_from_slice(chunk);
+        *word = u32::from_le_bytes(le);
+    }
+    words
+}
+
+pub struct Csprng {
+    key: [u32; 8],
+    // Everything added since the last reseed
+    pool: [u32; 8],
+    pool_count: u32,
+    seeded: bool,
+    since_reseed: usize,
+    reseeded_at: u64,
+}
+
+impl Csprng {
+    pub const fn new() -> Csprng {
+        Csprng {
+            key: [0; 8],
+            pool: [0; 8],
+            pool_count: 0,
+            seeded: false,
+            since_reseed: 0,
+            reseeded_at: 0,
+        }
+    }
+
+    /// Stirs `data` into the pool. It only reaches the output on reseed.
+    pub fn add_entropy(&mut self, data: &[u8]) {
+        for chunk in data.chunks(SEED_LEN) {
+            for (p, w) in self.pool.iter_mut().zip(words(chunk).iter()) {
+                *p ^= w;
+            }
+            self.pool = words(&chacha20_block(&self.pool, self.pool_count, &POOL_NONCE)[..SEED_LEN]);
+            self.pool_count = self.pool_count.wrapping_add(1);
+        }
+    }
+
+    pub fn needs_reseed(&self, now: u64) -> bool {
+        !self.seeded || self.since_reseed >= RESEED_BYTES || now >= self.reseeded_at + RESEED_TICKS
+    }
+
+    /// Folds the pool and `seed` into the key
+    pub fn reseed(&mut self, seed: &[u8], now: u64) {
+        self.add_entropy(seed);
+        let mut key = self.key;
+        for (k, p) in key.iter_mut().zip(self.pool.iter()) {
+            *k ^= p;
+        }
+        self.key = words(&chacha20_block(&key, 0, &RESEED_NONCE)[..SEED_LEN]);
+        self.pool = [0; 8];
+        self.seeded = true;
+        self.since_reseed = 0;
+        self.reseeded_at = now;
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(SEED_LEN) {
+            let block = chacha20_block(&self.key, 0, &OUTPUT_NONCE);
+            self.key = words(&block[..SEED_LEN]);
+            chunk.copy_from_slice(&block[SEED_LEN..SEED_LEN + chunk.len()]);
+        }
+        self.since_reseed += buf.len();
+    }
+}
+
+/// Fills the buffer and says whether it could
+type Source = fn(&mut [u8]) -> bool;
+
+static RNG: Mutex<Csprng> = Mutex::new(Csprng::new());
+static SOURCE: Mutex<Option<Source>> = Mutex::new(None);
+
+/// Sets where reseeds come from
+pub fn set_source(source: Source) {
+    SOURCE.lock().replace(source);
+}
+
+/// Timer jitter, for when there is no rng device. Each output byte folds in
+/// the counter's low bits around a little busy work, several times over.
+/// Under emulation there is much less of it than on hardware.
+fn jitter(seed: &mut [u8]) {
+    let mut work = 0u64;
+    for byte in seed.iter_mut() {
+        for _ in 0..8 {
+            let start = timer::uptime();
+            for i in 0..64 {
+                work = work.rotate_left(5) ^ i;
+            }
+            let elapsed = (timer::uptime() - start).as_nanos() as u64 ^ work;
+            *byte = byte.rotate_left(3) ^ elapsed as u8 ^ (elapsed >> 8) as u8;
+        }
+    }
+}
+
+/// Adds whatever the caller knows to be unpredictable, e.g. interrupt times
+pub fn add_entropy(data: &[u8]) {
+    with_intr_disabled(|| RNG.lock().add_entropy(data));
+}
+
+/// Fills `buf` with random bytes. Safe to call from any thread or IRQ: with
+/// interrupts masked the device can't be waited on, so reseeds there come
+/// from timer jitter.
+pub fn getrandom(buf: &mut [u8]) {
+    let can_block = interrupt_mask_get() & IRQ_MASKED == 0;
+    let now = timer::current_ticks();
+    let mut due = false;
+    with_intr_disabled(|| due = RNG.lock().needs_reseed(now));
+
+    // The device is read without the generator locked
+    if due {
+        let mut seed = [0; SEED_LEN];
+        let mut source = None;
+        if can_block {
+            with_intr_disabled(|| source = *SOURCE.lock());
+        }
+        if !source.map_or(false, |read| read(&mut seed)) {
+            jitter(&mut seed);
+        }
+        with_intr_disabled(|| RNG.lock().reseed(&seed, now));
+    }
+    with_intr_disabled(|| RNG.lock().fill(buf));
+}
+
+pub fn random_u32() -> u32 {
+    let mut bytes = [0; 4];
+    getrandom(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    getrandom(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_chacha20_block() {
+        // RFC 8439, section 2.3.2
+        let key = words(&(0..32).collect::<alloc::vec::Vec<u8>>());
+        let nonce = [0x09000000, 0x4a000000, 0];
+        let block = chacha20_block(&key, 1, &nonce);
+        assert_eq!(&block[..8], &[0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15]);
+        assert_eq!(&block[56..], &[0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e]);
+    }
+
+    #[test_case]
+    fn test_csprng_reseeds() {
+        let mut rng = Csprng::new();
+        assert!(rng.needs_reseed(0));
+        rng.reseed(&[7; SEED_LEN], 0);
+        assert!(!rng.needs_reseed(1));
+
+        let (mut a, mut b) = ([0; 40], [0; 40]);
+        rng.fill(&mut a);
+        rng.fill(&mut b);
+        assert_ne!(a, b);
+
+        // The same seed gives the same stream
+        let mut again = Csprng::new();
+        again.reseed(&[7; SEED_LEN], 0);
+        let mut c = [0; 40];
+        again.fill(&mut c);
+        assert_eq!(a, c);
+
+        assert!(rng.needs_reseed(RESEED_TICKS));
+    }
+}
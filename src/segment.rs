@@ -0,0 +1,210 @@
+//! Segments: pages shared between containers. The labels decide at map time
+//! whether a container gets to read them, or to write them too. The MMU is
+//! off, so that decision is only enforced through the `Mapped` handle. It
+//! hands out raw pointers to the pages themselves rather than references,
+//! so any number of handles may be out on the same pages and nothing has to
+//! be copied between them.
+
+use crate::kobject::{Access, Container, KObjectRef, Label, Segment, KOBJ_NPAGES};
+use crate::mm::PAGE_SIZE;
+use crate::thread;
+
+/// Makes a segment labelled `label` of `npages` zeroed pages out of
+/// `ct_ref`'s. Fails, with nothing taken, if the caller may not write to the
+/// container, the container's label may not flow to `label` or there aren't
+/// the pages. Kernel objects can't be freed yet, so the pages stay the
+/// segment's for good.
+pub fn create(ct_ref: KObjectRef<Container>, label: &str, npages: usize) -> Option<KObjectRef<Segment>> {
+    use labeled::buckle2::Buckle2;
+    use labeled::Label as _;
+
+    // label checks
+    let local_alloc = thread::current_thread_koref()?.meta().alloc.clone();
+    let seg_label = Buckle2::parse_in(label, local_alloc).ok()?;
+    let ct_label = ct_ref.label()?;
+    if !thread::current_label()?.can_flow_to(&ct_label) || !ct_label.as_ref().inner.can_flow_to(&seg_label) {
+        return None;
+    }
+
+    let data = ct_ref.get_pages(npages)?;
+    let lb_page = match ct_ref.get_pages(2 * KOBJ_NPAGES) {
+        Some(page) => page,
+        None => {
+            unsafe { ct_ref.put_pages(data, npages) };
+            return None;
+        }
+    };
+    let seg_page = lb_page + KOBJ_NPAGES;
+
+    let lb_slot = ct_ref.as_mut().get_slot()?;
+    let lb_ref = unsafe { Label::create(lb_page, label) };
+    lb_ref.meta_mut().parent = Some(ct_ref);
+    ct_ref.as_mut().set_slot(lb_slot, lb_ref);
+
+    let seg_slot = ct_ref.as_mut().get_slot()?;
+    let seg_ref = unsafe { Segment::create(seg_page, data, npages) };
+    seg_ref.meta_mut().parent = Some(ct_ref);
+    seg_ref.meta_mut().label = Some(lb_ref);
+    ct_ref.as_mut().set_slot(seg_slot, seg_ref);
+
+    Some(seg_ref)
+}
+
+/// What a container labelled `ct` may do with a segment labelled `seg`: read
+/// it if the data may flow to the container, and write it as well if the
+/// flow goes both ways
+pub fn access_for(seg: &KObjectRef<Label>, ct: &KObjectRef<Label>) -> Option<Access> {
+    match (seg.can_flow_to(ct), ct.can_flow_to(seg)) {
+        (true, true) => Some(Access::ReadWrite),
+        (true, false) => Some(Access::ReadOnly),
+        _ => None,
+    }
+}
+
+/// A segment as one container sees it
+pub struct Mapped {
+    seg_ref: KObjectRef<Segment>,
+    ct_ref: KObjectRef<Container>,
+}
+
+impl Mapped {
+    /// None once the segment has been unmapped
+    pub fn access(&self) -> Option<Access> {
+        self.seg_ref.as_ref().mapping(self.ct_ref)
+    }
+
+    pub fn len(&self) -> usize {
+        self.seg_ref.as_ref().npages * PAGE_SIZE
+    }
+
+    fn start(&self) -> *mut u8 {
+        (self.seg_ref.as_ref().start * PAGE_SIZE) as *mut u8
+    }
+
+    /// The segment's first byte, for reading in place. Other handles may be
+    /// writing it at the same time.
+    pub fn as_ptr(&self) -> Option<*const u8> {
+        self.access()?;
+        Some(self.start())
+    }
+
+    /// The segment's first byte, for writing in place, if the container
+    /// may write
+    pub fn as_mut_ptr(&self) -> Option<*mut u8> {
+        self.access().filter(|&access| access == Access::ReadWrite)?;
+        Some(self.start())
+    }
+
+    // How much of `len` bytes from `offset` on is in the segment
+    fn clamp(&self, offset: usize, len: usize) -> usize {
+        core::cmp::min(len, self.len().saturating_sub(offset))
+    }
+
+    /// Copies from `offset` on into `buf`. Returns how much was copied.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        self.access()?;
+        let len = self.clamp(offset, buf.len());
+        unsafe { core::ptr::copy(self.start().wrapping_add(offset), buf.as_mut_ptr(), len) };
+        Some(len)
+    }
+
+    /// Copies `data` in from `offset` on, if the container may write.
+    /// Returns how much was copied.
+    pub fn write(&self, offset: usize, data: &[u8]) -> Option<usize> {
+        self.access().filter(|&access| access == Access::ReadWrite)?;
+        let len = self.clamp(offset, data.len());
+        unsafe { core::ptr::copy(data.as_ptr(), self.start().wrapping_add(offset), len) };
+        Some(len)
+    }
+}
+
+// Mapping for a container means seeing what it sees and writing what it
+// writes, so the caller has to be able to do both. Boot code, before there
+// are threads, may.
+fn may_act_for(ct_ref: KObjectRef<Container>) -> bool {
+    match (thread::current_label(), ct_ref.label()) {
+        (Some(caller), Some(ct)) => caller.can_flow_to(&ct) && ct.can_flow_to(&caller),
+        (Some(_), None) => false,
+        (None, _) => true,
+    }
+}
+
+/// Maps `seg_ref` into `ct_ref`, or fails if the container may not even
+/// read it, or the caller may not act for the container. Mapping again
+/// redoes the check.
+pub fn map(seg_ref: KObjectRef<Segment>, ct_ref: KObjectRef<Container>) -> Option<Mapped> {
+    if !may_act_for(ct_ref) {
+        return None;
+    }
+    let access = access_for(&seg_ref.label()?, &ct_ref.label()?);
+    seg_ref.as_mut().set_mapping(ct_ref, access);
+    access.map(|_| Mapped { seg_ref, ct_ref })
+}
+
+pub fn unmap(seg_ref: KObjectRef<Segment>, ct_ref: KObjectRef<Container>) {
+    if may_act_for(ct_ref) {
+        seg_ref.as_mut().set_mapping(ct_ref, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kobject::fixtures::{as_thread, container, labelled, pages};
+
+    #[test_case]
+    fn test_create_follows_flow() {
+        let public = container(None, "T,T", 8);
+        let secret = container(None, "gongqi,T", 8);
+        as_thread("T,T", || {
+            let seg_ref = create(public, "gongqi,T", 1).unwrap();
+            assert_eq!(seg_ref.meta().parent, Some(public));
+            // Nothing secret flows into a public segment
+            assert!(create(secret, "T,T", 1).is_none());
+            // Nor is anything taken when the pages run out
+            let free = public.meta().free_pages.len();
+            assert!(create(public, "T,T", free).is_none());
+            assert_eq!(public.meta().free_pages.len(), free);
+        });
+        // A secret thread may not write into a public container
+        as_thread("gongqi,T", || assert!(create(public, "gongqi,T", 1).is_none()));
+    }
+
+    #[test_case]
+    fn test_map_follows_flow() {
+        let seg_ref = labelled(unsafe { Segment::create(pages(KOBJ_NPAGES), pages(2), 2) }, "T,T");
+        let public = container(None, "T,T", 0);
+        let secret = container(None, "gongqi,T", 0);
+
+        let writer = map(seg_ref, public).unwrap();
+        let reader = map(seg_ref, secret).unwrap();
+        assert_eq!((writer.access(), reader.access()), (Some(Access::ReadWrite), Some(Access::ReadOnly)));
+
+        // Both see the same pages, but only one may write them
+        assert_eq!(writer.write(PAGE_SIZE, &[42, 43]), Some(2));
+        assert_eq!(reader.write(PAGE_SIZE, &[0]), None);
+        assert!(reader.as_mut_ptr().is_none());
+        assert_eq!(reader.as_ptr(), writer.as_mut_ptr().map(|ptr| ptr as *const u8));
+        unsafe { writer.as_mut_ptr().unwrap().add(PAGE_SIZE + 2).write(44) };
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(PAGE_SIZE, &mut buf), Some(4));
+        assert_eq!(buf, [42, 43, 44, 0]);
+        assert_eq!(reader.len(), 2 * PAGE_SIZE);
+        // Nothing past the end
+        assert_eq!(writer.write(2 * PAGE_SIZE - 1, &[1, 2]), Some(1));
+        assert_eq!(reader.read(3 * PAGE_SIZE, &mut buf), Some(0));
+
+        // A second handle on the same container is no trouble
+        let again = map(seg_ref, public).unwrap();
+        assert_eq!(again.read(PAGE_SIZE, &mut buf[..1]), Some(1));
+        assert_eq!(buf[0], 42);
+
+        unmap(seg_ref, public);
+        assert!(writer.write(0, &[1]).is_none());
+
+        // Nothing secret flows into a public container
+        let secret_seg = labelled(unsafe { Segment::create(pages(KOBJ_NPAGES), pages(1), 1) }, "gongqi,T");
+        assert!(map(secret_seg, public).is_none());
+        assert!(map(secret_seg, secret).is_some());
+    }
+}
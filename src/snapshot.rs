@@ -0,0 +1,387 @@
+//! Checkpoint of the container tree on the virtio-blk disk
+//!
+//! The last `SNAPSHOT_NSECTORS` sectors of the disk are reserved. Sector 0 of
+//! that region holds a header, the following sectors hold fixed-size records,
+//! one per kobject found by walking container slots from the root.
+//!
+//! Containers are restored with their label, description, pages and time
+//! slices, segments with their label and size but not their contents.
+//! Threads can't be restored since their code lives in closures, so a
+//! restored container has no scheduler until boot installs one.
+
+use core::mem::size_of;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::container;
+use crate::debug;
+use crate::kobject::{Container, Label, KObjectKind, KObjectPtr, KObjectRef, Segment, TimeSlice};
+use crate::kobject::{KOBJ_DESCR_LEN, KOBJ_NPAGES};
+use crate::segment;
+use crate::utils::*;
+use crate::virtio::VirtIOBlk;
+use crate::{ResourceBlock, RESBLOCKS, TS};
+
+const SECTOR_SIZE: usize = 512;
+pub const SNAPSHOT_NSECTORS: u64 = 128;
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"ALLORASN";
+const SNAPSHOT_VERSION: u32 = 2;
+const LABEL_LEN: usize = 64;
+
+const RECORDS_PER_SECTOR: usize = SECTOR_SIZE / size_of::<Record>();
+const MAX_RECORDS: usize = (SNAPSHOT_NSECTORS as usize - 1) * RECORDS_PER_SECTOR;
+const NO_PARENT: u32 = u32::MAX;
+
+type LEU32 = Endian<u32, Little>;
+type LEU64 = Endian<u64, Little>;
+
+#[derive(Debug)]
+pub enum Error {
+    DiskTooSmall,
+    NoSnapshot,
+    BadVersion(u32),
+    BadChecksum,
+    TooManyObjects(usize),
+    LabelTooLong(usize),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: [u8; 8],
+    version: LEU32,
+    nrecords: LEU32,
+    checksum: LEU32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Record {
+    kind: LEU32,
+    parent: LEU32, // record index of the container holding the slot
+    npages: LEU64,
+    nslices: LEU32,
+    time_quota: LEU32,
+    label: [u8; LABEL_LEN],
+    descr: [u8; KOBJ_DESCR_LEN],
+    _reserved: [u8; 8],
+}
+
+impl Record {
+    fn new(kind: KObjectKind, parent: u32) -> Self {
+        Record {
+            kind: kind_to_u32(kind).into(),
+            parent: parent.into(),
+            npages: 0.into(),
+            nslices: 0.into(),
+            time_quota: 0.into(),
+            label: [0; LABEL_LEN],
+            descr: [0; KOBJ_DESCR_LEN],
+            _reserved: [0; 8],
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Record>())
+        }
+    }
+}
+
+fn kind_to_u32(kind: KObjectKind) -> u32 {
+    match kind {
+        KObjectKind::None => 0,
+        KObjectKind::Container => 1,
+        KObjectKind::Label => 2,
+        KObjectKind::Thread => 3,
+        KObjectKind::TimeSlices => 4,
+        KObjectKind::Segment => 5,
+    }
+}
+
+fn kind_from_u32(kind: u32) -> KObjectKind {
+    match kind {
+        1 => KObjectKind::Container,
+        2 => KObjectKind::Label,
+        3 => KObjectKind::Thread,
+        4 => KObjectKind::TimeSlices,
+        5 => KObjectKind::Segment,
+        _ => KObjectKind::None,
+    }
+}
+
+fn buf_to_str(buf: &[u8]) -> &str {
+    core::str::from_utf8(buf)
+        .unwrap_or("")
+        .trim_end_matches(char::from(0))
+}
+
+// FNV-1a
+fn checksum(records: &[Record]) -> u32 {
+    records
+        .iter()
+        .flat_map(|r| r.as_bytes().iter())
+        .fold(0x811c9dc5u32, |hash, &b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+/// The first sector of the reserved region
+pub fn region_start(blk: &VirtIOBlk) -> Result<u64, Error> {
+    blk.capacity()
+        .checked_sub(SNAPSHOT_NSECTORS)
+        .ok_or(Error::DiskTooSmall)
+}
+
+// Labels are written out whole, since a cut-off label is a different label
+fn label_to_buf(lb_ref: KObjectRef<Label>) -> Result<[u8; LABEL_LEN], Error> {
+    let source = lb_ref.as_ref().source().as_bytes();
+    if source.len() > LABEL_LEN {
+        return Err(Error::LabelTooLong(source.len()));
+    }
+    let mut buf = [0; LABEL_LEN];
+    buf[..source.len()].copy_from_slice(source);
+    Ok(buf)
+}
+
+fn container_record(ct_ref: KObjectRef<Container>, parent: u32) -> Result<Record, Error> {
+    let mut record = Record::new(KObjectKind::Container, parent);
+    // Free pages and those its objects use, which restoring uses again
+    let npages = ct_ref.meta().free_pages.len() + ct_ref.as_ref().pages.used;
+    record.npages = (npages as u64).into();
+    record.nslices = (ct_ref
+        .as_ref()
+        .time_slices
+        .as_ref()
+        .map(|slices| {
+            slices
+                .iter()
+                .filter(|slice| matches!(slice, TimeSlice::Routine))
+                .count()
+        })
+        .unwrap_or(0) as u32)
+        .into();
+    record.time_quota = (RESBLOCKS
+        .lock()
+        .as_ref()
+        .and_then(|(rbs, _)| rbs.iter().find(|rb| rb.holder == ct_ref).map(|rb| rb.time_quota))
+        .unwrap_or(0) as u32)
+        .into();
+    if let Some(lb_ref) = ct_ref.label() {
+        record.label = label_to_buf(lb_ref)?;
+    }
+    record.descr = ct_ref.meta().descr;
+    Ok(record)
+}
+
+// Labels created alongside a container or a thread are recreated with it
+fn is_owned_label(ct_ref: KObjectRef<Container>, slot: KObjectPtr) -> bool {
+    ct_ref
+        .as_ref()
+        .slots
+        .iter()
+        .filter(|s| !s.is_null() && **s != slot)
+        .any(|&s| KObjectRef::<Container>::from(s).meta().label.map(KObjectPtr::from) == Some(slot))
+}
+
+fn collect(root: KObjectRef<Container>) -> Result<Vec<Record>, Error> {
+    let mut records = Vec::new();
+    let mut containers = Vec::new(); // (container, record index)
+
+    records.push(container_record(root, NO_PARENT)?);
+    containers.push((root, 0));
+
+    while let Some((ct_ref, index)) = containers.pop() {
+        for &slot in ct_ref.as_ref().slots.iter().filter(|s| !s.is_null()) {
+            let ko_ref = KObjectRef::<Container>::from(slot);
+            let meta = ko_ref.meta();
+            match meta.kind {
+                KObjectKind::Container => {
+                    containers.push((ko_ref, records.len()));
+                    records.push(container_record(ko_ref, index as u32)?);
+                }
+                KObjectKind::Label if is_owned_label(ct_ref, slot) => {}
+                kind => {
+                    let mut record = Record::new(kind, index as u32);
+                    record.descr = meta.descr;
+                    match kind {
+                        KObjectKind::Label => record.label = label_to_buf(KObjectRef::from(slot))?,
+                        _ => if let Some(lb_ref) = meta.label {
+                            record.label = label_to_buf(lb_ref)?;
+                        }
+                    }
+                    if kind == KObjectKind::Segment {
+                        let seg_ref = KObjectRef::<Segment>::from(slot);
+                        record.npages = (seg_ref.as_ref().npages as u64).into();
+                    }
+                    records.push(record);
+                }
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+pub fn save(root: KObjectRef<Container>, blk: &mut VirtIOBlk) -> Result<usize, Error> {
+    let start = region_start(blk)?;
+    let records = collect(root)?;
+    if records.len() > MAX_RECORDS {
+        return Err(Error::TooManyObjects(records.len()));
+    }
+
+    // Records go first so that a partial write never pairs a valid header
+    // with stale records
+    for (i, chunk) in records.chunks(RECORDS_PER_SECTOR).enumerate() {
+        let mut buf = [0u8; SECTOR_SIZE];
+        chunk.iter().enumerate().for_each(|(j, record)| unsafe {
+            (buf.as_mut_ptr() as *mut Record).add(j).write_unaligned(*record)
+        });
+        blk.write(start + 1 + i as u64, &buf);
+    }
+
+    let header = Header {
+        magic: SNAPSHOT_MAGIC,
+        version: SNAPSHOT_VERSION.into(),
+        nrecords: (records.len() as u32).into(),
+        checksum: checksum(&records).into(),
+    };
+    let mut buf = [0u8; SECTOR_SIZE];
+    unsafe { (buf.as_mut_ptr() as *mut Header).write_unaligned(header) };
+    blk.write(start, &buf);
+
+    Ok(records.len())
+}
+
+fn load(blk: &mut VirtIOBlk) -> Result<Vec<Record>, Error> {
+    let start = region_start(blk)?;
+    let mut buf = [0u8; SECTOR_SIZE];
+    blk.read(start, &mut buf);
+    let header = unsafe { (buf.as_ptr() as *const Header).read_unaligned() };
+    if header.magic != SNAPSHOT_MAGIC {
+        return Err(Error::NoSnapshot);
+    }
+    if header.version.native() != SNAPSHOT_VERSION {
+        return Err(Error::BadVersion(header.version.native()));
+    }
+    let nrecords = header.nrecords.native() as usize;
+    if nrecords == 0 || nrecords > MAX_RECORDS {
+        return Err(Error::TooManyObjects(nrecords));
+    }
+
+    let mut records = Vec::with_capacity(nrecords);
+    let mut sector = start + 1;
+    while records.len() < nrecords {
+        blk.read(sector, &mut buf);
+        let n = core::cmp::min(RECORDS_PER_SECTOR, nrecords - records.len());
+        (0..n).for_each(|j| unsafe {
+            records.push((buf.as_ptr() as *const Record).add(j).read_unaligned())
+        });
+        sector += 1;
+    }
+
+    if checksum(&records) != header.checksum.native() {
+        return Err(Error::BadChecksum);
+    }
+    Ok(records)
+}
+
+fn restore_container(parent: KObjectRef<Container>, record: &Record) -> KObjectRef<Container> {
+    let label = buf_to_str(&record.label);
+    let ct_ref = container::create(parent, label);
+    ct_ref.meta_mut().set_descr(buf_to_str(&record.descr));
+    container::add_known(parent, ct_ref);
+
+    let npages = record.npages.native() as usize;
+    if npages > 0 && container::move_npages(parent, ct_ref, npages).is_none() {
+        debug!("snapshot: no {} pages to give {}", npages, ct_ref.meta().descr());
+    }
+
+    let nslices = record.nslices.native() as usize;
+    if nslices > 0 {
+        let alloc = ct_ref.meta().alloc.clone();
+        let mut slices = Vec::new_in(alloc);
+        (0..nslices).for_each(|_| slices.push(TimeSlice::Routine));
+        ct_ref.as_mut().time_slices = Some(slices);
+    }
+
+    let time_quota = record.time_quota.native() as usize;
+    if time_quota > 0 {
+        RESBLOCKS.map(|(rbs, _)| rbs.push(ResourceBlock { holder: ct_ref, time_quota }));
+        TS.map(|ts| ts.push((ct_ref, 0)));
+    }
+
+    ct_ref
+}
+
+// The root container is set up at boot, so its record only anchors the tree.
+// Returns the number of containers recreated.
+pub fn restore(root: KObjectRef<Container>, blk: &mut VirtIOBlk) -> Result<usize, Error> {
+    let records = load(blk)?;
+
+    let mut containers = vec![None; records.len()];
+    containers[0] = Some(root);
+    let mut restored = 0;
+    let mut skipped = 0;
+
+    for (i, record) in records.iter().enumerate().skip(1) {
+        // parents always come before their slots
+        let parent = match containers.get(record.parent.native() as usize) {
+            Some(&Some(parent)) => parent,
+            _ => continue,
+        };
+
+        match kind_from_u32(record.kind.native()) {
+            KObjectKind::Container => {
+                containers[i] = Some(restore_container(parent, record));
+                restored += 1;
+            }
+            KObjectKind::Label => {
+                let lb_slot = parent.as_mut().get_slot().unwrap();
+                let lb_page = parent.get_pages(KOBJ_NPAGES).unwrap();
+                let lb_ref = unsafe { Label::create(lb_page, buf_to_str(&record.label)) };
+                lb_ref.meta_mut().parent = Some(parent);
+                parent.as_mut().set_slot(lb_slot, lb_ref);
+            }
+            KObjectKind::Segment => {
+                let npages = record.npages.native() as usize;
+                if segment::create(parent, buf_to_str(&record.label), npages).is_none() {
+                    skipped += 1;
+                }
+            }
+            _ => skipped += 1,
+        }
+    }
+
+    if skipped > 0 {
+        debug!("snapshot: {} objects can't be restored and were skipped", skipped);
+    }
+
+    Ok(restored)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_snapshot_record_layout() {
+        assert_eq!(size_of::<Record>() * RECORDS_PER_SECTOR, SECTOR_SIZE);
+        assert!(size_of::<Header>() <= SECTOR_SIZE);
+
+        let mut record = Record::new(KObjectKind::Container, 3);
+        record.npages = 100.into();
+        record.label[..5].copy_from_slice(b"T,F\0\0");
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        unsafe { (buf.as_mut_ptr() as *mut Record).add(1).write_unaligned(record) };
+        let read = unsafe { (buf.as_ptr() as *const Record).add(1).read_unaligned() };
+
+        assert_eq!(kind_from_u32(read.kind.native()), KObjectKind::Container);
+        assert_eq!(read.parent.native(), 3);
+        assert_eq!(read.npages.native(), 100);
+        assert_eq!(buf_to_str(&read.label), "T,F");
+        assert_eq!(checksum(&[record]), checksum(&[read]));
+    }
+}
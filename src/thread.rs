@@ -31,7 +31,7 @@ pub fn spawn_raw<F: FnOnce() + 'static>(ct_ref: KObjectRef<Container>, label: &s
     }
 
     let lb_slot = ct_ref.as_mut().get_slot().unwrap();
-    let lb_page_id = ct_ref.meta_mut().free_pages.get_multiple(KOBJ_NPAGES).unwrap();
+    let lb_page_id = ct_ref.get_pages(KOBJ_NPAGES).unwrap();
     let lb_ref = unsafe {
         Label::create(lb_page_id, label)
     };
@@ -39,7 +39,7 @@ pub fn spawn_raw<F: FnOnce() + 'static>(ct_ref: KObjectRef<Container>, label: &s
     ct_ref.as_mut().set_slot(lb_slot, lb_ref);
 
     let th_slot = ct_ref.as_mut().get_slot().unwrap();
-    let th_page_id = ct_ref.meta_mut().free_pages.get_multiple(THREAD_NPAGES).unwrap();
+    let th_page_id = ct_ref.get_pages(THREAD_NPAGES).unwrap();
     let th_ref = unsafe {
         Thread::create(th_page_id, move || { f(); cpu_idle!(); })
     };
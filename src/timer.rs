@@ -33,6 +33,16 @@ pub fn current_ticks() -> u64 {
     TICK_COUNT.load(Ordering::SeqCst)
 }
 
+/// Time since the counter started, finer than the ticks
+pub fn uptime() -> time::Duration {
+    let count: u64;
+    unsafe {
+        asm!("mrs {}, CNTPCT_EL0", out(reg) count);
+    }
+    let freq = SYS_FREQ as u64;
+    time::Duration::new(count / freq, ((count % freq) * 1_000_000_000 / freq) as u32)
+}
+
 pub fn convert_to_ticks(duration: time::Duration) -> u64 {
     duration.as_millis() as u64 / 1000 * TIMER_FREQ as u64
 }
@@ -48,6 +58,8 @@ pub fn spin_wait(duration: time::Duration) {
 
 pub fn tick() -> u64 {
     let count = TICK_COUNT.fetch_add(1, Ordering::Relaxed);
+    // When exactly the tick lands wobbles a little
+    crate::random::add_entropy(&(uptime().as_nanos() as u64).to_le_bytes());
     reset_timer();
     count
 }
@@ -128,6 +128,9 @@ pub fn wfi() {
     }
 }
 
+// Only the boot core is brought up so far
+pub const ONLINE_CORES: usize = 1;
+
 pub fn current_core() -> usize {
     let core: usize;
     unsafe {
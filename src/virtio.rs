@@ -1,12 +1,27 @@
 use crate::utils::*;
+use core::alloc::Layout;
+use core::ptr::{read_volatile, write_volatile};
 
+use alloc::alloc::alloc_zeroed;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+mod balloon;
 mod blk;
+mod console;
 mod entropy;
 mod net;
+mod ninep;
+mod pci;
 
+pub use balloon::{VirtIOBalloon, BalloonStat, BALLOON_NSTATS};
 pub use blk::VirtIOBlk;
+pub use console::{VirtIOConsole, ConsolePort, ControlBuf};
+pub use console::{CONSOLE_CONTROL_NBUFS, CONSOLE_MAX_PORTS};
 pub use entropy::VirtIOEntropy;
-pub use net::VirtIONet;
+pub use net::{VirtIONet, SendError, TxChecksum, FRAME_LEN};
+pub use ninep::VirtIO9P;
+pub use pci::{device_id as pci_device_id, PciTransport};
 
 #[derive(Debug)]
 pub enum Status {
@@ -31,7 +46,7 @@ impl Into<Endian<u32, Little>> for Status {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum DeviceId {
     Invalid = 0,
     Net = 1,
@@ -45,6 +60,23 @@ pub enum DeviceId {
     NinePTransport = 9,
 }
 
+impl From<u32> for DeviceId {
+    fn from(id: u32) -> DeviceId {
+        match id {
+            1 => DeviceId::Net,
+            2 => DeviceId::Blk,
+            3 => DeviceId::Console,
+            4 => DeviceId::Entropy,
+            5 => DeviceId::MemoryBalloon,
+            6 => DeviceId::IOMemory,
+            7 => DeviceId::RPMSG,
+            8 => DeviceId::SCSIHost,
+            9 => DeviceId::NinePTransport,
+            _ => DeviceId::Invalid,
+        }
+    }
+}
+
 type LEU32 = Endian<u32, Little>;
 type LEU64 = Endian<u64, Little>;
 
@@ -88,6 +120,21 @@ pub struct VirtIORegs<C = LEU64> {
 
 const MAGIC: u32 = 0x74726976;
 
+// Feature bits handled by VirtQueue itself
+pub const VIRTIO_F_INDIRECT_DESC: u32 = 1 << 28;
+pub const VIRTIO_F_EVENT_IDX: u32 = 1 << 29;
+const VIRTQ_FEATURES: u32 = VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX;
+// Bit 32, in the second feature word. Devices without legacy support
+// refuse a driver that doesn't accept it.
+const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
 #[derive(Copy, Clone)]
 #[repr(C, align(16))]
 pub struct VirtQDesc {
@@ -108,74 +155,315 @@ impl VirtQDesc {
     }
 }
 
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct VirtQUsedElement {
+    id: LEU32,
+    len: LEU32,
+}
+
+/// One buffer of a descriptor chain
 #[derive(Copy, Clone)]
-#[repr(C, align(2))]
-pub struct VirtqAvailable {
-    flags: Endian<u16, Little>,
-    idx: Endian<u16, Little>,
-    ring: [Endian<u16, Little>; 128],
-    used_event: Endian<u16, Little>,
+pub struct Buffer {
+    addr: u64,
+    len: u32,
+    writable: bool,
 }
 
-impl VirtqAvailable {
-    pub const fn empty() -> VirtqAvailable {
-        VirtqAvailable {
-            flags: Endian::from_raw(0),
-            idx: Endian::from_raw(0),
-            ring: [Endian::from_raw(0); 128],
-            used_event: Endian::from_raw(0),
+impl Buffer {
+    /// A buffer the device only reads
+    pub fn readable<T: ?Sized>(data: &T) -> Buffer {
+        Buffer {
+            addr: data as *const T as *const u8 as u64,
+            len: core::mem::size_of_val(data) as u32,
+            writable: false,
         }
     }
-}
 
-#[derive(Copy, Clone, Default)]
-#[repr(C, packed)]
-struct VirtQUsedElement {
-    id: Endian<u16, Little>,
-    len: Endian<u16, Little>,
-}
+    /// A buffer the device writes into
+    pub fn writable<T: ?Sized>(data: &mut T) -> Buffer {
+        Buffer {
+            addr: data as *mut T as *mut u8 as u64,
+            len: core::mem::size_of_val(data) as u32,
+            writable: true,
+        }
+    }
 
-impl VirtQUsedElement {
-    pub const fn empty() -> VirtQUsedElement {
-        VirtQUsedElement {
-            id: Endian::from_raw(0),
-            len: Endian::from_raw(0),
+    fn desc(&self, next: Option<u16>) -> VirtQDesc {
+        let mut flags = 0;
+        if self.writable {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        if next.is_some() {
+            flags |= VIRTQ_DESC_F_NEXT;
+        }
+        VirtQDesc {
+            addr: self.addr.into(),
+            len: self.len.into(),
+            flags: flags.into(),
+            next: next.unwrap_or(0).into(),
         }
     }
 }
 
-#[derive(Copy, Clone)]
-#[repr(C, align(4))]
-pub struct VirtQUsed {
-    flags: Endian<u16, Little>,
-    idx: Endian<u16, Little>,
-    ring: [VirtQUsedElement; 128],
-    avail_event: Endian<u16, Little>,
+// Whether the other side asked to be notified once the index moves from
+// `old` to `new`
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
 }
 
-impl VirtQUsed {
-    pub const fn empty() -> VirtQUsed {
-        VirtQUsed {
-            flags: Endian::from_raw(0),
-            idx: Endian::from_raw(0),
-            ring: [VirtQUsedElement::empty(); 128],
-            avail_event: Endian::from_raw(0),
+/// A split virtqueue whose rings are sized against the device's
+/// `queue_num_max`
+pub struct VirtQueue {
+    index: u32,
+    size: u16,
+    desc: *mut VirtQDesc,
+    // flags, idx, ring[size], used_event
+    avail: *mut Endian<u16, Little>,
+    // flags, idx, ring[size], avail_event
+    used: *mut u8,
+    free_head: u16,
+    num_free: u16,
+    last_used: u16,
+    kicked: u16,
+    event_idx: bool,
+    indirect: bool,
+    indirect_tables: Vec<Option<Box<[VirtQDesc]>>>,
+}
+
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Sets up queue `index` with at most `size` entries. `features` are the
+    /// negotiated feature bits. Returns None if the device has no such queue.
+    pub fn new<C>(
+        regs: &mut Transport<C>,
+        index: u32,
+        size: u16,
+        features: u32,
+    ) -> Option<VirtQueue> {
+        let max = regs.queue_max(index);
+        if max == 0 {
+            return None;
         }
+        let queue = Self::alloc(index, core::cmp::min(max, size as u32) as u16, features);
+        regs.setup_queue(
+            index,
+            queue.size,
+            queue.desc as usize,
+            queue.avail as usize,
+            queue.used as usize,
+        );
+        Some(queue)
     }
-}
 
-pub struct Queue<const S: usize> {
-    pub descriptors: [VirtQDesc; S],
-    pub available: VirtqAvailable,
-    pub used: VirtQUsed,
-}
+    fn alloc(index: u32, size: u16, features: u32) -> VirtQueue {
+        let n = size as usize;
+        let (desc, avail, used) = unsafe {
+            (
+                alloc_zeroed(Layout::from_size_align(16 * n, 16).unwrap()) as *mut VirtQDesc,
+                alloc_zeroed(Layout::from_size_align(6 + 2 * n, 2).unwrap()) as *mut Endian<u16, Little>,
+                alloc_zeroed(Layout::from_size_align(6 + 8 * n, 4).unwrap()),
+            )
+        };
+        if desc.is_null() || avail.is_null() || used.is_null() {
+            panic!("Couldn't allocate virtqueue {}", index);
+        }
 
-impl<const S: usize> Queue<S> {
-    pub const fn new() -> Self {
-        Queue {
-            descriptors: [VirtQDesc::empty(); S],
-            available: VirtqAvailable::empty(),
-            used: VirtQUsed::empty(),
+        // Thread the free descriptors through their next fields
+        (0..size).for_each(|i| unsafe {
+            (*desc.add(i as usize)).next = i.wrapping_add(1).into();
+        });
+
+        VirtQueue {
+            index,
+            size,
+            desc,
+            avail,
+            used,
+            free_head: 0,
+            num_free: size,
+            last_used: 0,
+            kicked: 0,
+            event_idx: features & VIRTIO_F_EVENT_IDX != 0,
+            indirect: features & VIRTIO_F_INDIRECT_DESC != 0,
+            indirect_tables: (0..n).map(|_| None).collect(),
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    fn avail_idx(&self) -> u16 {
+        unsafe { read_volatile(self.avail.add(1)).native() }
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { read_volatile((self.used as *const Endian<u16, Little>).add(1)).native() }
+    }
+
+    fn used_elem(&self, i: u16) -> VirtQUsedElement {
+        unsafe {
+            let ring = self.used.add(4) as *const VirtQUsedElement;
+            read_volatile(ring.add((i % self.size) as usize))
+        }
+    }
+
+    fn alloc_desc(&mut self) -> u16 {
+        let id = self.free_head;
+        self.free_head = unsafe { read_volatile(self.desc.add(id as usize)).next.native() };
+        self.num_free -= 1;
+        id
+    }
+
+    /// Makes a descriptor chain out of `bufs` available to the device, using
+    /// an indirect table when negotiated. Returns the head descriptor, which
+    /// `pop_used` hands back once the device is done. The buffers have to
+    /// stay alive until then.
+    pub fn add(&mut self, bufs: &[Buffer]) -> Option<u16> {
+        let nbufs = bufs.len();
+        if nbufs == 0 || self.num_free == 0 {
+            return None;
+        }
+
+        let head = if self.indirect && nbufs > 1 {
+            let table: Box<[VirtQDesc]> = bufs
+                .iter()
+                .enumerate()
+                .map(|(i, buf)| buf.desc(if i + 1 < nbufs { Some(i as u16 + 1) } else { None }))
+                .collect();
+            let head = self.alloc_desc();
+            unsafe {
+                write_volatile(
+                    self.desc.add(head as usize),
+                    VirtQDesc {
+                        addr: (table.as_ptr() as u64).into(),
+                        len: ((nbufs * core::mem::size_of::<VirtQDesc>()) as u32).into(),
+                        flags: VIRTQ_DESC_F_INDIRECT.into(),
+                        next: 0.into(),
+                    },
+                );
+            }
+            self.indirect_tables[head as usize] = Some(table);
+            head
+        } else {
+            if (self.num_free as usize) < nbufs {
+                return None;
+            }
+            let head = self.free_head;
+            for (i, buf) in bufs.iter().enumerate() {
+                let id = self.alloc_desc();
+                let next = if i + 1 < nbufs { Some(self.free_head) } else { None };
+                unsafe { write_volatile(self.desc.add(id as usize), buf.desc(next)) };
+            }
+            head
+        };
+
+        let idx = self.avail_idx();
+        unsafe {
+            write_volatile(self.avail.add(2 + (idx % self.size) as usize), head.into());
+            mb();
+            write_volatile(self.avail.add(1), idx.wrapping_add(1).into());
+        }
+        Some(head)
+    }
+
+    /// Notifies the device of new buffers, unless it asked not to be
+    pub fn kick<C>(&mut self, regs: &mut Transport<C>) {
+        mb();
+        let new = self.avail_idx();
+        let old = self.kicked;
+        self.kicked = new;
+
+        let notify = unsafe {
+            if self.event_idx {
+                let avail_event = self.used.add(4 + 8 * self.size as usize) as *const Endian<u16, Little>;
+                need_event(read_volatile(avail_event).native(), new, old)
+            } else {
+                read_volatile(self.used as *const Endian<u16, Little>).native()
+                    & VIRTQ_USED_F_NO_NOTIFY == 0
+            }
+        };
+        if notify {
+            regs.notify(self.index);
+        }
+    }
+
+    /// Whether the device has handed back buffers not yet reaped
+    pub fn has_used(&self) -> bool {
+        self.used_idx() != self.last_used
+    }
+
+    /// Reaps one used chain, returning its head and the number of bytes the
+    /// device wrote
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        mb();
+        let elem = self.used_elem(self.last_used);
+        self.last_used = self.last_used.wrapping_add(1);
+        if self.event_idx {
+            // Interrupt again as soon as anything else is used
+            unsafe {
+                write_volatile(self.avail.add(2 + self.size as usize), self.last_used.into());
+            }
+        }
+
+        let head = elem.id.native() as u16;
+        self.free_chain(head);
+        Some((head, elem.len.native()))
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        self.indirect_tables[head as usize] = None;
+        let mut last = head;
+        loop {
+            self.num_free += 1;
+            let desc = unsafe { read_volatile(self.desc.add(last as usize)) };
+            if desc.flags.native() & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            last = desc.next.native();
+        }
+        unsafe { (*self.desc.add(last as usize)).next = self.free_head.into() };
+        self.free_head = head;
+    }
+
+    /// Spins until the device hands back a chain
+    pub fn wait_used<C>(&mut self, regs: &mut Transport<C>, irq: &crate::gic::GIC) -> (u16, u32) {
+        irq.enable();
+        let used = loop {
+            if let Some(used) = self.pop_used() {
+                break used;
+            }
+            //asm!("wfi");
+            regs.ack_interrupt();
+        };
+        irq.disable();
+        used
+    }
+
+    /// Submits one chain and waits for the device to finish it. Returns the
+    /// number of bytes written.
+    pub fn transfer<C>(
+        &mut self,
+        regs: &mut Transport<C>,
+        irq: &crate::gic::GIC,
+        bufs: &[Buffer],
+    ) -> u32 {
+        let head = self.add(bufs).expect("virtqueue full");
+        self.kick(regs);
+        loop {
+            let (id, len) = self.wait_used(regs, irq);
+            if id == head {
+                break len;
+            }
         }
     }
 }
@@ -194,17 +482,253 @@ impl<C> VirtIORegs<C> {
     }
 
     pub fn device_id(&self) -> DeviceId {
-        match self.device_id.native() {
-            1 => DeviceId::Net,
-            2 => DeviceId::Blk,
-            3 => DeviceId::Console,
-            4 => DeviceId::Entropy,
-            5 => DeviceId::MemoryBalloon,
-            6 => DeviceId::IOMemory,
-            7 => DeviceId::RPMSG,
-            8 => DeviceId::SCSIHost,
-            9 => DeviceId::NinePTransport,
-            _ => DeviceId::Invalid,
+        DeviceId::from(self.device_id.native())
+    }
+
+    fn status(&self) -> u32 {
+        unsafe { read_volatile(&self.status).native() }
+    }
+
+    fn set_status(&mut self, status: u32) {
+        unsafe { write_volatile(&mut self.status, status.into()) }
+    }
+
+    fn device_features(&mut self, word: u32) -> u32 {
+        unsafe {
+            write_volatile(&mut self.device_features_sel, word.into());
+            read_volatile(&self.device_features).native()
+        }
+    }
+
+    fn set_driver_features(&mut self, word: u32, features: u32) {
+        unsafe {
+            write_volatile(&mut self.driver_features_sel, word.into());
+            write_volatile(&mut self.driver_features, features.into());
+        }
+    }
+
+    pub fn ack_interrupt(&mut self) -> u32 {
+        unsafe {
+            let status = read_volatile(&self.interrupt_status);
+            if status.native() != 0 {
+                write_volatile(&mut self.interrupt_ack, status);
+            }
+            status.native()
+        }
+    }
+
+    fn queue_max(&mut self, index: u32) -> u32 {
+        unsafe {
+            write_volatile(&mut self.queue_sel, index.into());
+            if read_volatile(&self.queue_ready).native() != 0 {
+                return 0;
+            }
+            read_volatile(&self.queue_num_max).native()
+        }
+    }
+
+    fn setup_queue(&mut self, index: u32, size: u16, desc: usize, avail: usize, used: usize) {
+        unsafe {
+            write_volatile(&mut self.queue_sel, index.into());
+            write_volatile(&mut self.queue_num, (size as u32).into());
+            write_volatile(&mut self.queue_desc_low, (desc as u32).into());
+            write_volatile(&mut self.queue_desc_high, ((desc >> 32) as u32).into());
+            write_volatile(&mut self.queue_avail_low, (avail as u32).into());
+            write_volatile(&mut self.queue_avail_high, ((avail >> 32) as u32).into());
+            write_volatile(&mut self.queue_used_low, (used as u32).into());
+            write_volatile(&mut self.queue_used_high, ((used >> 32) as u32).into());
+            write_volatile(&mut self.queue_ready, 1.into());
+        }
+    }
+}
+
+/// A device's registers, behind either virtio-mmio or virtio-pci
+pub enum Transport<'a, C = LEU64> {
+    Mmio(&'a mut VirtIORegs<C>),
+    Pci(PciTransport<C>),
+}
+
+impl<'a, C> Transport<'a, C> {
+    pub fn device_id(&self) -> DeviceId {
+        match self {
+            Transport::Mmio(regs) => regs.device_id(),
+            Transport::Pci(pci) => pci.device_id(),
+        }
+    }
+
+    /// Reinterprets the device-specific configuration as `D`
+    pub fn cast<D>(self) -> Transport<'a, D> {
+        match self {
+            Transport::Mmio(regs) => {
+                Transport::Mmio(unsafe { &mut *(regs as *mut VirtIORegs<C> as *mut VirtIORegs<D>) })
+            }
+            Transport::Pci(pci) => Transport::Pci(pci.cast()),
+        }
+    }
+
+    fn status(&self) -> u32 {
+        match self {
+            Transport::Mmio(regs) => regs.status(),
+            Transport::Pci(pci) => pci.status(),
+        }
+    }
+
+    fn set_status(&mut self, status: u32) {
+        match self {
+            Transport::Mmio(regs) => regs.set_status(status),
+            Transport::Pci(pci) => pci.set_status(status),
+        }
+    }
+
+    fn device_features(&mut self, word: u32) -> u32 {
+        match self {
+            Transport::Mmio(regs) => regs.device_features(word),
+            Transport::Pci(pci) => pci.device_features(word),
+        }
+    }
+
+    fn set_driver_features(&mut self, word: u32, features: u32) {
+        match self {
+            Transport::Mmio(regs) => regs.set_driver_features(word, features),
+            Transport::Pci(pci) => pci.set_driver_features(word, features),
+        }
+    }
+
+    /// Resets the device and negotiates `features`, along with whatever
+    /// VirtQueue supports and VIRTIO_F_VERSION_1. Returns the accepted
+    /// feature bits of the first word.
+    pub fn negotiate(&mut self, features: u32) -> u32 {
+        let mut status = Status::Acknowledge as u32;
+        self.set_status(Status::Reset as u32);
+        self.set_status(status);
+        status |= Status::Driver as u32;
+        self.set_status(status);
+
+        let accepted = (features | VIRTQ_FEATURES) & self.device_features(0);
+        let accepted_high = VIRTIO_F_VERSION_1 & self.device_features(1);
+        self.set_driver_features(0, accepted);
+        self.set_driver_features(1, accepted_high);
+
+        status |= Status::FeaturesOk as u32;
+        self.set_status(status);
+        if self.status() & (Status::FeaturesOk as u32) == 0 {
+            panic!("Couldn't set {:?} features", self.device_id());
+        }
+        accepted
+    }
+
+    /// Whether `negotiate` settled on VIRTIO_F_VERSION_1, which it takes
+    /// whenever the device offers it
+    pub fn version_1(&mut self) -> bool {
+        self.device_features(1) & VIRTIO_F_VERSION_1 != 0
+    }
+
+    /// Tells the device the driver is done setting up its queues
+    pub fn driver_ok(&mut self) {
+        let status = self.status() | Status::DriverOk as u32;
+        self.set_status(status);
+    }
+
+    pub fn ack_interrupt(&mut self) -> u32 {
+        match self {
+            Transport::Mmio(regs) => regs.ack_interrupt(),
+            Transport::Pci(pci) => pci.ack_interrupt(),
+        }
+    }
+
+    /// The device-specific configuration, if the device has one
+    pub fn config(&self) -> Option<&C> {
+        match self {
+            Transport::Mmio(regs) => Some(&regs.config),
+            Transport::Pci(pci) => pci.config().map(|config| unsafe { &*config }),
+        }
+    }
+
+    pub fn config_mut(&mut self) -> Option<&mut C> {
+        match self {
+            Transport::Mmio(regs) => Some(&mut regs.config),
+            Transport::Pci(pci) => pci.config().map(|config| unsafe { &mut *config }),
+        }
+    }
+
+    fn queue_max(&mut self, index: u32) -> u32 {
+        match self {
+            Transport::Mmio(regs) => regs.queue_max(index),
+            Transport::Pci(pci) => pci.queue_max(index),
+        }
+    }
+
+    fn setup_queue(&mut self, index: u32, size: u16, desc: usize, avail: usize, used: usize) {
+        match self {
+            Transport::Mmio(regs) => regs.setup_queue(index, size, desc, avail, used),
+            Transport::Pci(pci) => pci.setup_queue(index, size, desc, avail, used),
+        }
+    }
+
+    fn notify(&mut self, index: u32) {
+        match self {
+            Transport::Mmio(regs) => unsafe {
+                write_volatile(&mut regs.queue_notify, index.into())
+            },
+            Transport::Pci(pci) => pci.notify(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Plays the device: marks the chain at `head` as used
+    fn complete(queue: &mut VirtQueue, head: u16, len: u32) {
+        let idx = queue.used_idx();
+        unsafe {
+            let ring = queue.used.add(4) as *mut VirtQUsedElement;
+            write_volatile(
+                ring.add((idx % queue.size) as usize),
+                VirtQUsedElement { id: (head as u32).into(), len: len.into() },
+            );
+            write_volatile((queue.used as *mut Endian<u16, Little>).add(1), idx.wrapping_add(1).into());
         }
     }
+
+    #[test_case]
+    fn test_virtqueue_chains() {
+        let mut queue = VirtQueue::alloc(0, 8, 0);
+        let (hdr, mut data, mut status) = ([0u8; 16], [0u8; 512], 0u8);
+        let bufs = [Buffer::readable(&hdr), Buffer::writable(&mut data), Buffer::writable(&mut status)];
+
+        let first = queue.add(&bufs).unwrap();
+        let second = queue.add(&bufs).unwrap();
+        assert_eq!(queue.num_free(), 2);
+        assert!(queue.add(&bufs).is_none());
+        assert_eq!(queue.avail_idx(), 2);
+
+        complete(&mut queue, second, 513);
+        assert_eq!(queue.pop_used(), Some((second, 513)));
+        assert_eq!(queue.num_free(), 5);
+        assert!(queue.pop_used().is_none());
+
+        complete(&mut queue, first, 1);
+        assert_eq!(queue.pop_used(), Some((first, 1)));
+        assert_eq!(queue.num_free(), 8);
+    }
+
+    #[test_case]
+    fn test_virtqueue_indirect_and_event_idx() {
+        let mut queue = VirtQueue::alloc(0, 4, VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX);
+        let (req, mut resp) = ([1u8; 8], [0u8; 8]);
+
+        let head = queue.add(&[Buffer::readable(&req), Buffer::writable(&mut resp)]).unwrap();
+        assert_eq!(queue.num_free(), 3);
+
+        complete(&mut queue, head, 8);
+        assert_eq!(queue.pop_used(), Some((head, 8)));
+        assert_eq!(queue.num_free(), 4);
+        assert!(queue.indirect_tables.iter().all(|t| t.is_none()));
+
+        assert!(need_event(0, 1, 0));
+        assert!(!need_event(5, 3, 2));
+        assert!(need_event(0xffff, 1, 0xfffe));
+    }
 }
@@ -0,0 +1,235 @@
+use crate::utils::*;
+use core::ptr::{read_volatile, write_volatile};
+
+use alloc::vec::Vec;
+
+use super::{Buffer, Transport, VirtQueue, LEU32, QUEUE_SIZE_MAX};
+use crate::kobject::{Container, KObjectRef};
+
+const BALLOON_F_STATS_VQ: u32 = 1 << 1;
+const BALLOON_DEVICE_FEATURES: u32 = BALLOON_F_STATS_VQ;
+
+const INFLATE_QUEUE: u32 = 0;
+const DEFLATE_QUEUE: u32 = 1;
+const STATS_QUEUE: u32 = 2;
+
+// Interrupt status bit for a change to the device configuration
+const INTERRUPT_CONFIG: u32 = 1 << 1;
+
+// Max page frame numbers sent in one request
+const PFNS_PER_REQUEST: usize = 256;
+
+// Stats tags
+pub const STAT_MEMFREE: u16 = 4;
+pub const STAT_AVAIL: u16 = 6;
+pub const BALLOON_NSTATS: usize = 2;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct VirtIOBalloonConfig {
+    pub num_pages: LEU32,
+    pub actual: LEU32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct BalloonStat {
+    tag: Endian<u16, Little>,
+    val: Endian<u64, Little>,
+}
+
+impl BalloonStat {
+    pub const fn empty() -> BalloonStat {
+        BalloonStat { tag: Endian::from_raw(0), val: Endian::from_raw(0) }
+    }
+}
+
+pub struct VirtIOBalloon<'a> {
+    regs: Transport<'a, VirtIOBalloonConfig>,
+    inflate_queue: VirtQueue,
+    deflate_queue: VirtQueue,
+    stats_queue: Option<VirtQueue>,
+    stats: &'a mut [BalloonStat; BALLOON_NSTATS],
+    pages: Vec<u32>, // page frame numbers currently given to the host
+    container: Option<KObjectRef<Container>>, // where those pages come from
+    irq: crate::gic::GIC,
+}
+
+impl<'a> VirtIOBalloon<'a> {
+    pub fn new(
+        mut regs: Transport<'a, VirtIOBalloonConfig>,
+        stats: &'a mut [BalloonStat; BALLOON_NSTATS],
+        irq: crate::gic::GIC,
+    ) -> Self {
+        let features = regs.negotiate(BALLOON_DEVICE_FEATURES);
+        let inflate_queue = VirtQueue::new(&mut regs, INFLATE_QUEUE, QUEUE_SIZE_MAX, features).expect("balloon has no inflate queue");
+        let deflate_queue = VirtQueue::new(&mut regs, DEFLATE_QUEUE, QUEUE_SIZE_MAX, features).expect("balloon has no deflate queue");
+        let stats_queue = if features & BALLOON_F_STATS_VQ != 0 {
+            VirtQueue::new(&mut regs, STATS_QUEUE, QUEUE_SIZE_MAX, features)
+        } else {
+            None
+        };
+        regs.driver_ok();
+
+        let mut balloon = VirtIOBalloon {
+            regs,
+            inflate_queue,
+            deflate_queue,
+            stats_queue,
+            stats,
+            pages: Vec::new(),
+            container: None,
+            irq,
+        };
+
+        // The device asks for stats by returning this buffer, so one has to
+        // be queued from the start
+        balloon.post_stats();
+
+        balloon
+    }
+}
+
+impl<'a> VirtIOBalloon<'a> {
+    /// Number of pages the host wants in the balloon
+    pub fn target(&self) -> usize {
+        self.regs
+            .config()
+            .map_or(0, |config| unsafe { read_volatile(&config.num_pages).native() as usize })
+    }
+
+    /// Number of pages currently in the balloon
+    pub fn actual(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn send_pfns(&mut self, qnum: u32, pfns: &[u32]) {
+        let queue = match qnum {
+            INFLATE_QUEUE => &mut self.inflate_queue,
+            _ => &mut self.deflate_queue,
+        };
+        queue.transfer(&mut self.regs, &self.irq, &[Buffer::readable(pfns)]);
+    }
+
+    fn report_actual(&mut self) {
+        let actual = self.pages.len() as u32;
+        if let Some(config) = self.regs.config_mut() {
+            unsafe { write_volatile(&mut config.actual, actual.into()) };
+        }
+    }
+
+    /// Takes pages for the host from `ct_ref` from now on, and as many as
+    /// the host asks for right away
+    pub fn draw_from(&mut self, ct_ref: KObjectRef<Container>) {
+        // The balloon grows from the IRQ, where it mustn't allocate
+        self.pages.reserve(ct_ref.meta().free_pages.len());
+        self.container = Some(ct_ref);
+        self.adjust();
+    }
+
+    /// Hands up to `npages` of the container's free pages to the host. They
+    /// stay in use by the container while in the balloon. Returns the
+    /// number of pages actually given.
+    fn inflate(&mut self, npages: usize) -> usize {
+        let ct_ref = match self.container {
+            Some(ct_ref) => ct_ref,
+            None => return 0,
+        };
+        let npages = core::cmp::min(npages, self.pages.capacity() - self.pages.len());
+        let mut pfns = [0u32; PFNS_PER_REQUEST];
+        let mut done = 0;
+        while done < npages {
+            let mut n = 0;
+            while n < PFNS_PER_REQUEST && done + n < npages {
+                match ct_ref.get_pages(1) {
+                    Some(page) => {
+                        pfns[n] = page as u32;
+                        n += 1;
+                    }
+                    None => break,
+                }
+            }
+            if n == 0 {
+                break;
+            }
+            self.send_pfns(INFLATE_QUEUE, &pfns[..n]);
+            self.pages.extend_from_slice(&pfns[..n]);
+            done += n;
+        }
+        self.report_actual();
+        done
+    }
+
+    /// Takes up to `npages` pages back from the host and gives them back to
+    /// the container
+    fn deflate(&mut self, npages: usize) -> usize {
+        let ct_ref = match self.container {
+            Some(ct_ref) => ct_ref,
+            None => return 0,
+        };
+        let mut pfns = [0u32; PFNS_PER_REQUEST];
+        let mut done = 0;
+        while done < npages && !self.pages.is_empty() {
+            let n = core::cmp::min(PFNS_PER_REQUEST, core::cmp::min(npages - done, self.pages.len()));
+            let start = self.pages.len() - n;
+            pfns[..n].copy_from_slice(&self.pages[start..]);
+            self.send_pfns(DEFLATE_QUEUE, &pfns[..n]);
+            self.pages.truncate(start);
+            pfns[..n]
+                .iter()
+                .for_each(|&pfn| unsafe { ct_ref.put_pages(pfn as usize, 1) });
+            done += n;
+        }
+        self.report_actual();
+        done
+    }
+
+    /// Inflates or deflates until the balloon matches what the host asked
+    /// for, as far as the container's free pages allow
+    pub fn adjust(&mut self) {
+        // The host may change its mind while we're at it
+        loop {
+            let target = self.target();
+            let actual = self.actual();
+            let done = if target > actual {
+                self.inflate(target - actual)
+            } else {
+                self.deflate(actual - target)
+            };
+            if done == 0 {
+                break;
+            }
+        }
+        // Listen for the next change
+        self.irq.enable();
+    }
+
+    /// Called from the device's IRQ: follows the host's new target if the
+    /// configuration changed
+    pub fn handle_interrupt(&mut self) {
+        if self.regs.ack_interrupt() & INTERRUPT_CONFIG != 0 {
+            self.adjust();
+        }
+    }
+
+    fn post_stats(&mut self) {
+        if let Some(queue) = self.stats_queue.as_mut() {
+            queue.add(&[Buffer::readable(&*self.stats)]).expect("stats queue full");
+            queue.kick(&mut self.regs);
+        }
+    }
+
+    /// Answers a pending stats request from the host, if any. `free` counts
+    /// free memory across all pools, `avail` what the balloon could still
+    /// take. Both in bytes.
+    pub fn update_stats(&mut self, free: u64, avail: u64) {
+        match self.stats_queue.as_mut().map(|queue| queue.pop_used()) {
+            Some(Some(_)) => {}
+            _ => return,
+        }
+
+        self.stats[0] = BalloonStat { tag: STAT_MEMFREE.into(), val: free.into() };
+        self.stats[1] = BalloonStat { tag: STAT_AVAIL.into(), val: avail.into() };
+        self.post_stats();
+    }
+}
@@ -0,0 +1,321 @@
+use crate::utils::*;
+use core::fmt;
+use core::ptr::read_volatile;
+
+use alloc::vec::Vec;
+
+use super::{Buffer, Transport, VirtQueue, LEU32, QUEUE_SIZE_MAX};
+use crate::kobject::{Container, KObjectRef, KOBJ_DESCR_LEN};
+
+type LEU16 = Endian<u16, Little>;
+
+pub const CONSOLE_MAX_PORTS: usize = 4;
+pub const CONSOLE_CONTROL_NBUFS: usize = 8;
+
+const CONTROL_RX_QUEUE: u32 = 2;
+const CONTROL_TX_QUEUE: u32 = 3;
+
+const CONSOLE_F_MULTIPORT: u32 = 1 << 1;
+const CONSOLE_DEVICE_FEATURES: u32 = CONSOLE_F_MULTIPORT;
+
+// Control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const DEVICE_REMOVE: u16 = 2;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct VirtIOConsoleConfig {
+    pub cols: LEU16,
+    pub rows: LEU16,
+    pub max_nr_ports: LEU32,
+    pub emerg_wr: LEU32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ControlMsg {
+    id: LEU32,
+    event: LEU16,
+    value: LEU16,
+}
+
+// Room for the name that follows a PORT_NAME message, which is ignored
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ControlBuf {
+    msg: ControlMsg,
+    _name: [u8; 24],
+}
+
+impl ControlBuf {
+    pub const fn empty() -> ControlBuf {
+        ControlBuf {
+            msg: ControlMsg {
+                id: Endian::from_raw(0),
+                event: Endian::from_raw(0),
+                value: Endian::from_raw(0),
+            },
+            _name: [0; 24],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Port {
+    added: bool,
+    host_open: bool,
+    is_console: bool,
+    owner: Option<KObjectRef<Container>>,
+    at_line_start: bool,
+}
+
+impl Port {
+    const fn empty() -> Port {
+        Port {
+            added: false,
+            host_open: false,
+            is_console: false,
+            owner: None,
+            at_line_start: true,
+        }
+    }
+}
+
+pub struct VirtIOConsole<'a> {
+    regs: Transport<'a, VirtIOConsoleConfig>,
+    queues: Vec<VirtQueue>,
+    control: &'a mut [ControlBuf; CONSOLE_CONTROL_NBUFS],
+    // head descriptor each control buffer was posted under
+    control_heads: [u16; CONSOLE_CONTROL_NBUFS],
+    multiport: bool,
+    nports: usize,
+    ports: [Port; CONSOLE_MAX_PORTS],
+    irq: crate::gic::GIC,
+}
+
+fn rx_queue(port: usize) -> u32 {
+    if port == 0 {
+        0
+    } else {
+        2 + 2 * port as u32
+    }
+}
+
+fn tx_queue(port: usize) -> u32 {
+    rx_queue(port) + 1
+}
+
+impl<'a> VirtIOConsole<'a> {
+    pub fn new(
+        mut regs: Transport<'a, VirtIOConsoleConfig>,
+        control: &'a mut [ControlBuf; CONSOLE_CONTROL_NBUFS],
+        irq: crate::gic::GIC,
+    ) -> Self {
+        let features = regs.negotiate(CONSOLE_DEVICE_FEATURES);
+        let multiport = features & CONSOLE_F_MULTIPORT != 0;
+        let nports = match regs.config() {
+            Some(config) if multiport => {
+                let max_nr_ports = unsafe { read_volatile(&config.max_nr_ports).native() } as usize;
+                core::cmp::min(max_nr_ports, CONSOLE_MAX_PORTS)
+            }
+            _ => 1,
+        };
+        let nqueues = if multiport { 2 * (nports + 1) } else { 2 };
+
+        let queues = (0..nqueues as u32)
+            .map(|i| VirtQueue::new(&mut regs, i, QUEUE_SIZE_MAX, features).expect("console queue missing"))
+            .collect();
+        regs.driver_ok();
+
+        let mut console = VirtIOConsole {
+            regs,
+            queues,
+            control,
+            control_heads: [0; CONSOLE_CONTROL_NBUFS],
+            multiport,
+            nports,
+            ports: [Port::empty(); CONSOLE_MAX_PORTS],
+            irq,
+        };
+
+        if multiport {
+            // Ports are announced by the device through the control queue
+            for i in 0..CONSOLE_CONTROL_NBUFS {
+                console.post_control_buf(i);
+            }
+            console.send_control(0, DEVICE_READY, 1);
+            console.poll_control();
+        } else {
+            console.ports[0].added = true;
+            console.ports[0].host_open = true;
+            console.ports[0].is_console = true;
+        }
+
+        console
+    }
+}
+
+impl<'a> VirtIOConsole<'a> {
+    pub fn config(&self) -> Option<&VirtIOConsoleConfig> {
+        self.regs.config()
+    }
+
+    pub fn nports(&self) -> usize {
+        self.nports
+    }
+
+    // Spins until the device hands back one buffer of the queue, and returns
+    // its head descriptor and written length
+    fn wait_used(&mut self, qnum: u32) -> (u16, u32) {
+        self.queues[qnum as usize].wait_used(&mut self.regs, &self.irq)
+    }
+
+    fn transfer(&mut self, qnum: u32, buf: Buffer) -> u32 {
+        self.queues[qnum as usize].transfer(&mut self.regs, &self.irq, &[buf])
+    }
+
+    fn post_control_buf(&mut self, i: usize) {
+        let buf = Buffer::writable(&mut self.control[i]);
+        let queue = &mut self.queues[CONTROL_RX_QUEUE as usize];
+        self.control_heads[i] = queue.add(&[buf]).expect("control queue full");
+        queue.kick(&mut self.regs);
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        let msg = ControlMsg {
+            id: id.into(),
+            event: event.into(),
+            value: value.into(),
+        };
+        self.transfer(CONTROL_TX_QUEUE, Buffer::readable(&msg));
+    }
+
+    /// Handles the control messages the device has sent so far
+    pub fn poll_control(&mut self) {
+        if !self.multiport {
+            return;
+        }
+        while self.queues[CONTROL_RX_QUEUE as usize].has_used() {
+            let (head, _) = self.wait_used(CONTROL_RX_QUEUE);
+            if let Some(i) = self.control_heads.iter().position(|&h| h == head) {
+                let msg = unsafe { read_volatile(&self.control[i].msg) };
+                self.handle_control(msg);
+                self.post_control_buf(i);
+            }
+        }
+    }
+
+    fn handle_control(&mut self, msg: ControlMsg) {
+        let id = msg.id.native() as usize;
+        if id >= self.nports {
+            return;
+        }
+        match msg.event.native() {
+            DEVICE_ADD => {
+                self.ports[id].added = true;
+                self.send_control(id as u32, PORT_READY, 1);
+            }
+            DEVICE_REMOVE => {
+                self.ports[id] = Port::empty();
+            }
+            CONSOLE_PORT => {
+                self.ports[id].is_console = true;
+                self.send_control(id as u32, PORT_OPEN, 1);
+            }
+            PORT_OPEN => {
+                self.ports[id].host_open = msg.value.native() != 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Hands a free port to `owner`. Everything written to it is tagged
+    /// with the owner's description.
+    pub fn open_port(&mut self, owner: KObjectRef<Container>) -> Option<usize> {
+        self.poll_control();
+        let port = (0..self.nports).find(|&p| self.ports[p].added && self.ports[p].owner.is_none())?;
+        self.ports[port].owner = Some(owner);
+        self.ports[port].at_line_start = true;
+        if self.multiport && !self.ports[port].is_console {
+            self.send_control(port as u32, PORT_OPEN, 1);
+        }
+        Some(port)
+    }
+
+    pub fn close_port(&mut self, port: usize) {
+        if port < self.nports
+            && self.ports[port].owner.take().is_some()
+            && self.multiport
+            && !self.ports[port].is_console
+        {
+            self.send_control(port as u32, PORT_OPEN, 0);
+        }
+    }
+
+    /// Whether the host side of `port` is connected
+    pub fn is_connected(&self, port: usize) -> bool {
+        port < self.nports && self.ports[port].host_open
+    }
+
+    pub fn port_of(&self, owner: KObjectRef<Container>) -> Option<usize> {
+        (0..self.nports).find(|&p| self.ports[p].owner == Some(owner))
+    }
+
+    fn transmit(&mut self, port: usize, data: &[u8]) {
+        self.transfer(tx_queue(port), Buffer::readable(data));
+    }
+
+    pub fn write(&mut self, port: usize, data: &[u8]) {
+        if port >= self.nports || !self.ports[port].added {
+            return;
+        }
+
+        let descr = self.ports[port]
+            .owner
+            .map(|ct_ref| ct_ref.meta().descr)
+            .unwrap_or([0; KOBJ_DESCR_LEN]);
+        let descr_len = descr.iter().position(|&b| b == 0).unwrap_or(KOBJ_DESCR_LEN);
+        let mut tag = [0u8; KOBJ_DESCR_LEN + 3];
+        let tag_len = if descr_len > 0 {
+            tag[0] = b'[';
+            tag[1..descr_len + 1].copy_from_slice(&descr[..descr_len]);
+            tag[descr_len + 1..descr_len + 3].copy_from_slice(b"] ");
+            descr_len + 3
+        } else {
+            0
+        };
+
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.ports[port].at_line_start && tag_len > 0 {
+                self.transmit(port, &tag[..tag_len]);
+            }
+            self.transmit(port, line);
+            self.ports[port].at_line_start = line.last() == Some(&b'\n');
+        }
+    }
+
+    /// Blocks until the host sends something on `port`
+    pub fn read(&mut self, port: usize, data: &mut [u8]) -> usize {
+        if port >= self.nports || !self.ports[port].added {
+            return 0;
+        }
+        let len = self.transfer(rx_queue(port), Buffer::writable(data));
+        len as usize
+    }
+}
+
+pub struct ConsolePort<'c, 'a> {
+    pub console: &'c mut VirtIOConsole<'a>,
+    pub port: usize,
+}
+
+impl<'c, 'a> fmt::Write for ConsolePort<'c, 'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.console.write(self.port, s.as_bytes());
+        Ok(())
+    }
+}
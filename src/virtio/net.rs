@@ -1,17 +1,79 @@
 use crate::utils::*;
-use core::ptr::{read_volatile, write_volatile};
+use core::ptr::read_volatile;
 
-use super::{Status, VirtIORegs, VirtQDesc, VirtQUsed, VirtqAvailable};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::{Buffer, Transport, VirtQueue, QUEUE_SIZE_MAX};
+use crate::net::pcap::Capture;
+use crate::timer;
 
 type LEU16 = Endian<u16, Little>;
 
+pub const FRAME_LEN: usize = 1526;
+
+// Buffers kept posted on each RX queue, and the spares that replace them
+// while received frames wait to be picked up
+const RX_POSTED: usize = 64;
+const RX_POOL: usize = 2 * RX_POSTED;
+const TX_SLOTS: usize = 32;
+
+const NET_F_CSUM: u32 = 1 << 0;
+const NET_F_GUEST_CSUM: u32 = 1 << 1;
+const NET_F_MTU: u32 = 1 << 3;
+const NET_F_MAC: u32 = 1 << 5;
+const NET_F_MRG_RXBUF: u32 = 1 << 15;
+const NET_F_STATUS: u32 = 1 << 16;
+const NET_F_CTRL_VQ: u32 = 1 << 17;
+const NET_F_MQ: u32 = 1 << 22;
+
+const NET_DEVICE_FEATURES: u32 = NET_F_CSUM
+    | NET_F_GUEST_CSUM
+    | NET_F_MTU
+    | NET_F_MAC
+    | NET_F_MRG_RXBUF
+    | NET_F_STATUS
+    | NET_F_CTRL_VQ
+    | NET_F_MQ;
+
+const NET_S_LINK_UP: u16 = 1;
+const NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+// The header without num_buffers, used by legacy devices without MRG_RXBUF
+const NET_HDR_LEN_LEGACY: usize = 10;
+
+const CTRL_MQ: u8 = 4;
+const CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const CTRL_OK: u8 = 0;
+
 pub struct VirtIONet<'a> {
-    regs: &'a mut VirtIORegs<VirtIONetConfig>,
-    read_queue: &'a mut super::Queue<128>,
-    write_queue: &'a mut super::Queue<128>,
+    regs: Transport<'a, VirtIONetConfig>,
+    features: u32,
+    hdr_len: usize,
+    pairs: Vec<QueuePair>,
+    ctrl_queue: Option<VirtQueue>,
+    rx_bufs: Box<[PacketBuf]>,
+    rx_free: Vec<usize>,
+    rx_ready: VecDeque<(usize, usize)>, // (pool index, frame length)
+    tx_bufs: Box<[PacketBuf]>,
+    tx_free: Vec<usize>,
+    // Everything read or written while set
+    capture: Option<Capture>,
     irq: crate::gic::GIC,
 }
 
+struct QueuePair {
+    read_queue: VirtQueue,
+    write_queue: VirtQueue,
+    rx_posted: Vec<Option<usize>>, // pool index by head descriptor
+    rx_nposted: usize,
+    // A merged frame still waiting for some of its buffers: pool index,
+    // length so far and how many buffers are left
+    rx_partial: Option<(usize, usize, u16)>,
+    tx_pending: Vec<Option<usize>>, // slot by head descriptor
+}
+
 #[repr(C)]
 #[derive(Debug, Default)]
 pub struct VirtIONetConfig {
@@ -30,165 +92,419 @@ pub struct NetHdr {
     pub gso_size: LEU16,
     pub csum_start: LEU16,
     pub csum_offset: LEU16,
+    pub num_buffers: LEU16,
 }
 
-const NET_DEVICE_FEATURES: u32 = 1 << 5; // VIRTIO_NET_F_MAC
+// With MRG_RXBUF, RX buffers are posted whole and the header sits right
+// before the frame
+#[repr(C)]
+struct PacketBuf {
+    hdr: NetHdr,
+    frame: [u8; FRAME_LEN],
+}
 
-impl<'a> VirtIONet<'a> {
-    pub fn new(
-        regs: &'a mut VirtIORegs<VirtIONetConfig>,
-        read_queue: &'a mut super::Queue<128>,
-        write_queue: &'a mut super::Queue<128>,
-        irq: crate::gic::GIC,
-    ) -> Self {
+impl PacketBuf {
+    fn empty() -> PacketBuf {
+        PacketBuf { hdr: NetHdr::default(), frame: [0; FRAME_LEN] }
+    }
+
+    fn bytes(&self) -> &[u8] {
         unsafe {
-            write_volatile(&mut regs.status, Status::Reset.into());
-            write_volatile(&mut regs.status, Status::Acknowledge.into());
-            write_volatile(&mut regs.status, Status::Driver.into());
-
-            write_volatile(&mut regs.device_features_sel, 0.into());
-            let device_features = read_volatile(&mut regs.device_features).native();
-            write_volatile(&mut regs.driver_features_sel, 0.into());
-            write_volatile(
-                &mut regs.driver_features,
-                (NET_DEVICE_FEATURES & device_features).into(),
-            );
-
-            write_volatile(&mut regs.status, Status::FeaturesOk.into());
-            if read_volatile(&mut regs.status).native() & (Status::FeaturesOk as u32) == 0 {
-                panic!("Coudln't set blk features");
-            }
+            core::slice::from_raw_parts(self as *const PacketBuf as *const u8, core::mem::size_of::<PacketBuf>())
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self as *mut PacketBuf as *mut u8, core::mem::size_of::<PacketBuf>())
+        }
+    }
+}
 
-            for (i, queue) in [&read_queue, &write_queue].iter().enumerate() {
-                write_volatile(&mut regs.queue_sel, (i as u32).into());
-                write_volatile(&mut regs.queue_num, (queue.descriptors.len() as u32).into());
-                write_volatile(
-                    &mut regs.queue_desc_low,
-                    ((queue.descriptors.as_ptr() as usize) as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_desc_high,
-                    ((queue.descriptors.as_ptr() as usize >> 32) as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_avail_low,
-                    (&queue.available as *const VirtqAvailable as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_avail_high,
-                    ((&queue.available as *const VirtqAvailable as usize >> 32) as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_used_low,
-                    (&queue.used as *const VirtQUsed as u32).into(),
-                );
-                write_volatile(
-                    &mut regs.queue_used_high,
-                    ((&queue.used as *const VirtQUsed as usize >> 32) as u32).into(),
-                );
-                write_volatile(&mut regs.queue_ready, 1.into());
+/// Where a frame's checksum goes, counted from the start of the frame. The
+/// checksum field must already hold the pseudo-header sum.
+#[derive(Debug, Clone, Copy)]
+pub struct TxChecksum {
+    pub start: u16,
+    pub offset: u16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    QueueFull,
+    TooLong,
+    LinkDown,
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).fold(0u32, |sum, word| {
+        sum + u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32
+    });
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Finishes a partial checksum: sums everything from `start` and stores the
+// result at `start + offset`
+fn complete_checksum(frame: &mut [u8], start: usize, offset: usize) {
+    let field = start + offset;
+    if field + 2 > frame.len() {
+        return;
+    }
+    let sum = checksum(&frame[start..]);
+    frame[field..field + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+impl<'a> VirtIONet<'a> {
+    pub fn new(mut regs: Transport<'a, VirtIONetConfig>, irq: crate::gic::GIC) -> Self {
+        let features = regs.negotiate(NET_DEVICE_FEATURES);
+        let max_pairs = match regs.config() {
+            Some(config) if features & NET_F_MQ != 0 && features & NET_F_CTRL_VQ != 0 => {
+                unsafe { read_volatile(&config.max_virtqueue_pairs).native() as usize }
             }
+            _ => 1,
+        };
+        // One queue pair per running core
+        let npairs = max_pairs.clamp(1, ONLINE_CORES);
 
-            write_volatile(&mut regs.status, Status::DriverOk.into());
-        }
-        VirtIONet {
+        let pairs: Vec<QueuePair> = (0..npairs as u32)
+            .map(|p| {
+                let read_queue =
+                    VirtQueue::new(&mut regs, 2 * p, QUEUE_SIZE_MAX, features).expect("net has no rx queue");
+                let write_queue =
+                    VirtQueue::new(&mut regs, 2 * p + 1, QUEUE_SIZE_MAX, features).expect("net has no tx queue");
+                QueuePair {
+                    rx_posted: (0..read_queue.size()).map(|_| None).collect(),
+                    rx_nposted: 0,
+                    rx_partial: None,
+                    tx_pending: (0..write_queue.size()).map(|_| None).collect(),
+                    read_queue,
+                    write_queue,
+                }
+            })
+            .collect();
+        // The control queue comes after every pair the device has
+        let ctrl_queue = if features & NET_F_CTRL_VQ != 0 {
+            VirtQueue::new(&mut regs, 2 * max_pairs as u32, QUEUE_SIZE_MAX, features)
+        } else {
+            None
+        };
+        regs.driver_ok();
+
+        // num_buffers is always there under VIRTIO_F_VERSION_1
+        let hdr_len = if regs.version_1() || features & NET_F_MRG_RXBUF != 0 {
+            core::mem::size_of::<NetHdr>()
+        } else {
+            NET_HDR_LEN_LEGACY
+        };
+        let rx_pool = RX_POOL * npairs;
+        let mut net = VirtIONet {
             regs,
-            read_queue,
-            write_queue,
+            features,
+            hdr_len,
+            pairs,
+            ctrl_queue,
+            rx_bufs: (0..rx_pool).map(|_| PacketBuf::empty()).collect(),
+            rx_free: (0..rx_pool).collect(),
+            rx_ready: VecDeque::with_capacity(rx_pool),
+            tx_bufs: (0..TX_SLOTS).map(|_| PacketBuf::empty()).collect(),
+            tx_free: (0..TX_SLOTS).collect(),
+            capture: None,
             irq,
+        };
+        if npairs > 1 && !net.set_queue_pairs(npairs as u16) {
+            panic!("net refused {} queue pairs", npairs);
         }
+        net.refill();
+        net.irq.enable();
+        net
     }
 }
 
 impl<'a> VirtIONet<'a> {
-    pub fn config(&self) -> &VirtIONetConfig {
-        unsafe { &*(&self.regs.config as *const _ as *const VirtIONetConfig) }
+    pub fn config(&self) -> Option<&VirtIONetConfig> {
+        self.regs.config()
     }
 
-    fn enqueue(&mut self, qnum: u32, descriptor: u16) {
-        let queue = match qnum {
-            0 => &mut self.read_queue,
-            _ => &mut self.write_queue,
-        };
+    pub fn mac(&self) -> [u8; 6] {
+        self.config().map_or([0; 6], |config| unsafe { read_volatile(&config.mac) })
+    }
+
+    /// The largest packet the device takes, if it says
+    pub fn mtu(&self) -> Option<u16> {
+        if self.features & NET_F_MTU == 0 {
+            return None;
+        }
+        self.config().map(|config| unsafe { read_volatile(&config.mtu).native() })
+    }
 
-        queue.available.ring[queue.available.idx.native() as usize % queue.available.ring.len()] =
-            descriptor.into();
-        mb();
+    /// Whether the link is up. Devices that don't report it are always up.
+    pub fn link_up(&self) -> bool {
+        match self.config() {
+            Some(config) if self.features & NET_F_STATUS != 0 => {
+                let status = unsafe { read_volatile(&config.status).native() };
+                status & NET_S_LINK_UP != 0
+            }
+            _ => true,
+        }
+    }
 
-        queue.available.idx = (queue.available.idx.native().wrapping_add(1)).into();
-        mb();
-        self.regs.queue_notify = qnum.into();
+    /// Starts recording frames into `capture`, or stops if it is None.
+    /// Returns the capture that was running.
+    pub fn set_capture(&mut self, capture: Option<Capture>) -> Option<Capture> {
+        core::mem::replace(&mut self.capture, capture)
     }
 
-    pub fn read(&mut self, data: &mut [u8; 1526]) {
-        let mut blkreq_hdr = NetHdr {
-            ..Default::default()
-        };
+    pub fn capture_mut(&mut self) -> Option<&mut Capture> {
+        self.capture.as_mut()
+    }
 
-        self.read_queue.descriptors[0] = VirtQDesc {
-            addr: (&mut blkreq_hdr as *mut _ as u64).into(),
-            len: (core::mem::size_of::<NetHdr>() as u32).into(),
-            flags: (3).into(),
-            next: 1.into(),
-        };
+    pub fn queue_pairs(&self) -> usize {
+        self.pairs.len()
+    }
 
-        self.read_queue.descriptors[1] = VirtQDesc {
-            addr: (data.as_ptr() as *const _ as u64).into(),
-            len: (data.len() as u32).into(),
-            flags: (2).into(),
-            next: 0.into(),
+    fn control(&mut self, class: u8, command: u8, data: &[u8]) -> bool {
+        let queue = match self.ctrl_queue.as_mut() {
+            Some(queue) => queue,
+            None => return false,
         };
+        let hdr = [class, command];
+        let mut ack = !CTRL_OK;
+        queue.transfer(
+            &mut self.regs,
+            &self.irq,
+            &[Buffer::readable(&hdr), Buffer::readable(data), Buffer::writable(&mut ack)],
+        );
+        ack == CTRL_OK
+    }
 
-        self.enqueue(0, 0);
+    fn set_queue_pairs(&mut self, npairs: u16) -> bool {
+        self.control(CTRL_MQ, CTRL_MQ_VQ_PAIRS_SET, &npairs.to_le_bytes())
+    }
 
-        unsafe {
-            self.irq.enable();
-            while read_volatile(&self.read_queue.used.idx)
-                != read_volatile(&self.read_queue.available.idx)
-            {
-                //asm!("wfi");
-                let status = read_volatile(&self.regs.interrupt_status);
-                if status.native() != 0 {
-                    write_volatile(&mut self.regs.interrupt_ack, 0b11.into());
+    // Posts spare buffers until RX_POSTED are with each RX queue
+    fn refill(&mut self) {
+        let merged = self.features & NET_F_MRG_RXBUF != 0;
+        let hdr_len = self.hdr_len;
+        for pair in self.pairs.iter_mut() {
+            let mut added = false;
+            while pair.rx_nposted < RX_POSTED {
+                let i = match self.rx_free.pop() {
+                    Some(i) => i,
+                    None => break,
+                };
+                let bytes = self.rx_bufs[i].bytes_mut();
+                let head = if merged {
+                    pair.read_queue.add(&[Buffer::writable(bytes)])
+                } else {
+                    let (hdr, frame) = bytes.split_at_mut(core::mem::size_of::<NetHdr>());
+                    pair.read_queue
+                        .add(&[Buffer::writable(&mut hdr[..hdr_len]), Buffer::writable(frame)])
+                };
+                match head {
+                    Some(head) => {
+                        pair.rx_posted[head as usize] = Some(i);
+                        pair.rx_nposted += 1;
+                        added = true;
+                    }
+                    None => {
+                        self.rx_free.push(i);
+                        break;
+                    }
                 }
             }
-            self.irq.disable();
+            if added {
+                pair.read_queue.kick(&mut self.regs);
+            }
         }
     }
 
-    pub fn write(&mut self, data: &[u8; 1526]) {
-        let mut blkreq_hdr = NetHdr {
-            ..Default::default()
+    // Appends a merged segment of `from` to the frame in `to`
+    fn merge_segment(&mut self, to: usize, len: usize, from: usize, seg_len: usize) -> usize {
+        let (dst, src) = if to < from {
+            let (low, high) = self.rx_bufs.split_at_mut(from);
+            (&mut low[to], &high[0])
+        } else {
+            let (low, high) = self.rx_bufs.split_at_mut(to);
+            (&mut high[0], &low[from])
         };
+        let n = core::cmp::min(seg_len, FRAME_LEN - len);
+        dst.frame[len..len + n].copy_from_slice(&src.bytes()[..n]);
+        len + n
+    }
 
-        self.write_queue.descriptors[0] = VirtQDesc {
-            addr: (&mut blkreq_hdr as *mut _ as u64).into(),
-            len: (core::mem::size_of::<NetHdr>() as u32).into(),
-            flags: (1).into(),
-            next: 1.into(),
-        };
+    fn reap_rx(&mut self) {
+        let merged = self.features & NET_F_MRG_RXBUF != 0;
+        for p in 0..self.pairs.len() {
+            while let Some((head, written)) = self.pairs[p].read_queue.pop_used() {
+                let j = match self.pairs[p].rx_posted[head as usize].take() {
+                    Some(j) => j,
+                    None => continue,
+                };
+                self.pairs[p].rx_nposted -= 1;
 
-        self.write_queue.descriptors[1] = VirtQDesc {
-            addr: (data.as_ptr() as *const _ as u64).into(),
-            len: (data.len() as u32).into(),
-            flags: (0).into(),
-            next: 0.into(),
-        };
+                // The rest of a merged frame may be used after this call
+                // returns, so a frame is only ready once all of it is here
+                let (i, len, left) = match self.pairs[p].rx_partial.take() {
+                    Some((i, len, left)) => {
+                        let len = self.merge_segment(i, len, j, written as usize);
+                        self.rx_free.push(j);
+                        (i, len, left - 1)
+                    }
+                    None => {
+                        let len = core::cmp::min((written as usize).saturating_sub(self.hdr_len), FRAME_LEN);
+                        let nbufs = if merged { self.rx_bufs[j].hdr.num_buffers.native() } else { 1 };
+                        (j, len, nbufs.saturating_sub(1))
+                    }
+                };
+                if left > 0 {
+                    self.pairs[p].rx_partial = Some((i, len, left));
+                    continue;
+                }
 
-        self.enqueue(1, 0);
+                let buf = &mut self.rx_bufs[i];
+                if buf.hdr.flags & NET_HDR_F_NEEDS_CSUM != 0 {
+                    let start = buf.hdr.csum_start.native() as usize;
+                    let offset = buf.hdr.csum_offset.native() as usize;
+                    complete_checksum(&mut buf.frame[..len], start, offset);
+                }
+                self.rx_ready.push_back((i, len));
+            }
+        }
+    }
 
-        unsafe {
-            self.irq.enable();
-            while read_volatile(&self.write_queue.used.idx)
-                != read_volatile(&self.write_queue.available.idx)
-            {
-                //asm!("wfi");
-                let status = read_volatile(&self.regs.interrupt_status);
-                if status.native() != 0 {
-                    write_volatile(&mut self.regs.interrupt_ack, 0b11.into());
+    fn reap_tx(&mut self) {
+        for pair in self.pairs.iter_mut() {
+            while let Some((head, _)) = pair.write_queue.pop_used() {
+                if let Some(slot) = pair.tx_pending[head as usize].take() {
+                    self.tx_free.push(slot);
+                }
+            }
+        }
+    }
+
+    /// Called from the device's IRQ: moves received frames aside, reposts
+    /// spare buffers in their place and reclaims sent ones
+    pub fn handle_interrupt(&mut self) {
+        self.regs.ack_interrupt();
+        self.reap_rx();
+        self.refill();
+        self.reap_tx();
+    }
+
+    /// Copies the oldest received frame into `data`. Returns its length, or
+    /// None if nothing has arrived.
+    pub fn poll_recv(&mut self, data: &mut [u8]) -> Option<usize> {
+        self.reap_rx();
+        let received = self.rx_ready.pop_front().map(|(i, len)| {
+            let len = core::cmp::min(len, data.len());
+            data[..len].copy_from_slice(&self.rx_bufs[i].frame[..len]);
+            if let Some(capture) = self.capture.as_mut() {
+                capture.record(&data[..len], timer::uptime());
+            }
+            self.rx_free.push(i);
+            len
+        });
+        self.refill();
+        received
+    }
+
+    /// Queues `data` for transmission without waiting for the device
+    pub fn try_send(&mut self, data: &[u8]) -> Result<(), SendError> {
+        self.try_send_csum(data, None)
+    }
+
+    /// Like `try_send`, but leaves the checksum at `csum` to the device, or
+    /// fills it in here if the device can't
+    pub fn try_send_csum(&mut self, data: &[u8], csum: Option<TxChecksum>) -> Result<(), SendError> {
+        if data.len() > FRAME_LEN {
+            return Err(SendError::TooLong);
+        }
+        if !self.link_up() {
+            return Err(SendError::LinkDown);
+        }
+        self.reap_tx();
+        let offload = self.features & NET_F_CSUM != 0;
+        let hdr_len = self.hdr_len;
+        let npairs = self.pairs.len();
+        let pair = &mut self.pairs[current_core() % npairs];
+        match self.tx_free.pop() {
+            Some(slot) => {
+                let buf = &mut self.tx_bufs[slot];
+                buf.hdr = NetHdr::default();
+                buf.frame[..data.len()].copy_from_slice(data);
+                match csum {
+                    Some(csum) if offload => {
+                        buf.hdr.flags = NET_HDR_F_NEEDS_CSUM;
+                        buf.hdr.csum_start = csum.start.into();
+                        buf.hdr.csum_offset = csum.offset.into();
+                    }
+                    Some(csum) => complete_checksum(
+                        &mut buf.frame[..data.len()],
+                        csum.start as usize,
+                        csum.offset as usize,
+                    ),
+                    None => {}
+                }
+                let (hdr, frame) = buf.bytes().split_at(core::mem::size_of::<NetHdr>());
+                match pair
+                    .write_queue
+                    .add(&[Buffer::readable(&hdr[..hdr_len]), Buffer::readable(&frame[..data.len()])])
+                {
+                    Some(head) => {
+                        // Offloaded checksums are still missing here
+                        if let Some(capture) = self.capture.as_mut() {
+                            capture.record(&frame[..data.len()], timer::uptime());
+                        }
+                        pair.tx_pending[head as usize] = Some(slot);
+                        pair.write_queue.kick(&mut self.regs);
+                        Ok(())
+                    }
+                    None => {
+                        self.tx_free.push(slot);
+                        Err(SendError::QueueFull)
+                    }
                 }
             }
-            self.irq.disable();
+            None => Err(SendError::QueueFull),
         }
     }
+
+    /// Blocks until a frame arrives
+    pub fn read(&mut self, data: &mut [u8; FRAME_LEN]) -> usize {
+        loop {
+            if let Some(len) = self.poll_recv(data) {
+                break len;
+            }
+            self.regs.ack_interrupt();
+        }
+    }
+
+    /// Blocks until `data` fits in the TX queue
+    pub fn write(&mut self, data: &[u8]) {
+        while self.try_send(data) == Err(SendError::QueueFull) {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_complete_checksum() {
+        // An IPv4 header with its checksum zeroed
+        let mut frame = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00,
+            0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        complete_checksum(&mut frame, 0, 10);
+        assert_eq!(&frame[10..12], &[0xb8, 0x61]);
+        assert_eq!(checksum(&frame), 0);
+
+        // Fields past the end are left alone
+        complete_checksum(&mut frame, 0, 19);
+        assert_eq!(frame[19], 0xc7);
+    }
 }
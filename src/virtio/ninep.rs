@@ -0,0 +1,55 @@
+use crate::utils::*;
+use core::ptr::read_volatile;
+
+use super::{Buffer, Transport, VirtQueue, QUEUE_SIZE_MAX};
+
+type LEU16 = Endian<u16, Little>;
+
+pub struct VirtIO9P<'a> {
+    regs: Transport<'a, NinePConfig>,
+    queue: VirtQueue,
+    irq: crate::gic::GIC,
+}
+
+#[repr(C)]
+pub struct NinePConfig {
+    pub tag_len: LEU16,
+    pub tag: [u8; 32],
+}
+
+const NINEP_F_MOUNT_TAG: u32 = 1 << 0;
+const NINEP_DEVICE_FEATURES: u32 = NINEP_F_MOUNT_TAG;
+
+impl<'a> VirtIO9P<'a> {
+    pub fn new(mut regs: Transport<'a, NinePConfig>, irq: crate::gic::GIC) -> Self {
+        let features = regs.negotiate(NINEP_DEVICE_FEATURES);
+        let queue = VirtQueue::new(&mut regs, 0, QUEUE_SIZE_MAX, features).expect("9p has no queue");
+        regs.driver_ok();
+        VirtIO9P { regs, queue, irq }
+    }
+}
+
+impl<'a> VirtIO9P<'a> {
+    /// The mount tag the host exported the share under
+    pub fn tag(&self, buf: &mut [u8; 32]) -> usize {
+        let config = match self.regs.config() {
+            Some(config) => config,
+            None => return 0,
+        };
+        unsafe {
+            let len = core::cmp::min(read_volatile(&config.tag_len).native() as usize, 32);
+            (0..len).for_each(|i| buf[i] = read_volatile(&config.tag[i]));
+            len
+        }
+    }
+
+    /// Sends one T-message and waits for its R-message. Returns the number of
+    /// bytes the device wrote into `response`.
+    pub fn transact(&mut self, request: &[u8], response: &mut [u8]) -> usize {
+        self.queue.transfer(
+            &mut self.regs,
+            &self.irq,
+            &[Buffer::readable(request), Buffer::writable(response)],
+        ) as usize
+    }
+}
@@ -0,0 +1,191 @@
+use crate::utils::*;
+use core::ptr::{read_volatile, write_volatile};
+
+use super::{DeviceId, LEU32};
+use crate::pci::{PciFunction, CAP_VENDOR_SPECIFIC};
+
+type LEU16 = Endian<u16, Little>;
+
+const PCI_VENDOR_VIRTIO: u16 = 0x1af4;
+const PCI_DEVICE_MODERN: u16 = 0x1040;
+const PCI_DEVICE_TRANSITIONAL: u16 = 0x1000;
+
+// virtio_pci_cap cfg_type
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// 64-bit fields are split since the device only takes 32-bit accesses
+#[repr(C)]
+pub struct CommonCfg {
+    pub device_feature_select: LEU32,
+    pub device_feature: LEU32,
+    pub driver_feature_select: LEU32,
+    pub driver_feature: LEU32,
+    pub msix_config: LEU16,
+    pub num_queues: LEU16,
+    pub device_status: u8,
+    pub config_generation: u8,
+    pub queue_select: LEU16,
+    pub queue_size: LEU16,
+    pub queue_msix_vector: LEU16,
+    pub queue_enable: LEU16,
+    pub queue_notify_off: LEU16,
+    pub queue_desc_low: LEU32,
+    pub queue_desc_high: LEU32,
+    pub queue_driver_low: LEU32,
+    pub queue_driver_high: LEU32,
+    pub queue_device_low: LEU32,
+    pub queue_device_high: LEU32,
+}
+
+/// Which virtio device a PCI function is, if it is one
+pub fn device_id(func: &PciFunction) -> Option<DeviceId> {
+    if func.vendor_id() != PCI_VENDOR_VIRTIO {
+        return None;
+    }
+    let id = match func.device_id() {
+        id if id >= PCI_DEVICE_MODERN => (id - PCI_DEVICE_MODERN) as u32,
+        id if id >= PCI_DEVICE_TRANSITIONAL => func.subsystem_id() as u32,
+        _ => return None,
+    };
+    Some(DeviceId::from(id))
+}
+
+/// The virtio registers of a PCI function, found through its vendor
+/// capabilities
+pub struct PciTransport<C> {
+    common: *mut CommonCfg,
+    notify: usize,
+    notify_off_multiplier: u32,
+    isr: *mut u8,
+    // Not every device has a configuration of its own
+    device: Option<*mut C>,
+    device_id: DeviceId,
+}
+
+unsafe impl<C> Send for PciTransport<C> {}
+
+impl<C> PciTransport<C> {
+    /// `bars` are the CPU addresses the function's BARs were placed at
+    pub unsafe fn new(func: &PciFunction, bars: &[Option<usize>; 6]) -> Option<PciTransport<C>> {
+        let device_id = device_id(func)?;
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+
+        for cap in func.capabilities(CAP_VENDOR_SPECIFIC) {
+            let cfg_type = func.read_u8(cap + 3);
+            let bar = func.read_u8(cap + 4) as usize;
+            let offset = func.read_u32(cap + 8) as usize;
+            let addr = match bars.get(bar).copied().flatten() {
+                Some(base) => base + offset,
+                None => continue,
+            };
+            match cfg_type {
+                CAP_COMMON_CFG if common.is_none() => common = Some(addr),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = Some((addr, func.read_u32(cap + 16)))
+                }
+                CAP_ISR_CFG if isr.is_none() => isr = Some(addr),
+                CAP_DEVICE_CFG if device.is_none() => device = Some(addr),
+                _ => {}
+            }
+        }
+
+        let (notify, notify_off_multiplier) = notify?;
+        Some(PciTransport {
+            common: common? as *mut CommonCfg,
+            notify,
+            notify_off_multiplier,
+            isr: isr? as *mut u8,
+            device: device.map(|addr| addr as *mut C),
+            device_id,
+        })
+    }
+
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    pub fn status(&self) -> u32 {
+        unsafe { read_volatile(&(*self.common).device_status) as u32 }
+    }
+
+    pub fn set_status(&mut self, status: u32) {
+        unsafe { write_volatile(&mut (*self.common).device_status, status as u8) }
+    }
+
+    /// Feature bits `32 * word` and up
+    pub fn device_features(&mut self, word: u32) -> u32 {
+        unsafe {
+            write_volatile(&mut (*self.common).device_feature_select, word.into());
+            read_volatile(&(*self.common).device_feature).native()
+        }
+    }
+
+    pub fn set_driver_features(&mut self, word: u32, features: u32) {
+        unsafe {
+            write_volatile(&mut (*self.common).driver_feature_select, word.into());
+            write_volatile(&mut (*self.common).driver_feature, features.into());
+        }
+    }
+
+    pub fn ack_interrupt(&mut self) -> u32 {
+        // Reading the ISR status acknowledges it
+        unsafe { read_volatile(self.isr) as u32 }
+    }
+
+    pub fn config(&self) -> Option<*mut C> {
+        self.device
+    }
+
+    pub fn queue_max(&mut self, index: u32) -> u32 {
+        unsafe {
+            let common = &mut *self.common;
+            write_volatile(&mut common.queue_select, (index as u16).into());
+            if read_volatile(&common.queue_enable).native() != 0 {
+                return 0;
+            }
+            read_volatile(&common.queue_size).native() as u32
+        }
+    }
+
+    pub fn setup_queue(&mut self, index: u32, size: u16, desc: usize, avail: usize, used: usize) {
+        unsafe {
+            let common = &mut *self.common;
+            write_volatile(&mut common.queue_select, (index as u16).into());
+            write_volatile(&mut common.queue_size, size.into());
+            write_volatile(&mut common.queue_desc_low, (desc as u32).into());
+            write_volatile(&mut common.queue_desc_high, ((desc >> 32) as u32).into());
+            write_volatile(&mut common.queue_driver_low, (avail as u32).into());
+            write_volatile(&mut common.queue_driver_high, ((avail >> 32) as u32).into());
+            write_volatile(&mut common.queue_device_low, (used as u32).into());
+            write_volatile(&mut common.queue_device_high, ((used >> 32) as u32).into());
+            write_volatile(&mut common.queue_enable, 1.into());
+        }
+    }
+
+    pub fn notify(&mut self, index: u32) {
+        unsafe {
+            let common = &mut *self.common;
+            write_volatile(&mut common.queue_select, (index as u16).into());
+            let off = read_volatile(&common.queue_notify_off).native() as usize;
+            let addr = self.notify + off * self.notify_off_multiplier as usize;
+            write_volatile(addr as *mut LEU16, (index as u16).into());
+        }
+    }
+
+    pub(super) fn cast<D>(self) -> PciTransport<D> {
+        PciTransport {
+            common: self.common,
+            notify: self.notify,
+            notify_off_multiplier: self.notify_off_multiplier,
+            isr: self.isr,
+            device: self.device.map(|device| device as *mut D),
+            device_id: self.device_id,
+        }
+    }
+}